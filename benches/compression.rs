@@ -0,0 +1,99 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Throughput of compressing and decompressing programs shaped like real
+//! views. Sizes are compared in `examples/compression.rs`.
+//!
+//! ```text
+//! cargo +nightly bench --bench compression
+//! ```
+
+#![feature(test)]
+
+extern crate test;
+
+use librender::compress::{compress, decompress};
+use librender::io::Builder;
+use test::Bencher;
+
+/// A data table with `rows` rows of four cells.
+fn table(rows: usize) -> Vec<u8> {
+    let mut b = Builder::new(Vec::new());
+
+    b.create_element("table").unwrap();
+    b.set_attribute("class", "data-table striped").unwrap();
+
+    for row in 0..rows {
+        b.create_element("tr").unwrap();
+        b.set_attribute("id", &format!("row-{}", row)).unwrap();
+
+        for (cell, class) in ["name", "email", "role", "status"].iter().enumerate() {
+            b.create_element("td").unwrap();
+            b.set_attribute("class", class).unwrap();
+            b.text_node(&format!("cell {} of row {}", cell, row))
+                .unwrap();
+            b.append_child().unwrap();
+            b.append_child().unwrap();
+        }
+
+        b.add_event_listener("click").unwrap();
+        b.append_child().unwrap();
+    }
+
+    b.finish().unwrap().0
+}
+
+/// A navigation menu with `items` links, mostly preset strings.
+fn menu(items: usize) -> Vec<u8> {
+    let mut b = Builder::new(Vec::new());
+
+    b.create_element("nav").unwrap();
+    b.create_element("ul").unwrap();
+
+    for item in 0..items {
+        b.create_element("li").unwrap();
+        b.create_element("a").unwrap();
+        b.set_attribute("href", &format!("/section/{}", item))
+            .unwrap();
+        b.set_style("display", "block").unwrap();
+        b.set_style("padding", "4px").unwrap();
+        b.text_node("Section").unwrap();
+        b.append_child().unwrap();
+        b.append_child().unwrap();
+        b.append_child().unwrap();
+    }
+
+    b.append_child().unwrap();
+    b.finish().unwrap().0
+}
+
+fn bench_compress(b: &mut Bencher, program: Vec<u8>) {
+    b.bytes = program.len() as u64;
+    b.iter(|| compress(test::black_box(&program)).unwrap());
+}
+
+fn bench_decompress(b: &mut Bencher, program: Vec<u8>) {
+    let compressed = compress(&program).unwrap();
+
+    b.bytes = program.len() as u64;
+    b.iter(|| decompress(test::black_box(&compressed)).unwrap());
+}
+
+#[bench]
+fn compress_table(b: &mut Bencher) {
+    bench_compress(b, table(200));
+}
+
+#[bench]
+fn decompress_table(b: &mut Bencher) {
+    bench_decompress(b, table(200));
+}
+
+#[bench]
+fn compress_menu(b: &mut Bencher) {
+    bench_compress(b, menu(200));
+}
+
+#[bench]
+fn decompress_menu(b: &mut Bencher) {
+    bench_decompress(b, menu(200));
+}
@@ -0,0 +1,118 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Compares the size of compressed programs against the raw bytes written
+//! by `librender_output_bytecode`, for a few programs shaped like real views.
+//! Throughput is measured by `benches/compression.rs`.
+//!
+//! ```text
+//! cargo +nightly run --release --example compression
+//! ```
+
+use std::slice;
+use std::time::Instant;
+
+use librender::compress::{compress, compress_with, decompress};
+use librender::*;
+
+type Build = unsafe fn(*mut librender_bytecode_buffer, usize);
+
+unsafe fn element(buf: *mut librender_bytecode_buffer, tag: &str) {
+    librender_create_element(buf, tag.as_ptr() as *const libc::c_char, tag.len() as u8);
+}
+
+unsafe fn attribute(buf: *mut librender_bytecode_buffer, name: &str, value: &str) {
+    librender_set_attribute(
+        buf,
+        name.as_ptr() as *const libc::c_char,
+        name.len() as u8,
+        value.as_ptr() as *const libc::c_char,
+        value.len() as u8,
+    );
+}
+
+unsafe fn text(buf: *mut librender_bytecode_buffer, text: &str) {
+    librender_text_node(buf, text.as_ptr() as *const libc::c_char, text.len() as u8);
+}
+
+unsafe fn table(buf: *mut librender_bytecode_buffer, rows: usize) {
+    element(buf, "table");
+    attribute(buf, "class", "data-table striped");
+
+    for row in 0..rows {
+        element(buf, "tr");
+        attribute(
+            buf,
+            "class",
+            if row % 2 == 0 { "row even" } else { "row odd" },
+        );
+
+        for column in ["id", "name", "status", "updated"] {
+            element(buf, "td");
+            attribute(buf, "class", "cell");
+            text(buf, &format!("{} {}", column, row));
+            librender_append_child(buf);
+            librender_append_child(buf);
+        }
+
+        librender_append_child(buf);
+    }
+}
+
+unsafe fn list(buf: *mut librender_bytecode_buffer, items: usize) {
+    element(buf, "ul");
+    attribute(buf, "class", "menu");
+
+    for item in 0..items {
+        element(buf, "li");
+        element(buf, "a");
+        attribute(buf, "href", &format!("/docs/page-{}", item % 12));
+        attribute(buf, "class", "menu-link");
+        text(buf, "Read more");
+        librender_append_child(buf);
+        librender_add_event_listener(buf, b"click".as_ptr() as *const libc::c_char, 5);
+        librender_append_child(buf);
+        librender_append_child(buf);
+    }
+}
+
+fn main() {
+    let programs: [(&str, Build, usize); 4] = [
+        ("table 10 rows", table, 10),
+        ("table 500 rows", table, 500),
+        ("list 20 items", list, 20),
+        ("list 1000 items", list, 1000),
+    ];
+
+    println!(
+        "{:<16} {:>9} {:>9} {:>7} {:>9} {:>7} {:>10}",
+        "program", "raw", "preset", "ratio", "no preset", "ratio", "decompress"
+    );
+
+    for (name, build, n) in programs {
+        let raw = unsafe {
+            let buf = librender_create_buffer(0);
+            build(buf, n);
+            let raw = slice::from_raw_parts((*buf).buffer, (*buf).size as usize).to_vec();
+            librender_free_buffer(buf);
+            raw
+        };
+
+        let packed = compress(&raw).unwrap();
+        let unseeded = compress_with(&raw, 0).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(decompress(&packed).unwrap(), raw);
+        let elapsed = start.elapsed();
+
+        println!(
+            "{:<16} {:>9} {:>9} {:>6.1}% {:>9} {:>6.1}% {:>10.2?}",
+            name,
+            raw.len(),
+            packed.len(),
+            100.0 * packed.len() as f64 / raw.len() as f64,
+            unseeded.len(),
+            100.0 * unseeded.len() as f64 / raw.len() as f64,
+            elapsed
+        );
+    }
+}
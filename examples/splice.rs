@@ -0,0 +1,93 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Times bulk buffer operations on multi-megabyte programs. Each row doubles
+//! the program size, so the time per megabyte should stay roughly constant:
+//! appends, splices and cursor edits move each byte a bounded number of
+//! times rather than once per inserted or removed byte.
+//!
+//! ```text
+//! cargo +nightly run --release --example splice
+//! ```
+
+use std::slice;
+use std::time::{Duration, Instant};
+
+use librender::cursor::*;
+use librender::*;
+
+/// A `<li>` with a text child, repeated to fill the program.
+const ITEM: &[u8] = b"\x01\x02li\x06\x0bhello world\x03\x03";
+
+/// Edits made in the middle of the program per row.
+const EDITS: usize = 100;
+
+const MEGABYTE: usize = 1 << 20;
+
+fn per_megabyte(elapsed: Duration, size: usize) -> Duration {
+    elapsed.mul_f64(MEGABYTE as f64 / size as f64)
+}
+
+fn main() {
+    println!(
+        "{:>8} {:>12} {:>12} {:>12} {:>12}",
+        "size", "append/MB", "splice/MB", "cursor/MB", "shrink"
+    );
+
+    for megabytes in [1, 2, 4, 8, 16] {
+        let count = megabytes * MEGABYTE / ITEM.len();
+        let size = count * ITEM.len();
+
+        unsafe {
+            let buf = librender_create_buffer(0);
+
+            let start = Instant::now();
+            for _ in 0..count {
+                librender_append_bytes(buf, ITEM.as_ptr(), ITEM.len() as size_t);
+            }
+            let append = start.elapsed();
+
+            // Inserting and removing an instruction in the middle shifts the
+            // second half of the program twice per edit.
+            let middle = (count / 2 * ITEM.len()) as size_t;
+            let start = Instant::now();
+            for _ in 0..EDITS {
+                librender_splice(buf, middle, 0, ITEM.as_ptr(), ITEM.len() as size_t);
+                librender_splice(buf, middle, ITEM.len() as size_t, std::ptr::null(), 0);
+            }
+            let splice = start.elapsed();
+
+            // Deleting each instruction and inserting it back leaves the
+            // program unchanged and the cursor on the next instruction.
+            let program = slice::from_raw_parts((*buf).buffer, (*buf).size as usize);
+            let mut cursor = Cursor::new(program.to_vec()).unwrap();
+            cursor.seek(count / 2 * 4).unwrap();
+
+            let start = Instant::now();
+            for _ in 0..EDITS {
+                let instruction = cursor.delete().unwrap();
+                cursor.insert(&instruction).unwrap();
+            }
+            let edits = start.elapsed();
+            assert_eq!(cursor.program(), program);
+
+            assert_eq!((*buf).size as usize, size);
+
+            librender_truncate(buf, middle);
+            let start = Instant::now();
+            librender_shrink_to_fit(buf);
+            let shrink = start.elapsed();
+            assert_eq!((*buf).capacity, middle);
+
+            librender_free_buffer(buf);
+
+            println!(
+                "{:>6}MB {:>12.2?} {:>12.2?} {:>12.2?} {:>12.2?}",
+                megabytes,
+                per_megabyte(append, size),
+                per_megabyte(splice, size),
+                per_megabyte(edits, size),
+                shrink
+            );
+        }
+    }
+}
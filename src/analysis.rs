@@ -0,0 +1,351 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Node lifetime and tree-shape analysis.
+//!
+//! Nodes that are created but never attached stay on the node stack until
+//! the end of the program. Since nothing can be attached below such an
+//! orphan, every node created after the first orphan is dead as well, which
+//! is what makes [`strip_orphans`] a simple filter over instructions.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::decode::{decode, encode, Instruction};
+use crate::interp::{InterpretError, Interpreter, NodeId, Tree};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttachKind {
+    Child,
+    Sibling,
+    Replace,
+}
+
+/// A node being attached to (or next to) another node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Attachment {
+    /// Index of the attaching instruction.
+    pub index: usize,
+    pub kind: AttachKind,
+    pub node: NodeId,
+    /// Parent for `Child`/`Replace`, preceding sibling for `Sibling`.
+    pub target: NodeId,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lifetime {
+    pub node: NodeId,
+    /// Index of the instruction that created the node.
+    pub created: usize,
+    pub attached: Option<usize>,
+    pub detached: Option<usize>,
+    /// Index of the last instruction that touched the node.
+    pub last_use: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Shape {
+    pub lifetimes: BTreeMap<NodeId, Lifetime>,
+    pub attachments: Vec<Attachment>,
+    /// Nodes that were never attached.
+    pub orphans: Vec<NodeId>,
+    /// Orphans together with every node attached under them.
+    pub dead: Vec<NodeId>,
+    pub node_count: usize,
+    /// Number of levels in the rendered tree, roots included.
+    pub max_depth: usize,
+    /// Largest number of children of a single rendered node.
+    pub max_fan_out: usize,
+    pub tree: Tree,
+    /// Nodes touched by each instruction, by instruction index.
+    touched: Vec<Vec<NodeId>>,
+}
+
+impl Shape {
+    /// Instructions that only touch dead nodes.
+    pub fn dead_instructions(&self) -> Vec<usize> {
+        let dead: HashSet<NodeId> = self.dead.iter().copied().collect();
+
+        self.touched
+            .iter()
+            .enumerate()
+            .filter(|(_, nodes)| !nodes.is_empty() && nodes.iter().all(|n| dead.contains(n)))
+            .map(|(index, _)| index)
+            .collect()
+    }
+}
+
+pub fn analyze_instructions(instructions: &[Instruction]) -> Result<Shape, InterpretError> {
+    let mut interpreter = Interpreter::new();
+    let mut shape = Shape::default();
+    let mut offset = 0;
+
+    for (index, instruction) in instructions.iter().enumerate() {
+        let stack = interpreter.stack();
+        let top = stack.last().copied();
+        let below = stack.len().checked_sub(2).map(|i| stack[i]);
+        let last_child = |id: Option<NodeId>| {
+            id.and_then(|id| interpreter.tree().nodes[id].children.last().copied())
+        };
+
+        let mut touched = match instruction {
+            Instruction::CreateElement(_)
+            | Instruction::TextNode(_)
+            | Instruction::Placeholder(_) => {
+                vec![interpreter.tree().nodes.len()]
+            }
+            Instruction::SetAttribute(..)
+            | Instruction::RemoveAttribute(_)
+            | Instruction::Style(..)
+            | Instruction::EventListener(_) => interpreter.target().into_iter().collect(),
+            Instruction::AppendChild | Instruction::AppendSibling => {
+                below.into_iter().chain(top).collect()
+            }
+            Instruction::ReplaceChild => below
+                .into_iter()
+                .chain(top)
+                .chain(last_child(below))
+                .collect(),
+            Instruction::RemoveChild => top.into_iter().chain(last_child(top)).collect(),
+            Instruction::SetText(_) => top.into_iter().collect(),
+            Instruction::Nop => Vec::new(),
+        };
+
+        let attachment = match (instruction, below, top) {
+            (Instruction::AppendChild, Some(target), Some(node)) => {
+                Some((AttachKind::Child, target, node))
+            }
+            (Instruction::AppendSibling, Some(target), Some(node)) => {
+                Some((AttachKind::Sibling, target, node))
+            }
+            (Instruction::ReplaceChild, Some(target), Some(node)) => {
+                Some((AttachKind::Replace, target, node))
+            }
+            _ => None,
+        };
+
+        let detached = match instruction {
+            Instruction::RemoveChild => last_child(top),
+            Instruction::ReplaceChild => last_child(below),
+            _ => None,
+        };
+
+        interpreter.step(instruction, offset)?;
+        offset += instruction.encoded_len();
+
+        if instruction.creates_node() {
+            shape.lifetimes.insert(
+                touched[0],
+                Lifetime {
+                    node: touched[0],
+                    created: index,
+                    attached: None,
+                    detached: None,
+                    last_use: index,
+                },
+            );
+        }
+
+        if let Some((kind, target, node)) = attachment {
+            shape.attachments.push(Attachment {
+                index,
+                kind,
+                node,
+                target,
+            });
+
+            if let Some(lifetime) = shape.lifetimes.get_mut(&node) {
+                lifetime.attached.get_or_insert(index);
+            }
+        }
+
+        if let Some(lifetime) = detached.and_then(|node| shape.lifetimes.get_mut(&node)) {
+            lifetime.detached = Some(index);
+        }
+
+        // nodes implicitly created by `set_text` on an element are not
+        // program nodes and have no lifetime of their own
+        touched.retain(|node| shape.lifetimes.contains_key(node));
+
+        for node in &touched {
+            shape.lifetimes.get_mut(node).unwrap().last_use = index;
+        }
+
+        shape.touched.push(touched);
+    }
+
+    let tree = interpreter.finish();
+    let mut dead: HashSet<NodeId> = tree.orphans.iter().copied().collect();
+
+    // a subtree can be built before it is attached, so iterate until no more
+    // nodes turn out to hang off a dead node
+    loop {
+        let before = dead.len();
+
+        for attachment in &shape.attachments {
+            if dead.contains(&attachment.target) {
+                dead.insert(attachment.node);
+            }
+        }
+
+        if dead.len() == before {
+            break;
+        }
+    }
+
+    let mut pending = tree.roots.clone();
+    let mut levels = vec![0usize; tree.nodes.len()];
+
+    for &root in &tree.roots {
+        levels[root] = 1;
+    }
+
+    while let Some(id) = pending.pop() {
+        let node = &tree.nodes[id];
+
+        shape.max_depth = shape.max_depth.max(levels[id]);
+        shape.max_fan_out = shape.max_fan_out.max(node.children.len());
+
+        for &child in &node.children {
+            levels[child] = levels[id] + 1;
+            pending.push(child);
+        }
+    }
+
+    let mut dead: Vec<NodeId> = dead.into_iter().collect();
+    dead.sort_unstable();
+
+    shape.node_count = shape.lifetimes.len();
+    shape.orphans = tree.orphans.clone();
+    shape.dead = dead;
+    shape.tree = tree;
+
+    Ok(shape)
+}
+
+pub fn analyze(program: &[u8]) -> Result<Shape, InterpretError> {
+    analyze_instructions(&decode(program)?)
+}
+
+/// Removes the instructions that only build orphaned subtrees, given the
+/// shape of `instructions`. Listener IDs are ordinals, so if a dead listener
+/// precedes a live one the program is returned unchanged rather than
+/// renumbering the live listener.
+pub fn strip_dead(instructions: Vec<Instruction>, shape: &Shape) -> Vec<Instruction> {
+    let dead: HashSet<usize> = shape.dead_instructions().into_iter().collect();
+
+    let renumbers_listeners = instructions
+        .iter()
+        .enumerate()
+        .filter(|(_, instruction)| matches!(instruction, Instruction::EventListener(_)))
+        .skip_while(|(index, _)| !dead.contains(index))
+        .any(|(index, _)| !dead.contains(&index));
+
+    if dead.is_empty() || renumbers_listeners {
+        return instructions;
+    }
+
+    instructions
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, instruction)| (!dead.contains(&index)).then_some(instruction))
+        .collect()
+}
+
+pub fn strip_orphans(program: &[u8]) -> Result<Vec<u8>, InterpretError> {
+    let instructions = decode(program)?;
+    let shape = analyze_instructions(&instructions)?;
+
+    Ok(encode(&strip_dead(instructions, &shape)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::canon::semantically_eq;
+    use crate::interp::interpret;
+
+    const LIST: &str = r#"
+        create_element "ul"
+        create_element "li"
+        event_listener "click"
+        append_child
+        create_element "li"
+        create_element "span"
+        text_node "x"
+        append_child
+        append_child
+        append_child
+        create_element "li"
+        append_child
+        create_element "aside"
+        text_node "gone"
+        append_child
+        event_listener "focus"
+    "#;
+
+    #[test]
+    fn measures_depth_and_fan_out() {
+        let shape = analyze(&assemble(LIST).unwrap()).unwrap();
+
+        assert_eq!(shape.max_depth, 4);
+        assert_eq!(shape.max_fan_out, 3);
+        assert_eq!(shape.node_count, 8);
+    }
+
+    #[test]
+    fn finds_orphans_and_what_hangs_off_them() {
+        let shape = analyze(&assemble(LIST).unwrap()).unwrap();
+
+        assert_eq!(shape.orphans, [6]);
+        assert_eq!(shape.dead, [6, 7]);
+        assert_eq!(shape.dead_instructions(), [12, 13, 14, 15]);
+    }
+
+    #[test]
+    fn tracks_lifetimes() {
+        let shape = analyze(&assemble(LIST).unwrap()).unwrap();
+        let li = &shape.lifetimes[&1];
+
+        assert_eq!((li.created, li.attached, li.last_use), (1, Some(3), 3));
+        assert_eq!(shape.lifetimes[&0].last_use, 11);
+
+        // The listener keeps the orphan in use after it is last attached to.
+        let aside = &shape.lifetimes[&6];
+        assert_eq!((aside.attached, aside.last_use), (None, 15));
+
+        let removed = analyze(
+            &assemble("create_element \"p\"\ntext_node \"a\"\nappend_child\nremove_child").unwrap(),
+        )
+        .unwrap();
+        let text = &removed.lifetimes[&1];
+
+        assert_eq!(
+            (text.attached, text.detached, text.last_use),
+            (Some(2), Some(3), 3)
+        );
+        assert_eq!(
+            removed.attachments,
+            [Attachment {
+                index: 2,
+                kind: AttachKind::Child,
+                node: 1,
+                target: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn strips_orphans_without_changing_the_tree() {
+        let program = assemble(LIST).unwrap();
+        let stripped = strip_orphans(&program).unwrap();
+
+        assert!(stripped.len() < program.len());
+        assert!(semantically_eq(&program, &stripped).unwrap());
+        assert!(analyze(&stripped).unwrap().orphans.is_empty());
+
+        // Nodes created after an orphan are dead, so dead listeners come
+        // last and the listeners left are numbered as before, without gaps.
+        assert_eq!(interpret(&program).unwrap().listener_count, 2);
+        assert_eq!(interpret(&stripped).unwrap().listener_count, 1);
+    }
+}
@@ -0,0 +1,347 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Textual assembly for programs.
+//!
+//! One instruction per line, written the way [`Instruction`] displays it:
+//! the mnemonic followed by its payloads as double-quoted strings using
+//! Rust escapes. Everything after a `;` outside of a string is a comment,
+//! which is where [`disassemble`] puts instruction offsets.
+//!
+//! ```text
+//! create_element "div"        ; 0x0000
+//! set_attribute "class" "row" ; 0x0005
+//! ```
+
+use std::fmt::{self, Write};
+
+use crate::debug::DebugInfo;
+use crate::decode::{
+    encode, opcode_arity, opcode_mnemonic, DecodeError, Instruction, Instructions,
+    MAX_PAYLOAD_LENGTH,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    /// The instruction takes `expected` payloads but `found` were given.
+    Arity {
+        expected: usize,
+        found: usize,
+    },
+    UnterminatedString,
+    BadEscape(String),
+    /// A payload char does not fit in a byte.
+    NotLatin1(char),
+    PayloadTooLong(usize),
+    /// Text between operands that is not a string.
+    Unexpected(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    /// 1-based line number.
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+
+        match &self.kind {
+            AsmErrorKind::UnknownMnemonic(m) => write!(f, "unknown mnemonic `{}`", m),
+            AsmErrorKind::Arity { expected, found } => {
+                write!(f, "expected {} payloads, found {}", expected, found)
+            }
+            AsmErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            AsmErrorKind::BadEscape(e) => write!(f, "invalid escape `{}`", e),
+            AsmErrorKind::NotLatin1(c) => write!(f, "{:?} is not an 8-bit character", c),
+            AsmErrorKind::PayloadTooLong(n) => {
+                write!(f, "payload of {} bytes exceeds {}", n, MAX_PAYLOAD_LENGTH)
+            }
+            AsmErrorKind::Unexpected(s) => write!(f, "unexpected `{}`", s),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+fn opcode_for(mnemonic: &str) -> Option<u8> {
+    (0..=u8::MAX).find(|&op| opcode_mnemonic(op) == Some(mnemonic))
+}
+
+/// Reads one escape sequence after a backslash.
+fn unescape(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<char, AsmErrorKind> {
+    let c = chars.next().ok_or(AsmErrorKind::UnterminatedString)?;
+
+    Ok(match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        '0' => '\0',
+        '\\' | '"' | '\'' => c,
+        'x' => {
+            let hex: String = chars.by_ref().take(2).collect();
+            u8::from_str_radix(&hex, 16)
+                .map_err(|_| AsmErrorKind::BadEscape(format!("\\x{}", hex)))? as char
+        }
+        'u' => {
+            let mut hex = String::new();
+
+            if chars.next() != Some('{') {
+                return Err(AsmErrorKind::BadEscape("\\u".into()));
+            }
+
+            for c in chars.by_ref() {
+                if c == '}' {
+                    break;
+                }
+                hex.push(c);
+            }
+
+            u32::from_str_radix(&hex, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or_else(|| AsmErrorKind::BadEscape(format!("\\u{{{}}}", hex)))?
+        }
+        c => return Err(AsmErrorKind::BadEscape(format!("\\{}", c))),
+    })
+}
+
+struct Line<'a> {
+    mnemonic: &'a str,
+    payloads: Vec<Vec<u8>>,
+}
+
+/// Parses one line, or returns `None` if it is blank or a comment.
+fn parse_line(line: &str) -> Result<Option<Line<'_>>, AsmErrorKind> {
+    let line = line.trim_start();
+    let end = line
+        .find(|c: char| c.is_whitespace() || c == ';')
+        .unwrap_or(line.len());
+    let (mnemonic, rest) = line.split_at(end);
+
+    if mnemonic.is_empty() {
+        return Ok(None);
+    }
+
+    let mut payloads = Vec::new();
+    let mut chars = rest.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => break,
+            c if c.is_whitespace() => {}
+            '"' => {
+                let mut payload = Vec::new();
+
+                loop {
+                    let c = match chars.next() {
+                        None => return Err(AsmErrorKind::UnterminatedString),
+                        Some('"') => break,
+                        Some('\\') => unescape(&mut chars)?,
+                        Some(c) => c,
+                    };
+
+                    if c as u32 > 0xff {
+                        return Err(AsmErrorKind::NotLatin1(c));
+                    }
+
+                    payload.push(c as u8);
+                }
+
+                if payload.len() > MAX_PAYLOAD_LENGTH {
+                    return Err(AsmErrorKind::PayloadTooLong(payload.len()));
+                }
+
+                payloads.push(payload);
+            }
+            c => {
+                let word: String = std::iter::once(c)
+                    .chain(std::iter::from_fn(|| {
+                        chars.next_if(|c| !c.is_whitespace() && *c != '"')
+                    }))
+                    .collect();
+
+                return Err(AsmErrorKind::Unexpected(word));
+            }
+        }
+    }
+
+    Ok(Some(Line { mnemonic, payloads }))
+}
+
+pub fn assemble_instructions(source: &str) -> Result<Vec<Instruction>, AsmError> {
+    let mut instructions = Vec::new();
+
+    for (index, line) in source.lines().enumerate() {
+        let fail = |kind| AsmError {
+            line: index + 1,
+            kind,
+        };
+
+        let Some(Line { mnemonic, payloads }) = parse_line(line).map_err(fail)? else {
+            continue;
+        };
+
+        let opcode = opcode_for(mnemonic)
+            .ok_or_else(|| fail(AsmErrorKind::UnknownMnemonic(mnemonic.to_string())))?;
+        let expected = opcode_arity(opcode).unwrap_or(0);
+        let found = payloads.len();
+
+        instructions.push(
+            Instruction::from_parts(opcode, payloads)
+                .ok_or_else(|| fail(AsmErrorKind::Arity { expected, found }))?,
+        );
+    }
+
+    Ok(instructions)
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    Ok(encode(&assemble_instructions(source)?))
+}
+
+/// One instruction per line, with its offset as a trailing comment. The
+/// output assembles back to the same bytes.
+pub fn disassemble(program: &[u8]) -> Result<String, DecodeError> {
+    disassemble_with(program, None)
+}
+
+/// Like [`disassemble`], with a comment line naming each component from
+/// `debug` where its instructions start.
+pub fn disassemble_with(program: &[u8], debug: Option<&DebugInfo>) -> Result<String, DecodeError> {
+    let mut lines = Vec::new();
+
+    for item in Instructions::new(program) {
+        let (offset, instruction) = item?;
+        lines.push((offset, instruction.to_string()));
+    }
+
+    let width = lines.iter().map(|(_, s)| s.len()).max().unwrap_or(0);
+    let mut out = String::new();
+
+    for (offset, text) in lines {
+        if let Some(debug) = debug {
+            let depth = debug.lookup(offset).len();
+            let starting: Vec<_> = debug.starting_at(offset).collect();
+
+            for (i, entry) in starting.iter().enumerate() {
+                let indent = "  ".repeat(depth - starting.len() + i);
+                let _ = writeln!(out, "; {}{}", indent, entry);
+            }
+        }
+
+        let _ = writeln!(out, "{:<width$} ; 0x{:04x}", text, offset, width = width);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug::{DebugEntry, SourceLocation};
+    use crate::decode::Instruction;
+
+    fn every_opcode() -> Vec<u8> {
+        let payload = |bytes: &[u8]| bytes.to_vec();
+
+        encode(&[
+            Instruction::CreateElement(payload(b"div")),
+            Instruction::SetAttribute(payload(b"title"), payload(b"say \"hi\"\\\n")),
+            Instruction::Style(payload(b"color"), payload(b"")),
+            Instruction::EventListener(payload(b"click")),
+            Instruction::RemoveAttribute(payload(b"title")),
+            Instruction::TextNode(payload(b"\x00\x7f\xe9\xff\t\r")),
+            Instruction::SetText(payload(b";")),
+            Instruction::AppendChild,
+            Instruction::Placeholder(payload(b"slot")),
+            Instruction::AppendSibling,
+            Instruction::ReplaceChild,
+            Instruction::RemoveChild,
+            Instruction::Nop,
+        ])
+    }
+
+    #[test]
+    fn disassembly_assembles_back() {
+        let program = every_opcode();
+
+        assert_eq!(assemble(&disassemble(&program).unwrap()).unwrap(), program);
+        assert_eq!(assemble("").unwrap(), b"");
+
+        let debug = DebugInfo::new(vec![DebugEntry {
+            range: 0..program.len(),
+            name: "Page".to_string(),
+            location: Some(SourceLocation {
+                file: "page.tsx".to_string(),
+                line: 3,
+            }),
+        }]);
+        let text = disassemble_with(&program, Some(&debug)).unwrap();
+
+        assert!(text.starts_with("; Page (page.tsx:3)\n"));
+        assert_eq!(assemble(&text).unwrap(), program);
+    }
+
+    #[test]
+    fn reads_escapes_and_comments() {
+        assert_eq!(
+            assemble("  ; comment\n\ntext_node \"\\x41\\u{e9}\\'\" ; trailing").unwrap(),
+            b"\x06\x03A\xe9'"
+        );
+    }
+
+    #[test]
+    fn reports_errors_by_line() {
+        let error = |source: &str| assemble(source).unwrap_err();
+
+        assert_eq!(
+            error("nop\njump"),
+            AsmError {
+                line: 2,
+                kind: AsmErrorKind::UnknownMnemonic("jump".to_string())
+            }
+        );
+        assert_eq!(
+            error("set_attribute \"id\"").kind,
+            AsmErrorKind::Arity {
+                expected: 2,
+                found: 1
+            }
+        );
+        assert_eq!(
+            error("nop \"x\"").kind,
+            AsmErrorKind::Arity {
+                expected: 0,
+                found: 1
+            }
+        );
+        assert_eq!(
+            error("text_node \"abc").kind,
+            AsmErrorKind::UnterminatedString
+        );
+        assert_eq!(
+            error("text_node \"\\q\"").kind,
+            AsmErrorKind::BadEscape("\\q".to_string())
+        );
+        assert_eq!(
+            error("text_node \"\\xzz\"").kind,
+            AsmErrorKind::BadEscape("\\xzz".to_string())
+        );
+        assert_eq!(
+            error("text_node \"\u{2713}\"").kind,
+            AsmErrorKind::NotLatin1('\u{2713}')
+        );
+        assert_eq!(
+            error(&format!("text_node \"{}\"", "a".repeat(256))).kind,
+            AsmErrorKind::PayloadTooLong(256)
+        );
+        assert_eq!(
+            error("create_element div").kind,
+            AsmErrorKind::Unexpected("div".to_string())
+        );
+        assert_eq!(error("nop\n\nnop x").to_string(), "line 3: unexpected `x`");
+    }
+}
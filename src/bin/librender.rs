@@ -0,0 +1,307 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Command-line tool for inspecting and converting programs written by
+//! `librender_output_bytecode`. Inputs are file paths, or stdin when the
+//! path is `-` or missing.
+
+use std::io::{self, Read, Write};
+use std::process::ExitCode;
+
+use librender::asm::{assemble, disassemble_with};
+use librender::debug::{split, DebugInfo};
+use librender::decode::{decode, DecodeError, Instruction};
+use librender::diag::{validate, Diagnostic, Severity};
+use librender::html::render_html;
+use librender::interp::{interpret, InterpretError, Tree};
+use librender::opt::{optimize, OptLevel};
+use librender::snapshot::{diff_lines, dump};
+use librender::stats::stats_with_components;
+
+const USAGE: &str = "\
+usage: librender <command> [options] [file]
+
+commands:
+  disasm [file]          print one instruction per line
+  asm [file]             assemble text into a program
+  validate [file]        check that a program decodes and runs
+  stats [--json] [file]  print size attribution statistics
+  render-html [file]     render a program to HTML
+  diff <a> <b>           compare two programs instruction by instruction
+  optimize [-O0|-O1|-O2] [file]
+                         optimize a program (default -O1)
+  tree [file]            print the rendered node tree
+
+options:
+  -o <file>              write output to a file instead of stdout
+  -g <file>              read debug info from a separate file; otherwise a
+                         debug trailer on the program is used
+  -h, --help             print this message";
+
+type Error = Box<dyn std::error::Error>;
+
+struct Args {
+    command: String,
+    inputs: Vec<String>,
+    output: Option<String>,
+    level: OptLevel,
+    json: bool,
+    debug: Option<String>,
+}
+
+struct Input {
+    program: Vec<u8>,
+    debug: Option<DebugInfo>,
+}
+
+impl Input {
+    /// Renders a diagnostic against the program, adding the component
+    /// containing its offset.
+    fn report(&self, diagnostic: Diagnostic) -> String {
+        diagnostic
+            .with_debug_info(self.debug.as_ref())
+            .render(&self.program)
+    }
+
+    fn decode_error(&self, err: &DecodeError) -> Error {
+        self.report(Diagnostic::decode(&self.program, err)).into()
+    }
+
+    fn interpret_error(&self, err: &InterpretError) -> Error {
+        self.report(Diagnostic::interpret(&self.program, err))
+            .into()
+    }
+
+    fn decode(&self) -> Result<Vec<Instruction>, Error> {
+        decode(&self.program).map_err(|err| self.decode_error(&err))
+    }
+
+    fn interpret(&self) -> Result<Tree, Error> {
+        interpret(&self.program).map_err(|err| self.interpret_error(&err))
+    }
+}
+
+fn parse_args() -> Result<Option<Args>, Error> {
+    let mut args = std::env::args().skip(1);
+    let mut command = None;
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut level = OptLevel::default();
+    let mut json = false;
+    let mut debug = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" => output = Some(args.next().ok_or("-o requires a file")?),
+            "-g" => debug = Some(args.next().ok_or("-g requires a file")?),
+            "-O0" => level = OptLevel::O0,
+            "-O1" => level = OptLevel::O1,
+            "-O2" => level = OptLevel::O2,
+            "--json" => json = true,
+            "-" => inputs.push(arg),
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg).into()),
+            _ if command.is_none() => command = Some(arg),
+            _ => inputs.push(arg),
+        }
+    }
+
+    Ok(command.map(|command| Args {
+        command,
+        inputs,
+        output,
+        level,
+        json,
+        debug,
+    }))
+}
+
+fn read_input(path: Option<&str>) -> Result<Vec<u8>, Error> {
+    match path {
+        None | Some("-") => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            Ok(data)
+        }
+        Some(path) => std::fs::read(path).map_err(|err| format!("{}: {}", path, err).into()),
+    }
+}
+
+fn write_output(path: Option<&str>, data: &[u8]) -> Result<(), Error> {
+    match path {
+        None => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(data)?;
+            stdout.flush()?;
+        }
+        Some(path) => std::fs::write(path, data).map_err(|err| format!("{}: {}", path, err))?,
+    }
+
+    Ok(())
+}
+
+fn single_input(args: &Args) -> Result<Vec<u8>, Error> {
+    match args.inputs.as_slice() {
+        [] => read_input(None),
+        [path] => read_input(Some(path)),
+        _ => Err(format!("{} takes a single input", args.command).into()),
+    }
+}
+
+fn read_program(args: &Args, path: Option<&str>) -> Result<Input, Error> {
+    let data = read_input(path)?;
+    let (program, trailer) = split(&data);
+
+    let debug = match &args.debug {
+        Some(path) => Some(DebugInfo::decode(&read_input(Some(path))?)?),
+        None => trailer,
+    };
+
+    Ok(Input {
+        program: program.to_vec(),
+        debug,
+    })
+}
+
+fn single_program(args: &Args) -> Result<Input, Error> {
+    match args.inputs.as_slice() {
+        [] => read_program(args, None),
+        [path] => read_program(args, Some(path)),
+        _ => Err(format!("{} takes a single input", args.command).into()),
+    }
+}
+
+fn run(args: &Args) -> Result<ExitCode, Error> {
+    let output = args.output.as_deref();
+
+    match args.command.as_str() {
+        "disasm" => {
+            let input = single_program(args)?;
+            let text = disassemble_with(&input.program, input.debug.as_ref())
+                .map_err(|err| input.decode_error(&err))?;
+            write_output(output, text.as_bytes())?;
+        }
+        "asm" => {
+            let source = String::from_utf8(single_input(args)?)?;
+            write_output(output, &assemble(&source)?)?;
+        }
+        "validate" => {
+            let input = single_program(args)?;
+            let diagnostics = validate(&input.program, input.debug.as_ref());
+            let mut report = String::new();
+
+            for diagnostic in diagnostics {
+                if diagnostic.severity == Severity::Error {
+                    return Err(diagnostic.render(&input.program).into());
+                }
+
+                report.push_str(&diagnostic.render(&input.program));
+            }
+
+            let instructions = input.decode()?;
+            let tree = input.interpret()?;
+            report.push_str(&format!(
+                "ok: {} bytes, {} instructions, {} nodes\n",
+                input.program.len(),
+                instructions.len(),
+                tree.nodes.len()
+            ));
+
+            write_output(output, report.as_bytes())?;
+        }
+        "stats" => {
+            let input = single_program(args)?;
+            let components = input.debug.as_ref().map(DebugInfo::components);
+            let stats = stats_with_components(&input.program, &components.unwrap_or_default())
+                .map_err(|err| input.interpret_error(&err))?;
+            let report = if args.json {
+                stats.to_json() + "\n"
+            } else {
+                stats.to_string()
+            };
+
+            write_output(output, report.as_bytes())?;
+        }
+        "render-html" => {
+            let mut html = render_html(&single_program(args)?.interpret()?);
+            html.push('\n');
+            write_output(output, html.as_bytes())?;
+        }
+        "diff" => {
+            let [a, b] = args.inputs.as_slice() else {
+                return Err("diff takes two inputs".into());
+            };
+
+            let (a, b) = (read_program(args, Some(a))?, read_program(args, Some(b))?);
+            let lines = |input: &Input| -> Result<Vec<String>, Error> {
+                Ok(input.decode()?.iter().map(Instruction::to_string).collect())
+            };
+
+            let diff = diff_lines(&lines(&a)?, &lines(&b)?);
+            let changed = diff.iter().any(|(sign, _)| *sign != ' ');
+            let equivalent = a.interpret()?.equivalent(&b.interpret()?);
+            let mut report = String::new();
+
+            if changed {
+                for (sign, line) in &diff {
+                    report.push_str(&format!("{} {}\n", sign, line));
+                }
+            }
+
+            report.push_str(match (changed, equivalent) {
+                (false, _) => "programs are identical\n",
+                (true, true) => "programs differ but render the same tree\n",
+                (true, false) => "programs render different trees\n",
+            });
+
+            write_output(output, report.as_bytes())?;
+
+            if changed {
+                return Ok(ExitCode::from(1));
+            }
+        }
+        "optimize" => {
+            let input = single_program(args)?;
+            let program =
+                optimize(&input.program, args.level).map_err(|err| input.decode_error(&err))?;
+
+            write_output(output, &program)?;
+        }
+        "tree" => {
+            let tree = single_program(args)?.interpret()?;
+            write_output(output, dump(&tree).as_bytes())?;
+        }
+        command => return Err(format!("unknown command `{}`\n\n{}", command, USAGE).into()),
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args() {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            let _ = writeln!(io::stdout(), "{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("librender: {}", err);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(code) => code,
+        Err(err) => {
+            let message = err.to_string();
+
+            // Rendered diagnostics carry their own `error:` header.
+            if message.starts_with("error: ") {
+                eprint!("{}", message);
+            } else {
+                eprintln!("librender: {}", message);
+            }
+
+            ExitCode::from(2)
+        }
+    }
+}
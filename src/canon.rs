@@ -0,0 +1,292 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Canonical form of programs.
+//!
+//! The canonical program re-emits the rendered tree in document order: each
+//! node is created, its attributes are set in name order and its styles in
+//! declaration order, and its children follow, each attached with
+//! `append_child`. `nop`s, removed and orphaned nodes, overwritten
+//! attributes and empty text nodes are dropped, and adjacent text nodes are
+//! merged, split again only where the text exceeds a payload. Placeholders
+//! are kept so that fills still find their slots.
+//!
+//! Listeners are emitted whenever their element is the target and their ID
+//! is the next one due, which preserves listener IDs for every program that
+//! registers listeners in document order and for most others. Top-level
+//! siblings are attached with `append_sibling` after the first node, last
+//! sibling first, so their listeners can only keep their IDs if they were
+//! registered in that order too. Listeners on nodes that are not rendered
+//! still take up an ID when the program runs; the canonical form leaves
+//! them out and numbers the remaining listeners without gaps.
+
+use std::collections::VecDeque;
+
+use crate::decode::{encode, to_latin1, Instruction, MAX_PAYLOAD_LENGTH};
+use crate::interp::{interpret, InterpretError, NodeId, NodeKind, Tree};
+use crate::{buffer_bytes, librender_bytecode_buffer};
+
+struct Canonicalizer<'a> {
+    tree: &'a Tree,
+    out: Vec<Instruction>,
+    /// Listeners not yet emitted, per node, as `(id, event)`.
+    pending: Vec<VecDeque<(usize, String)>>,
+    emitted: Vec<bool>,
+    /// Lowest listener ID not yet emitted.
+    next: usize,
+}
+
+impl<'a> Canonicalizer<'a> {
+    fn new(tree: &'a Tree) -> Self {
+        let pending = tree
+            .nodes
+            .iter()
+            .map(|node| match &node.kind {
+                NodeKind::Element { listeners, .. } => {
+                    listeners.iter().map(|l| (l.id, l.event.clone())).collect()
+                }
+                _ => VecDeque::new(),
+            })
+            .collect();
+
+        // Listeners of nodes that are not rendered count as emitted, so the
+        // IDs of rendered listeners close up around them.
+        let mut emitted = vec![true; tree.listener_count];
+        let mut stack = tree.roots.clone();
+
+        while let Some(id) = stack.pop() {
+            if let NodeKind::Element { listeners, .. } = &tree.nodes[id].kind {
+                for l in listeners {
+                    emitted[l.id] = false;
+                }
+            }
+
+            stack.extend(&tree.nodes[id].children);
+        }
+
+        let next = emitted.iter().position(|&e| !e).unwrap_or(emitted.len());
+
+        Self {
+            tree,
+            out: Vec::new(),
+            pending,
+            emitted,
+            next,
+        }
+    }
+
+    fn listener(&mut self, id: usize, event: String) {
+        self.out.push(Instruction::EventListener(to_latin1(&event)));
+
+        if let Some(emitted) = self.emitted.get_mut(id) {
+            *emitted = true;
+        }
+
+        while self.emitted.get(self.next) == Some(&true) {
+            self.next += 1;
+        }
+    }
+
+    /// Emits the listeners of `node` that are due while it is the target.
+    fn listeners_due(&mut self, node: NodeId) {
+        while self.pending[node]
+            .front()
+            .is_some_and(|&(id, _)| id == self.next)
+        {
+            let (id, event) = self.pending[node].pop_front().unwrap();
+            self.listener(id, event);
+        }
+    }
+
+    /// Emits the remaining listeners of `node`, whose target window ends.
+    fn listeners_remaining(&mut self, node: NodeId) {
+        while let Some((id, event)) = self.pending[node].pop_front() {
+            self.listener(id, event);
+        }
+    }
+
+    fn node(&mut self, id: NodeId) {
+        match &self.tree.nodes[id].kind {
+            NodeKind::Text(text) => self.out.push(Instruction::TextNode(to_latin1(text))),
+            NodeKind::Slot(slot) => {
+                self.out.push(Instruction::Placeholder(slot.clone()));
+                self.children(id);
+            }
+            NodeKind::Element {
+                tag,
+                attributes,
+                styles,
+                ..
+            } => {
+                self.out.push(Instruction::CreateElement(to_latin1(tag)));
+
+                for (name, value) in attributes {
+                    self.out
+                        .push(Instruction::SetAttribute(to_latin1(name), to_latin1(value)));
+                }
+
+                for (name, value) in styles {
+                    self.out
+                        .push(Instruction::Style(to_latin1(name), to_latin1(value)));
+                }
+
+                self.listeners_due(id);
+                self.children(id);
+                self.listeners_remaining(id);
+            }
+        }
+    }
+
+    /// Emits and attaches the children of `parent`, merging adjacent text.
+    fn children(&mut self, parent: NodeId) {
+        let tree = self.tree;
+        let mut text = String::new();
+
+        for &child in &tree.nodes[parent].children {
+            if let NodeKind::Text(value) = &tree.nodes[child].kind {
+                text.push_str(value);
+                continue;
+            }
+
+            self.text_child(parent, &mut text);
+            self.node(child);
+            self.out.push(Instruction::AppendChild);
+            self.listeners_due(parent);
+        }
+
+        self.text_child(parent, &mut text);
+    }
+
+    /// Emits merged text as few text nodes as payloads allow.
+    fn text_child(&mut self, parent: NodeId, text: &mut String) {
+        let text = to_latin1(&std::mem::take(text));
+
+        for chunk in text.chunks(MAX_PAYLOAD_LENGTH) {
+            self.out.push(Instruction::TextNode(chunk.to_vec()));
+            self.out.push(Instruction::AppendChild);
+            self.listeners_due(parent);
+        }
+    }
+
+    fn run(mut self) -> Vec<Instruction> {
+        let roots = &self.tree.roots;
+
+        if let Some((&first, rest)) = roots.split_first() {
+            self.node(first);
+
+            for &root in rest.iter().rev() {
+                self.node(root);
+                self.out.push(Instruction::AppendSibling);
+                self.listeners_due(first);
+            }
+
+            self.listeners_remaining(first);
+        }
+
+        self.out
+    }
+}
+
+/// Canonical instructions for an interpreted tree.
+pub fn canonical_instructions(tree: &Tree) -> Vec<Instruction> {
+    Canonicalizer::new(tree).run()
+}
+
+/// Rewrites `program` into canonical form. Programs that render the same
+/// tree with the same slots have the same canonical form.
+pub fn canonicalize(program: &[u8]) -> Result<Vec<u8>, InterpretError> {
+    Ok(encode(&canonical_instructions(&interpret(program)?)))
+}
+
+/// Whether `a` and `b` render the same tree with the same slots and
+/// listener IDs, regardless of how their instructions are arranged.
+pub fn semantically_eq(a: &[u8], b: &[u8]) -> Result<bool, InterpretError> {
+    let tree = interpret(a)?;
+
+    // Identical bytes render identically, but only once they are known to
+    // render at all.
+    if a == b {
+        return Ok(true);
+    }
+
+    let (a, b) = (tree, interpret(b)?);
+
+    Ok(a.equivalent(&b) && canonical_instructions(&a) == canonical_instructions(&b))
+}
+
+/// Compares two buffers by what they render. Returns 1 if they are
+/// semantically equal, 0 if not and -1 if either does not interpret.
+///
+/// # Safety
+///
+/// `a` and `b` must each be null or point to a valid buffer.
+#[no_mangle]
+pub unsafe extern "C" fn librender_semantically_eq(
+    a: *const librender_bytecode_buffer,
+    b: *const librender_bytecode_buffer,
+) -> libc::c_int {
+    match semantically_eq(buffer_bytes(a), buffer_bytes(b)) {
+        Ok(true) => 1,
+        Ok(false) => 0,
+        Err(_) => -1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::decode::decode;
+
+    #[test]
+    fn splits_merged_text_longer_than_a_payload() {
+        let text = "a".repeat(200);
+        let program = assemble(&format!(
+            "create_element \"p\"\n\
+             text_node \"{text}\"\nappend_child\n\
+             text_node \"{text}\"\nappend_child\n"
+        ))
+        .unwrap();
+
+        let canonical = canonicalize(&program).unwrap();
+        let texts: Vec<_> = decode(&canonical)
+            .unwrap()
+            .into_iter()
+            .filter_map(|i| match i {
+                Instruction::TextNode(text) => Some(text.len()),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(texts, [MAX_PAYLOAD_LENGTH, 400 - MAX_PAYLOAD_LENGTH]);
+        assert!(semantically_eq(&program, &canonical).unwrap());
+    }
+
+    #[test]
+    fn rejects_identical_malformed_programs() {
+        let program = assemble("create_element \"div\"\nappend_child").unwrap();
+
+        assert!(semantically_eq(&program, &program).is_err());
+        assert!(semantically_eq(&[0xee], &[0xee]).is_err());
+    }
+
+    #[test]
+    fn compares_by_rendered_tree() {
+        let a = assemble(
+            "create_element \"p\"\nnop\n\
+             set_attribute \"id\" \"x\"\nset_attribute \"class\" \"y\"\n\
+             text_node \"a\"\nappend_child\ntext_node \"b\"\nappend_child",
+        )
+        .unwrap();
+        let b = assemble(
+            "create_element \"p\"\n\
+             set_attribute \"class\" \"y\"\nset_attribute \"id\" \"x\"\n\
+             text_node \"ab\"\nappend_child",
+        )
+        .unwrap();
+        let c = assemble("create_element \"p\"\ntext_node \"ab\"\nappend_child").unwrap();
+
+        assert!(semantically_eq(&a, &b).unwrap());
+        assert!(!semantically_eq(&a, &c).unwrap());
+        assert_eq!(canonicalize(&a).unwrap(), canonicalize(&b).unwrap());
+    }
+}
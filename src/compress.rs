@@ -0,0 +1,359 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Opcode-aware compression for shipped programs.
+//!
+//! Programs are dominated by short length-prefixed strings that repeat a lot
+//! (tag names, attribute names, class names), which generic compressors
+//! handle poorly at these sizes. A compressed program splits instructions
+//! into two streams:
+//!
+//! - the opcode stream, two opcodes per byte (every opcode fits in a nibble)
+//! - the string stream, where each payload is either a reference into the
+//!   dictionary or a literal that is appended to the dictionary
+//!
+//! The dictionary starts out with [`PRESET`] when `FLAG_PRESET_DICTIONARY`
+//! is set, so common names cost a single byte even on first use.
+//!
+//! ```text
+//! magic "LRZ" | version | flags | varint count | opcodes | strings
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::decode::{decode, opcode_arity, DecodeError, MAX_PAYLOAD_LENGTH};
+
+pub const MAGIC: &[u8; 3] = b"LRZ";
+pub const VERSION: u8 = 1;
+
+/// The string dictionary is seeded with [`PRESET`].
+pub const FLAG_PRESET_DICTIONARY: u8 = 1 << 0;
+
+/// Strings shared by encoder and decoder without being shipped.
+#[rustfmt::skip]
+pub const PRESET: &[&str] = &[
+    "div", "span", "p", "a", "button", "input", "img", "ul", "ol", "li", "table", "thead",
+    "tbody", "tr", "td", "th", "section", "header", "footer", "article", "aside", "nav",
+    "main", "h1", "h2", "h3", "h4", "h5", "h6", "pre", "code", "label", "form", "select",
+    "option", "textarea", "br", "hr", "id", "class", "style", "href", "src", "alt", "type",
+    "name", "value", "title", "role", "click", "change", "submit", "keydown", "keyup",
+    "mouseenter", "mouseleave", "focus", "blur", "color", "display", "margin", "padding",
+    "width", "height", "none", "flex", "block",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecompressError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownFlags(u8),
+    /// The input ends in the middle of the given section.
+    Truncated(&'static str),
+    UnknownOpcode(u8),
+    /// A string reference points past the end of the dictionary.
+    BadReference(usize),
+    /// Bytes remain after the last instruction.
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecompressError::BadMagic => write!(f, "not a compressed program"),
+            DecompressError::UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            DecompressError::UnknownFlags(flags) => write!(f, "unknown flags 0x{:02x}", flags),
+            DecompressError::Truncated(section) => write!(f, "truncated {}", section),
+            DecompressError::UnknownOpcode(op) => write!(f, "unknown opcode 0x{:02x}", op),
+            DecompressError::BadReference(r) => write!(f, "string reference {} out of range", r),
+            DecompressError::TrailingBytes(n) => write!(f, "{} trailing bytes", n),
+        }
+    }
+}
+
+impl std::error::Error for DecompressError {}
+
+/// Whether `data` starts with the compressed program header. Raw programs
+/// never do, since `L` is not an opcode.
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub(crate) fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+
+    out.push(value as u8);
+}
+
+pub(crate) fn read_varint(data: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut value = 0usize;
+
+    for shift in (0..usize::BITS).step_by(7) {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+struct Dictionary {
+    strings: Vec<Vec<u8>>,
+    index: HashMap<Vec<u8>, usize>,
+}
+
+impl Dictionary {
+    fn new(flags: u8) -> Self {
+        let mut dictionary = Dictionary {
+            strings: Vec::new(),
+            index: HashMap::new(),
+        };
+
+        if flags & FLAG_PRESET_DICTIONARY != 0 {
+            for s in PRESET {
+                dictionary.insert(s.as_bytes().to_vec());
+            }
+        }
+
+        dictionary
+    }
+
+    fn insert(&mut self, s: Vec<u8>) {
+        self.index.entry(s.clone()).or_insert(self.strings.len());
+        self.strings.push(s);
+    }
+}
+
+pub fn compress_with(program: &[u8], flags: u8) -> Result<Vec<u8>, DecodeError> {
+    let instructions = decode(program)?;
+    let mut dictionary = Dictionary::new(flags);
+    let mut opcodes = Vec::with_capacity(instructions.len().div_ceil(2));
+    let mut strings = Vec::new();
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        if i % 2 == 0 {
+            opcodes.push(instruction.opcode());
+        } else {
+            *opcodes.last_mut().unwrap() |= instruction.opcode() << 4;
+        }
+
+        // reference 0 introduces a literal, n refers to dictionary entry n - 1
+        for payload in instruction.payloads() {
+            match dictionary.index.get(payload) {
+                Some(&n) => write_varint(&mut strings, n + 1),
+                None => {
+                    strings.push(0);
+                    strings.push(payload.len() as u8);
+                    strings.extend_from_slice(payload);
+                    dictionary.insert(payload.to_vec());
+                }
+            }
+        }
+    }
+
+    let mut out = Vec::with_capacity(8 + opcodes.len() + strings.len());
+
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    out.push(flags);
+    write_varint(&mut out, instructions.len());
+    out.extend_from_slice(&opcodes);
+    out.extend_from_slice(&strings);
+
+    Ok(out)
+}
+
+pub fn compress(program: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    compress_with(program, FLAG_PRESET_DICTIONARY)
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, DecompressError> {
+    if !is_compressed(data) {
+        return Err(DecompressError::BadMagic);
+    }
+
+    let version = *data.get(3).ok_or(DecompressError::Truncated("header"))?;
+    let flags = *data.get(4).ok_or(DecompressError::Truncated("header"))?;
+
+    if version != VERSION {
+        return Err(DecompressError::UnsupportedVersion(version));
+    }
+
+    if flags & !FLAG_PRESET_DICTIONARY != 0 {
+        return Err(DecompressError::UnknownFlags(flags));
+    }
+
+    let mut pos = 5;
+    let count = read_varint(data, &mut pos).ok_or(DecompressError::Truncated("header"))?;
+    let opcodes = pos
+        .checked_add(count.div_ceil(2))
+        .and_then(|end| data.get(pos..end))
+        .ok_or(DecompressError::Truncated("opcode stream"))?;
+
+    pos += opcodes.len();
+
+    let mut dictionary = Dictionary::new(flags);
+    let mut program = Vec::with_capacity(data.len() * 2);
+
+    for i in 0..count {
+        let opcode = (opcodes[i / 2] >> (4 * (i % 2))) & 0x0f;
+        let arity = opcode_arity(opcode).ok_or(DecompressError::UnknownOpcode(opcode))?;
+
+        program.push(opcode);
+
+        for _ in 0..arity {
+            let reference =
+                read_varint(data, &mut pos).ok_or(DecompressError::Truncated("string stream"))?;
+
+            let payload = if reference == 0 {
+                let length = *data
+                    .get(pos)
+                    .ok_or(DecompressError::Truncated("string stream"))?
+                    as usize;
+                let payload = data
+                    .get(pos + 1..pos + 1 + length)
+                    .ok_or(DecompressError::Truncated("string stream"))?
+                    .to_vec();
+
+                pos += 1 + length;
+                dictionary.insert(payload.clone());
+                payload
+            } else {
+                dictionary
+                    .strings
+                    .get(reference - 1)
+                    .filter(|s| s.len() <= MAX_PAYLOAD_LENGTH)
+                    .ok_or(DecompressError::BadReference(reference))?
+                    .clone()
+            };
+
+            program.push(payload.len() as u8);
+            program.extend_from_slice(&payload);
+        }
+    }
+
+    if pos != data.len() {
+        return Err(DecompressError::TrailingBytes(data.len() - pos));
+    }
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::decode::{encode, Instruction};
+
+    const PROGRAM: &str = r#"
+        create_element "div"
+        set_attribute "class" "card"
+        create_element "h2"
+        text_node "Title"
+        append_child
+        append_child
+        create_element "p"
+        set_attribute "class" "card-body"
+        style "color" "red"
+        text_node "Body"
+        append_child
+        event_listener "click"
+        append_child
+    "#;
+
+    #[test]
+    fn round_trips() {
+        let program = assemble(PROGRAM).unwrap();
+
+        for flags in [0, FLAG_PRESET_DICTIONARY] {
+            let compressed = compress_with(&program, flags).unwrap();
+
+            assert!(is_compressed(&compressed));
+            assert_eq!(decompress(&compressed).unwrap(), program);
+        }
+    }
+
+    #[test]
+    fn round_trips_empty_and_odd_instruction_counts() {
+        for program in [
+            Vec::new(),
+            assemble("nop").unwrap(),
+            assemble("nop\nnop").unwrap(),
+        ] {
+            assert_eq!(decompress(&compress(&program).unwrap()).unwrap(), program);
+        }
+    }
+
+    #[test]
+    fn preset_strings_cost_one_byte() {
+        let program = assemble(r#"create_element "div""#).unwrap();
+        let with_preset = compress(&program).unwrap();
+        let without = compress_with(&program, 0).unwrap();
+
+        assert_eq!(without.len() - with_preset.len(), 4);
+    }
+
+    #[test]
+    fn grows_the_dictionary_past_single_byte_references() {
+        // Each distinct class is a literal once and a reference after that,
+        // with references above 127 taking two varint bytes.
+        let classes: Vec<Vec<u8>> = (0..300).map(|i| format!("c{}", i).into_bytes()).collect();
+        let mut instructions = vec![Instruction::CreateElement(b"div".to_vec())];
+
+        for _ in 0..2 {
+            for class in &classes {
+                instructions.push(Instruction::SetAttribute(b"class".to_vec(), class.clone()));
+            }
+        }
+
+        let program = encode(&instructions);
+        let compressed = compress_with(&program, 0).unwrap();
+
+        assert_eq!(decompress(&compressed).unwrap(), program);
+        assert!(compressed.len() < program.len() / 2);
+    }
+
+    #[test]
+    fn round_trips_longest_payload() {
+        let program = encode(&[
+            Instruction::TextNode(vec![b'x'; MAX_PAYLOAD_LENGTH]),
+            Instruction::TextNode(vec![b'x'; MAX_PAYLOAD_LENGTH]),
+        ]);
+
+        assert_eq!(decompress(&compress(&program).unwrap()).unwrap(), program);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let compressed = compress(&assemble(PROGRAM).unwrap()).unwrap();
+
+        assert_eq!(decompress(b"div"), Err(DecompressError::BadMagic));
+        assert_eq!(
+            decompress(b"LRZ\x02\x00\x00"),
+            Err(DecompressError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            decompress(b"LRZ\x01\x80\x00"),
+            Err(DecompressError::UnknownFlags(0x80))
+        );
+        assert_eq!(
+            decompress(&compressed[..compressed.len() - 1]),
+            Err(DecompressError::Truncated("string stream"))
+        );
+        assert_eq!(
+            decompress(&[&compressed[..], b"\0"].concat()),
+            Err(DecompressError::TrailingBytes(1))
+        );
+        // One create_element referring to the first entry of an empty
+        // dictionary.
+        assert_eq!(
+            decompress(b"LRZ\x01\x00\x01\x01\x01"),
+            Err(DecompressError::BadReference(1))
+        );
+    }
+}
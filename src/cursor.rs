@@ -0,0 +1,651 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Instruction-granular editing of programs.
+//!
+//! A [`Cursor`] sits on an instruction boundary of a program: before the
+//! instruction at [`Cursor::index`], or at the end. Every edit replaces
+//! whole instructions, so the program keeps decoding no matter what is
+//! inserted or deleted. Whether it still interprets is up to the edits;
+//! deleting an `append_child`, for example, leaves an orphan.
+//!
+//! The same cursor works on a `Vec<u8>` and, through the `librender_cursor_*`
+//! functions, on a `librender_bytecode_buffer`.
+
+use std::fmt;
+use std::ops::Range;
+
+use crate::decode::{decode, decode_at, DecodeError, Instruction, MAX_PAYLOAD_LENGTH};
+use crate::interp::{interpret, InterpretError, NodeId};
+use crate::{buffer_bytes, librender_bytecode_buffer, librender_splice, size_t, uint8_t};
+
+/// Byte storage a [`Cursor`] can edit.
+pub trait ProgramBuffer {
+    fn bytes(&self) -> &[u8];
+
+    /// Replaces `range` with `bytes`. Returns `false` if the buffer refuses
+    /// the edit.
+    fn splice(&mut self, range: Range<usize>, bytes: &[u8]) -> bool;
+}
+
+impl ProgramBuffer for Vec<u8> {
+    fn bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn splice(&mut self, range: Range<usize>, bytes: &[u8]) -> bool {
+        Vec::splice(self, range, bytes.iter().copied());
+        true
+    }
+}
+
+impl<T: ProgramBuffer + ?Sized> ProgramBuffer for &mut T {
+    fn bytes(&self) -> &[u8] {
+        (**self).bytes()
+    }
+
+    fn splice(&mut self, range: Range<usize>, bytes: &[u8]) -> bool {
+        (**self).splice(range, bytes)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CursorError {
+    /// The program does not decode.
+    Decode(DecodeError),
+    Interpret(InterpretError),
+    /// The cursor is past the last instruction.
+    AtEnd,
+    /// The instruction has no payload with this index.
+    NoPayload(usize),
+    PayloadTooLong(usize),
+    /// No instruction has this index, or no node this ID.
+    OutOfRange(usize),
+    /// The buffer refused the edit, for example because it is locked.
+    Rejected,
+}
+
+impl fmt::Display for CursorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CursorError::Decode(err) => err.fmt(f),
+            CursorError::Interpret(err) => err.fmt(f),
+            CursorError::AtEnd => write!(f, "cursor is at the end of the program"),
+            CursorError::NoPayload(index) => write!(f, "instruction has no payload {}", index),
+            CursorError::PayloadTooLong(length) => write!(
+                f,
+                "payload of {} bytes exceeds {} bytes",
+                length, MAX_PAYLOAD_LENGTH
+            ),
+            CursorError::OutOfRange(n) => write!(f, "{} is out of range", n),
+            CursorError::Rejected => write!(f, "buffer rejected the edit"),
+        }
+    }
+}
+
+impl std::error::Error for CursorError {}
+
+impl From<DecodeError> for CursorError {
+    fn from(err: DecodeError) -> Self {
+        CursorError::Decode(err)
+    }
+}
+
+impl From<InterpretError> for CursorError {
+    fn from(err: InterpretError) -> Self {
+        CursorError::Interpret(err)
+    }
+}
+
+pub struct Cursor<B: ProgramBuffer> {
+    buffer: B,
+    offset: usize,
+    index: usize,
+}
+
+impl<B: ProgramBuffer> Cursor<B> {
+    /// Places a cursor before the first instruction of `buffer`, which must
+    /// decode.
+    pub fn new(buffer: B) -> Result<Self, CursorError> {
+        decode(buffer.bytes())?;
+
+        Ok(Self {
+            buffer,
+            offset: 0,
+            index: 0,
+        })
+    }
+
+    pub fn get_ref(&self) -> &B {
+        &self.buffer
+    }
+
+    pub fn into_inner(self) -> B {
+        self.buffer
+    }
+
+    pub fn program(&self) -> &[u8] {
+        self.buffer.bytes()
+    }
+
+    /// Byte offset of the current instruction.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Index of the current instruction.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.offset >= self.program().len()
+    }
+
+    /// The instruction after the cursor and its encoded length. The buffer
+    /// may have been edited behind the cursor's back since it was checked,
+    /// so this decodes again rather than trusting it.
+    fn peek(&self) -> Result<Option<(Instruction, usize)>, CursorError> {
+        if self.is_at_end() {
+            Ok(None)
+        } else {
+            Ok(Some(decode_at(self.program(), self.offset)?))
+        }
+    }
+
+    /// The instruction after the cursor, or `None` at the end.
+    pub fn current(&self) -> Result<Option<Instruction>, CursorError> {
+        Ok(self.peek()?.map(|(instruction, _)| instruction))
+    }
+
+    /// Moves past the current instruction. Returns `false` at the end.
+    pub fn advance(&mut self) -> Result<bool, CursorError> {
+        match self.peek()? {
+            Some((_, len)) => {
+                self.offset += len;
+                self.index += 1;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Moves before instruction `n`, or to the end if `n` is the number of
+    /// instructions.
+    pub fn seek(&mut self, n: usize) -> Result<(), CursorError> {
+        if n < self.index {
+            self.offset = 0;
+            self.index = 0;
+        }
+
+        while self.index < n {
+            if !self.advance()? {
+                return Err(CursorError::OutOfRange(n));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves before the instruction that created node `id`. Call
+    /// [`Cursor::advance`] to move past it, for example to add attributes to
+    /// the element.
+    pub fn seek_node(&mut self, id: NodeId) -> Result<(), CursorError> {
+        let tree = interpret(self.program())?;
+        let offset = tree
+            .nodes
+            .get(id)
+            .map(|node| node.offset)
+            .ok_or(CursorError::OutOfRange(id))?;
+
+        if offset < self.offset {
+            self.offset = 0;
+            self.index = 0;
+        }
+
+        while self.offset < offset && self.advance()? {}
+
+        Ok(())
+    }
+
+    fn splice(&mut self, range: Range<usize>, bytes: &[u8]) -> Result<(), CursorError> {
+        if self.buffer.splice(range, bytes) {
+            Ok(())
+        } else {
+            Err(CursorError::Rejected)
+        }
+    }
+
+    /// Inserts `instruction` before the cursor and moves past it.
+    pub fn insert(&mut self, instruction: &Instruction) -> Result<(), CursorError> {
+        check_payloads(instruction)?;
+
+        let mut bytes = Vec::with_capacity(instruction.encoded_len());
+        instruction.encode_into(&mut bytes);
+
+        self.splice(self.offset..self.offset, &bytes)?;
+        self.offset += bytes.len();
+        self.index += 1;
+
+        Ok(())
+    }
+
+    /// Removes the current instruction and returns it.
+    pub fn delete(&mut self) -> Result<Instruction, CursorError> {
+        let (instruction, len) = self.peek()?.ok_or(CursorError::AtEnd)?;
+
+        self.splice(self.offset..self.offset + len, &[])?;
+        Ok(instruction)
+    }
+
+    /// Replaces the current instruction, staying before it.
+    pub fn replace(&mut self, instruction: &Instruction) -> Result<Instruction, CursorError> {
+        check_payloads(instruction)?;
+
+        let (old, len) = self.peek()?.ok_or(CursorError::AtEnd)?;
+        let mut bytes = Vec::with_capacity(instruction.encoded_len());
+        instruction.encode_into(&mut bytes);
+
+        self.splice(self.offset..self.offset + len, &bytes)?;
+        Ok(old)
+    }
+
+    /// Replaces payload `index` of the current instruction, such as the
+    /// value of a `set_attribute`, rewriting its length prefix.
+    pub fn replace_payload(&mut self, index: usize, payload: &[u8]) -> Result<(), CursorError> {
+        if payload.len() > MAX_PAYLOAD_LENGTH {
+            return Err(CursorError::PayloadTooLong(payload.len()));
+        }
+
+        let (instruction, _) = self.peek()?.ok_or(CursorError::AtEnd)?;
+        let payloads = instruction.payloads();
+
+        if index >= payloads.len() {
+            return Err(CursorError::NoPayload(index));
+        }
+
+        let start = self.offset + 1 + payloads[..index].iter().map(|p| 1 + p.len()).sum::<usize>();
+        let end = start + 1 + payloads[index].len();
+        let mut bytes = Vec::with_capacity(1 + payload.len());
+
+        bytes.push(payload.len() as u8);
+        bytes.extend_from_slice(payload);
+
+        self.splice(start..end, &bytes)
+    }
+}
+
+fn check_payloads(instruction: &Instruction) -> Result<(), CursorError> {
+    match instruction
+        .payloads()
+        .into_iter()
+        .find(|p| p.len() > MAX_PAYLOAD_LENGTH)
+    {
+        Some(payload) => Err(CursorError::PayloadTooLong(payload.len())),
+        None => Ok(()),
+    }
+}
+
+/// A `librender_bytecode_buffer` edited through `librender_splice`.
+pub struct BufferRef(*mut librender_bytecode_buffer);
+
+impl ProgramBuffer for BufferRef {
+    fn bytes(&self) -> &[u8] {
+        unsafe { buffer_bytes(self.0) }
+    }
+
+    fn splice(&mut self, range: Range<usize>, bytes: &[u8]) -> bool {
+        unsafe {
+            librender_splice(
+                self.0,
+                range.start as size_t,
+                range.len() as size_t,
+                bytes.as_ptr() as *const uint8_t,
+                bytes.len() as size_t,
+            ) == 0
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub type librender_cursor = Cursor<BufferRef>;
+
+/// Creates a cursor before the first instruction of `buf`. Returns null if
+/// the buffer does not hold a well-formed program. The buffer must outlive
+/// the cursor and only be edited through it while the cursor exists.
+///
+/// # Safety
+///
+/// `buf` must be null or point to a valid buffer that outlives the cursor.
+#[no_mangle]
+pub unsafe extern "C" fn librender_cursor_create(
+    buf: *mut librender_bytecode_buffer,
+) -> *mut librender_cursor {
+    if buf.is_null() {
+        return std::ptr::null_mut();
+    }
+
+    match Cursor::new(BufferRef(buf)) {
+        Ok(cursor) => Box::into_raw(Box::new(cursor)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `cursor` must be null or a cursor from `librender_cursor_create` that
+/// is not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn librender_cursor_free(cursor: *mut librender_cursor) {
+    if !cursor.is_null() {
+        drop(Box::from_raw(cursor));
+    }
+}
+
+/// # Safety
+///
+/// `cursor` must be null or a live cursor.
+#[no_mangle]
+pub unsafe extern "C" fn librender_cursor_offset(cursor: *const librender_cursor) -> size_t {
+    match cursor.as_ref() {
+        Some(cursor) => cursor.offset() as size_t,
+        None => 0,
+    }
+}
+
+/// # Safety
+///
+/// `cursor` must be null or a live cursor.
+#[no_mangle]
+pub unsafe extern "C" fn librender_cursor_index(cursor: *const librender_cursor) -> size_t {
+    match cursor.as_ref() {
+        Some(cursor) => cursor.index() as size_t,
+        None => 0,
+    }
+}
+
+/// Opcode of the current instruction, or -1 at the end or if it does not
+/// decode.
+///
+/// # Safety
+///
+/// `cursor` must be null or a live cursor.
+#[no_mangle]
+pub unsafe extern "C" fn librender_cursor_opcode(cursor: *const librender_cursor) -> libc::c_int {
+    match cursor
+        .as_ref()
+        .and_then(|cursor| cursor.current().ok().flatten())
+    {
+        Some(instruction) => instruction.opcode() as libc::c_int,
+        None => -1,
+    }
+}
+
+/// Moves past the current instruction. Returns 0, or -1 at the end or if
+/// it does not decode.
+///
+/// # Safety
+///
+/// `cursor` must be null or a live cursor.
+#[no_mangle]
+pub unsafe extern "C" fn librender_cursor_advance(cursor: *mut librender_cursor) -> libc::c_int {
+    match cursor.as_mut().map(|cursor| cursor.advance()) {
+        Some(Ok(true)) => 0,
+        _ => -1,
+    }
+}
+
+fn status(result: Result<(), CursorError>) -> libc::c_int {
+    match result {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// # Safety
+///
+/// `cursor` must be null or a live cursor.
+#[no_mangle]
+pub unsafe extern "C" fn librender_cursor_seek(
+    cursor: *mut librender_cursor,
+    n: size_t,
+) -> libc::c_int {
+    match cursor.as_mut() {
+        Some(cursor) => status(cursor.seek(n as usize)),
+        None => -1,
+    }
+}
+
+/// # Safety
+///
+/// `cursor` must be null or a live cursor.
+#[no_mangle]
+pub unsafe extern "C" fn librender_cursor_seek_node(
+    cursor: *mut librender_cursor,
+    id: size_t,
+) -> libc::c_int {
+    match cursor.as_mut() {
+        Some(cursor) => status(cursor.seek_node(id as usize)),
+        None => -1,
+    }
+}
+
+unsafe fn payload(bytes: *const uint8_t, length: uint8_t) -> Vec<u8> {
+    if bytes.is_null() {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(bytes, length as usize).to_vec()
+    }
+}
+
+/// Inserts an instruction before the cursor and moves past it. Payloads
+/// the opcode does not take are ignored. Returns 0, or -1 if the opcode is
+/// unknown or the buffer is locked.
+///
+/// # Safety
+///
+/// `cursor` must be null or a live cursor, and each payload pointer must
+/// be null or point to as many bytes as its length.
+#[no_mangle]
+pub unsafe extern "C" fn librender_cursor_insert(
+    cursor: *mut librender_cursor,
+    opcode: uint8_t,
+    first: *const uint8_t,
+    first_length: uint8_t,
+    second: *const uint8_t,
+    second_length: uint8_t,
+) -> libc::c_int {
+    let Some(cursor) = cursor.as_mut() else {
+        return -1;
+    };
+
+    let mut payloads = vec![payload(first, first_length), payload(second, second_length)];
+
+    payloads.truncate(crate::decode::opcode_arity(opcode).unwrap_or(0));
+
+    match Instruction::from_parts(opcode, payloads) {
+        Some(instruction) => status(cursor.insert(&instruction)),
+        None => -1,
+    }
+}
+
+/// Removes the current instruction. Returns 0, or -1 at the end or if the
+/// buffer is locked.
+///
+/// # Safety
+///
+/// `cursor` must be null or a live cursor.
+#[no_mangle]
+pub unsafe extern "C" fn librender_cursor_delete(cursor: *mut librender_cursor) -> libc::c_int {
+    match cursor.as_mut() {
+        Some(cursor) => status(cursor.delete().map(|_| ())),
+        None => -1,
+    }
+}
+
+/// # Safety
+///
+/// `cursor` must be null or a live cursor, and `bytes` must be null or
+/// point to `length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn librender_cursor_replace_payload(
+    cursor: *mut librender_cursor,
+    index: size_t,
+    bytes: *const uint8_t,
+    length: uint8_t,
+) -> libc::c_int {
+    match cursor.as_mut() {
+        Some(cursor) => status(cursor.replace_payload(index as usize, &payload(bytes, length))),
+        None => -1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::decode::DecodeErrorKind;
+    use crate::{buffer_from_bytes, librender_append_byte, librender_free_buffer};
+
+    const LIST: &str = r#"
+        create_element "ul"
+        create_element "li"
+        text_node "one"
+        append_child
+        append_child
+    "#;
+
+    #[test]
+    fn edits_whole_instructions() {
+        let mut cursor = Cursor::new(assemble(LIST).unwrap()).unwrap();
+
+        cursor.advance().unwrap();
+        cursor
+            .insert(&Instruction::SetAttribute(b"id".to_vec(), b"list".to_vec()))
+            .unwrap();
+        assert_eq!(cursor.index(), 2);
+
+        cursor.seek(3).unwrap();
+        cursor.replace_payload(0, b"two").unwrap();
+        assert_eq!(
+            cursor.current().unwrap(),
+            Some(Instruction::TextNode(b"two".to_vec()))
+        );
+
+        cursor.seek(0).unwrap();
+        cursor.seek_node(1).unwrap();
+        assert_eq!(cursor.index(), 2);
+        assert_eq!(
+            cursor.replace(&Instruction::CreateElement(b"ol".to_vec())),
+            Ok(Instruction::CreateElement(b"li".to_vec()))
+        );
+
+        cursor.seek(6).unwrap();
+        assert!(cursor.is_at_end());
+        assert_eq!(cursor.delete(), Err(CursorError::AtEnd));
+        assert_eq!(cursor.seek(7), Err(CursorError::OutOfRange(7)));
+
+        let expected = assemble(
+            r#"
+            create_element "ul"
+            set_attribute "id" "list"
+            create_element "ol"
+            text_node "two"
+            append_child
+            append_child
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(cursor.program(), expected);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn rejects_edits_to_locked_buffers() {
+        unsafe {
+            let buf = buffer_from_bytes(&assemble(LIST).unwrap());
+            let mut cursor = Cursor::new(BufferRef(buf)).unwrap();
+
+            crate::librender_lock_buffer(buf);
+            assert_eq!(cursor.delete(), Err(CursorError::Rejected));
+            assert_eq!(cursor.index(), 0);
+
+            librender_free_buffer(buf);
+        }
+    }
+
+    #[test]
+    fn reports_buffers_corrupted_behind_its_back() {
+        unsafe {
+            let buf = buffer_from_bytes(&assemble(LIST).unwrap());
+            let cursor = librender_cursor_create(buf);
+
+            librender_append_byte(buf, 0xee);
+            assert_eq!(librender_cursor_seek(cursor, 5), 0);
+            assert_eq!(librender_cursor_opcode(cursor), -1);
+            assert_eq!(librender_cursor_advance(cursor), -1);
+            assert_eq!(librender_cursor_delete(cursor), -1);
+
+            let err = (*cursor).current().unwrap_err();
+            assert!(matches!(
+                err,
+                CursorError::Decode(DecodeError {
+                    kind: DecodeErrorKind::UnknownOpcode(0xee),
+                    ..
+                })
+            ));
+
+            librender_cursor_free(cursor);
+            librender_free_buffer(buf);
+        }
+    }
+
+    #[test]
+    fn edits_buffers_through_the_c_functions() {
+        unsafe {
+            let buf = buffer_from_bytes(&assemble(LIST).unwrap());
+            let cursor = librender_cursor_create(buf);
+
+            assert_eq!(librender_cursor_opcode(cursor), 0x01);
+            assert_eq!(librender_cursor_advance(cursor), 0);
+            assert_eq!(
+                librender_cursor_insert(cursor, 0x09, b"color".as_ptr(), 5, b"red".as_ptr(), 3),
+                0
+            );
+            assert_eq!(librender_cursor_index(cursor), 2);
+            assert_eq!(librender_cursor_offset(cursor), 15);
+            assert_eq!(
+                librender_cursor_insert(cursor, 0xee, std::ptr::null(), 0, std::ptr::null(), 0),
+                -1
+            );
+
+            assert_eq!(librender_cursor_seek(cursor, 3), 0);
+            assert_eq!(
+                librender_cursor_replace_payload(cursor, 0, b"uno".as_ptr(), 3),
+                0
+            );
+            assert_eq!(
+                librender_cursor_replace_payload(cursor, 1, b"x".as_ptr(), 1),
+                -1
+            );
+
+            let expected = assemble(
+                r#"
+                create_element "ul"
+                style "color" "red"
+                create_element "li"
+                text_node "uno"
+                append_child
+                append_child
+                "#,
+            )
+            .unwrap();
+
+            assert_eq!(buffer_bytes(buf), expected);
+            assert!(librender_cursor_create(std::ptr::null_mut()).is_null());
+
+            librender_cursor_free(cursor);
+            librender_free_buffer(buf);
+        }
+    }
+}
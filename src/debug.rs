@@ -0,0 +1,487 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Debug information mapping program offsets to source components.
+//!
+//! A debug section lists byte ranges of the program together with the
+//! component that emitted them and, optionally, a `file:line` location.
+//! Ranges nest the way components do. The section can be kept in a separate
+//! file or appended to the program as a trailer:
+//!
+//! ```text
+//! program | section | section length: u32 LE
+//! section: magic "LRD" | version | varint count | entries
+//! entry: varint start | varint end | string name | string file | varint line
+//! ```
+//!
+//! Strings are a varint length followed by UTF-8 bytes; an empty file means
+//! no location. The VM does not understand trailers, so [`split`] them off
+//! before shipping a program.
+
+use std::fmt;
+use std::ops::Range;
+
+use crate::compress::{read_varint, write_varint};
+use crate::stats::Component;
+
+pub const MAGIC: &[u8; 3] = b"LRD";
+pub const VERSION: u8 = 1;
+
+/// Length of the section length that ends a trailer.
+pub const TRAILER_LENGTH: usize = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugEntry {
+    pub range: Range<usize>,
+    pub name: String,
+    pub location: Option<SourceLocation>,
+}
+
+impl fmt::Display for DebugEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+
+        match &self.location {
+            Some(location) => write!(f, " ({})", location),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DebugInfoError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    /// A name or file is not valid UTF-8.
+    BadString,
+    /// An entry ends before it starts.
+    BadRange {
+        start: usize,
+        end: usize,
+    },
+}
+
+impl fmt::Display for DebugInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DebugInfoError::BadMagic => write!(f, "not a debug section"),
+            DebugInfoError::UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            DebugInfoError::Truncated => write!(f, "truncated debug section"),
+            DebugInfoError::BadString => write!(f, "invalid UTF-8 in debug section"),
+            DebugInfoError::BadRange { start, end } => {
+                write!(f, "invalid range 0x{:x}..0x{:x}", start, end)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DebugInfoError {}
+
+/// Entries sorted by start offset, outer components before the components
+/// nested in them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    entries: Vec<DebugEntry>,
+}
+
+impl DebugInfo {
+    pub fn new(mut entries: Vec<DebugEntry>) -> Self {
+        entries.sort_by(|a, b| {
+            a.range
+                .start
+                .cmp(&b.range.start)
+                .then(b.range.end.cmp(&a.range.end))
+        });
+
+        Self { entries }
+    }
+
+    pub fn entries(&self) -> &[DebugEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Components containing `offset`, outermost first.
+    pub fn lookup(&self, offset: usize) -> Vec<&DebugEntry> {
+        self.entries
+            .iter()
+            .take_while(|e| e.range.start <= offset)
+            .filter(|e| e.range.contains(&offset))
+            .collect()
+    }
+
+    /// Components that start at `offset`, outermost first.
+    pub fn starting_at(&self, offset: usize) -> impl Iterator<Item = &DebugEntry> {
+        self.entries.iter().filter(move |e| e.range.start == offset)
+    }
+
+    /// Describes where `offset` is, such as `in Card (card.tsx:4) > Button`.
+    pub fn context(&self, offset: usize) -> Option<String> {
+        let entries = self.lookup(offset);
+
+        if entries.is_empty() {
+            return None;
+        }
+
+        let path: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+        Some(format!("in {}", path.join(" > ")))
+    }
+
+    /// Appends the context of `offset` to a diagnostic message.
+    pub fn annotate(&self, message: impl fmt::Display, offset: usize) -> String {
+        match self.context(offset) {
+            Some(context) => format!("{} {}", message, context),
+            None => message.to_string(),
+        }
+    }
+
+    /// Entries as components for [`crate::stats::stats_with_components`].
+    pub fn components(&self) -> Vec<Component> {
+        self.entries
+            .iter()
+            .map(|e| Component {
+                name: e.name.clone(),
+                range: e.range.clone(),
+            })
+            .collect()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        write_varint(&mut out, self.entries.len());
+
+        for entry in &self.entries {
+            let (file, line) = match &entry.location {
+                Some(location) => (location.file.as_str(), location.line as usize),
+                None => ("", 0),
+            };
+
+            write_varint(&mut out, entry.range.start);
+            write_varint(&mut out, entry.range.end);
+            write_varint(&mut out, entry.name.len());
+            out.extend_from_slice(entry.name.as_bytes());
+            write_varint(&mut out, file.len());
+            out.extend_from_slice(file.as_bytes());
+            write_varint(&mut out, line);
+        }
+
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DebugInfoError> {
+        if !data.starts_with(MAGIC) {
+            return Err(DebugInfoError::BadMagic);
+        }
+
+        match data.get(3) {
+            None => return Err(DebugInfoError::Truncated),
+            Some(&VERSION) => {}
+            Some(&version) => return Err(DebugInfoError::UnsupportedVersion(version)),
+        }
+
+        let mut pos = 4;
+        let mut varint = |pos: &mut usize| read_varint(data, pos).ok_or(DebugInfoError::Truncated);
+        let string = |pos: &mut usize, length: usize| {
+            let bytes = pos
+                .checked_add(length)
+                .and_then(|end| data.get(*pos..end))
+                .ok_or(DebugInfoError::Truncated)?;
+
+            *pos += length;
+            String::from_utf8(bytes.to_vec()).map_err(|_| DebugInfoError::BadString)
+        };
+
+        let count = varint(&mut pos)?;
+        let mut entries = Vec::new();
+
+        for _ in 0..count {
+            let start = varint(&mut pos)?;
+            let end = varint(&mut pos)?;
+
+            if end < start {
+                return Err(DebugInfoError::BadRange { start, end });
+            }
+
+            let length = varint(&mut pos)?;
+            let name = string(&mut pos, length)?;
+            let length = varint(&mut pos)?;
+            let file = string(&mut pos, length)?;
+            let line = varint(&mut pos)?;
+
+            entries.push(DebugEntry {
+                range: start..end,
+                name,
+                location: (!file.is_empty()).then_some(SourceLocation {
+                    file,
+                    line: line as u32,
+                }),
+            });
+        }
+
+        Ok(Self::new(entries))
+    }
+
+    /// Appends the section to `program` as a trailer.
+    pub fn append_to(&self, program: &mut Vec<u8>) {
+        let section = self.encode();
+
+        program.extend_from_slice(&section);
+        program.extend_from_slice(&(section.len() as u32).to_le_bytes());
+    }
+}
+
+/// Splits a debug trailer off `data`. Data without a well-formed trailer is
+/// returned whole as the program.
+pub fn split(data: &[u8]) -> (&[u8], Option<DebugInfo>) {
+    let trailer = data.len().checked_sub(TRAILER_LENGTH).and_then(|end| {
+        let length = u32::from_le_bytes(data[end..].try_into().unwrap()) as usize;
+        let start = end.checked_sub(length)?;
+        let info = DebugInfo::decode(&data[start..end]).ok()?;
+
+        Some((start, info))
+    });
+
+    match trailer {
+        Some((start, info)) => (&data[..start], Some(info)),
+        None => (data, None),
+    }
+}
+
+/// State of a [`DebugInfoBuilder`] to return to with
+/// [`DebugInfoBuilder::restore`].
+#[derive(Clone, Debug)]
+pub struct DebugCheckpoint {
+    open: Vec<DebugEntry>,
+    closed: usize,
+}
+
+/// Records nested component ranges while a program is being written.
+#[derive(Clone, Debug, Default)]
+pub struct DebugInfoBuilder {
+    open: Vec<DebugEntry>,
+    closed: Vec<DebugEntry>,
+}
+
+impl DebugInfoBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens a component whose instructions start at `offset`.
+    pub fn begin(&mut self, offset: usize, name: &str, location: Option<SourceLocation>) {
+        self.open.push(DebugEntry {
+            range: offset..offset,
+            name: name.to_string(),
+            location,
+        });
+    }
+
+    /// Closes the innermost open component at `offset`. Returns `false` if
+    /// no component is open.
+    pub fn end(&mut self, offset: usize) -> bool {
+        match self.open.pop() {
+            Some(mut entry) => {
+                entry.range.end = offset;
+                self.closed.push(entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds the entries of `info`, whose offsets are relative to a fragment
+    /// spliced in at `offset`.
+    pub fn extend(&mut self, offset: usize, info: &DebugInfo) {
+        self.closed
+            .extend(info.entries.iter().rev().map(|e| DebugEntry {
+                range: e.range.start + offset..e.range.end + offset,
+                ..e.clone()
+            }));
+    }
+
+    pub fn depth(&self) -> usize {
+        self.open.len()
+    }
+
+    pub fn checkpoint(&self) -> DebugCheckpoint {
+        DebugCheckpoint {
+            open: self.open.clone(),
+            closed: self.closed.len(),
+        }
+    }
+
+    /// Drops the components recorded since `checkpoint` and reopens the
+    /// ones that were open then.
+    pub fn restore(&mut self, checkpoint: DebugCheckpoint) {
+        self.open = checkpoint.open;
+        self.closed.truncate(checkpoint.closed);
+    }
+
+    /// Closes any components still open at `offset` and returns the result.
+    pub fn finish(mut self, offset: usize) -> DebugInfo {
+        while self.end(offset) {}
+
+        // Components close inside out; reversed, components with the same
+        // range stay outer first when sorted.
+        self.closed.reverse();
+        DebugInfo::new(self.closed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::decode::decode;
+
+    fn entry(range: Range<usize>, name: &str, location: Option<(&str, u32)>) -> DebugEntry {
+        DebugEntry {
+            range,
+            name: name.to_string(),
+            location: location.map(|(file, line)| SourceLocation {
+                file: file.to_string(),
+                line,
+            }),
+        }
+    }
+
+    fn info() -> DebugInfo {
+        DebugInfo::new(vec![
+            entry(4..9, "Button", None),
+            entry(0..300, "Card", Some(("card.tsx", 4))),
+            entry(200..300, "Footer", Some(("footer.tsx", 1000))),
+        ])
+    }
+
+    #[test]
+    fn round_trips_through_a_trailer() {
+        let info = info();
+
+        assert_eq!(DebugInfo::decode(&info.encode()), Ok(info.clone()));
+
+        let program = assemble("create_element \"div\"\ntext_node \"hi\"").unwrap();
+        let mut data = program.clone();
+
+        info.append_to(&mut data);
+
+        assert_eq!(split(&data), (&program[..], Some(info)));
+    }
+
+    #[test]
+    fn programs_without_a_trailer_have_no_debug_info() {
+        let program = assemble("create_element \"div\"\nappend_child").unwrap();
+
+        assert_eq!(split(&program), (&program[..], None));
+        assert_eq!(split(&[]), (&[][..], None));
+    }
+
+    #[test]
+    fn rejects_corrupt_sections() {
+        let section = info().encode();
+        let corrupt = |at: usize, byte: u8| {
+            let mut section = section.clone();
+            section[at] = byte;
+            DebugInfo::decode(&section)
+        };
+
+        assert_eq!(corrupt(0, b'X'), Err(DebugInfoError::BadMagic));
+        assert_eq!(corrupt(3, 2), Err(DebugInfoError::UnsupportedVersion(2)));
+        assert_eq!(
+            DebugInfo::decode(&section[..section.len() - 1]),
+            Err(DebugInfoError::Truncated)
+        );
+        // The name of the first entry, "Card", starts after its range.
+        assert_eq!(corrupt(10, 0xff), Err(DebugInfoError::BadString));
+        assert_eq!(
+            DebugInfo::decode(b"LRD\x01\x01\x05\x04\x00\x00\x00"),
+            Err(DebugInfoError::BadRange { start: 5, end: 4 })
+        );
+
+        // A program with a corrupt trailer keeps the trailer bytes, which
+        // then fail to decode as instructions.
+        let mut data = assemble("create_element \"div\"").unwrap();
+        let length = section.len() as u32;
+
+        data.extend_from_slice(&section[..section.len() - 1]);
+        data.push(0xff);
+        data.extend_from_slice(&length.to_le_bytes());
+
+        let (program, debug) = split(&data);
+
+        assert_eq!((program.len(), debug), (data.len(), None));
+        assert!(decode(program).is_err());
+    }
+
+    #[test]
+    fn looks_up_nested_components() {
+        let info = info();
+        let names = |offset| -> Vec<_> {
+            info.lookup(offset)
+                .iter()
+                .map(|e| e.name.as_str())
+                .collect()
+        };
+
+        assert_eq!(names(5), ["Card", "Button"]);
+        assert_eq!(names(9), ["Card"]);
+        assert_eq!(names(300), Vec::<&str>::new());
+        assert_eq!(
+            info.context(250).as_deref(),
+            Some("in Card (card.tsx:4) > Footer (footer.tsx:1000)")
+        );
+        assert_eq!(info.context(400), None);
+        assert_eq!(
+            info.annotate("oops", 4),
+            "oops in Card (card.tsx:4) > Button"
+        );
+    }
+
+    #[test]
+    fn builder_restores_checkpoints() {
+        let mut builder = DebugInfoBuilder::new();
+
+        builder.begin(0, "Page", None);
+        builder.begin(2, "Header", None);
+        builder.end(6);
+
+        let checkpoint = builder.checkpoint();
+
+        builder.begin(6, "Broken", None);
+        builder.begin(8, "Inner", None);
+        builder.end(10);
+        assert_eq!(builder.depth(), 2);
+
+        builder.restore(checkpoint);
+        assert_eq!(builder.depth(), 1);
+
+        builder.begin(6, "Fallback", None);
+        builder.end(7);
+
+        assert_eq!(
+            builder.finish(9),
+            DebugInfo::new(vec![
+                entry(0..9, "Page", None),
+                entry(2..6, "Header", None),
+                entry(6..7, "Fallback", None),
+            ])
+        );
+    }
+}
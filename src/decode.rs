@@ -0,0 +1,623 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Instruction-level decoding and encoding of librender programs.
+//!
+//! A program is a flat sequence of instructions, each made of a single
+//! opcode byte followed by zero or more length-prefixed byte strings. The
+//! layout mirrors the emitters in `target.rs` (`librender_create_element`,
+//! `librender_set_attribute`, ...), so `encode(&decode(p)?) == p` holds for
+//! any well-formed program.
+
+use std::fmt;
+
+use crate::{
+    OPCODE_APPEND_CHILD, OPCODE_APPEND_SIBLING, OPCODE_CREATE_ELEMENT, OPCODE_EVENT_LISTENER,
+    OPCODE_NOP, OPCODE_PLACEHOLDER, OPCODE_REMOVE_ATTRIBUTE, OPCODE_REMOVE_CHILD,
+    OPCODE_REPLACE_CHILD, OPCODE_SET_ATTRIBUTE, OPCODE_SET_TEXT, OPCODE_STYLE, OPCODE_TEXT_NODE,
+};
+
+/// Largest payload a single length prefix can describe.
+pub const MAX_PAYLOAD_LENGTH: usize = u8::MAX as usize;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Nop,
+    CreateElement(Vec<u8>),
+    SetAttribute(Vec<u8>, Vec<u8>),
+    AppendChild,
+    RemoveChild,
+    ReplaceChild,
+    TextNode(Vec<u8>),
+    SetText(Vec<u8>),
+    RemoveAttribute(Vec<u8>),
+    Style(Vec<u8>, Vec<u8>),
+    EventListener(Vec<u8>),
+    AppendSibling,
+    /// Reserves a slot, identified by its payload, that a later fill frame
+    /// replaces. Children appended to it are the fallback.
+    Placeholder(Vec<u8>),
+}
+
+impl Instruction {
+    pub fn opcode(&self) -> u8 {
+        (match self {
+            Instruction::Nop => OPCODE_NOP,
+            Instruction::CreateElement(_) => OPCODE_CREATE_ELEMENT,
+            Instruction::SetAttribute(..) => OPCODE_SET_ATTRIBUTE,
+            Instruction::AppendChild => OPCODE_APPEND_CHILD,
+            Instruction::RemoveChild => OPCODE_REMOVE_CHILD,
+            Instruction::ReplaceChild => OPCODE_REPLACE_CHILD,
+            Instruction::TextNode(_) => OPCODE_TEXT_NODE,
+            Instruction::SetText(_) => OPCODE_SET_TEXT,
+            Instruction::RemoveAttribute(_) => OPCODE_REMOVE_ATTRIBUTE,
+            Instruction::Style(..) => OPCODE_STYLE,
+            Instruction::EventListener(_) => OPCODE_EVENT_LISTENER,
+            Instruction::AppendSibling => OPCODE_APPEND_SIBLING,
+            Instruction::Placeholder(_) => OPCODE_PLACEHOLDER,
+        }) as u8
+    }
+
+    /// Mnemonic used by the disassembler and in diagnostics.
+    pub fn mnemonic(&self) -> &'static str {
+        opcode_mnemonic(self.opcode()).unwrap_or("unknown")
+    }
+
+    /// Length-prefixed operands in encoding order.
+    pub fn payloads(&self) -> Vec<&[u8]> {
+        match self {
+            Instruction::CreateElement(a)
+            | Instruction::TextNode(a)
+            | Instruction::SetText(a)
+            | Instruction::RemoveAttribute(a)
+            | Instruction::EventListener(a)
+            | Instruction::Placeholder(a) => vec![a],
+            Instruction::SetAttribute(a, b) | Instruction::Style(a, b) => vec![a, b],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        1 + self.payloads().iter().map(|p| 1 + p.len()).sum::<usize>()
+    }
+
+    /// Appends the encoded instruction to `out`.
+    ///
+    /// # Panics
+    ///
+    /// If a payload is longer than [`MAX_PAYLOAD_LENGTH`], which a length
+    /// prefix cannot describe. Callers taking payloads from outside check
+    /// them first, as [`crate::io::Builder::instruction`] does.
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.push(self.opcode());
+
+        for payload in self.payloads() {
+            assert!(
+                payload.len() <= MAX_PAYLOAD_LENGTH,
+                "{} payload of {} bytes exceeds {} bytes",
+                self.mnemonic(),
+                payload.len(),
+                MAX_PAYLOAD_LENGTH
+            );
+
+            out.push(payload.len() as u8);
+            out.extend_from_slice(payload);
+        }
+    }
+
+    /// Builds an instruction from its opcode and exactly `opcode_arity`
+    /// payloads.
+    pub fn from_parts(opcode: u8, payloads: Vec<Vec<u8>>) -> Option<Instruction> {
+        if opcode_arity(opcode)? != payloads.len() {
+            return None;
+        }
+
+        let mut payloads = payloads.into_iter();
+        let mut next = || payloads.next().unwrap_or_default();
+
+        Some(match opcode as libc::c_uint {
+            OPCODE_NOP => Instruction::Nop,
+            OPCODE_CREATE_ELEMENT => Instruction::CreateElement(next()),
+            OPCODE_SET_ATTRIBUTE => Instruction::SetAttribute(next(), next()),
+            OPCODE_APPEND_CHILD => Instruction::AppendChild,
+            OPCODE_REMOVE_CHILD => Instruction::RemoveChild,
+            OPCODE_REPLACE_CHILD => Instruction::ReplaceChild,
+            OPCODE_TEXT_NODE => Instruction::TextNode(next()),
+            OPCODE_SET_TEXT => Instruction::SetText(next()),
+            OPCODE_REMOVE_ATTRIBUTE => Instruction::RemoveAttribute(next()),
+            OPCODE_STYLE => Instruction::Style(next(), next()),
+            OPCODE_EVENT_LISTENER => Instruction::EventListener(next()),
+            OPCODE_PLACEHOLDER => Instruction::Placeholder(next()),
+            _ => Instruction::AppendSibling,
+        })
+    }
+
+    /// Whether the instruction pushes a new node onto the node stack.
+    pub fn creates_node(&self) -> bool {
+        matches!(
+            self,
+            Instruction::CreateElement(_) | Instruction::TextNode(_) | Instruction::Placeholder(_)
+        )
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.mnemonic())?;
+
+        for payload in self.payloads() {
+            write!(f, " {:?}", latin1(payload))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn opcode_mnemonic(opcode: u8) -> Option<&'static str> {
+    Some(match opcode as libc::c_uint {
+        OPCODE_NOP => "nop",
+        OPCODE_CREATE_ELEMENT => "create_element",
+        OPCODE_SET_ATTRIBUTE => "set_attribute",
+        OPCODE_APPEND_CHILD => "append_child",
+        OPCODE_REMOVE_CHILD => "remove_child",
+        OPCODE_REPLACE_CHILD => "replace_child",
+        OPCODE_TEXT_NODE => "text_node",
+        OPCODE_SET_TEXT => "set_text",
+        OPCODE_REMOVE_ATTRIBUTE => "remove_attribute",
+        OPCODE_STYLE => "style",
+        OPCODE_EVENT_LISTENER => "event_listener",
+        OPCODE_APPEND_SIBLING => "append_sibling",
+        OPCODE_PLACEHOLDER => "placeholder",
+        _ => return None,
+    })
+}
+
+/// Number of length-prefixed operands following `opcode`.
+pub fn opcode_arity(opcode: u8) -> Option<usize> {
+    Some(match opcode as libc::c_uint {
+        OPCODE_NOP
+        | OPCODE_APPEND_CHILD
+        | OPCODE_REMOVE_CHILD
+        | OPCODE_REPLACE_CHILD
+        | OPCODE_APPEND_SIBLING => 0,
+        OPCODE_CREATE_ELEMENT
+        | OPCODE_TEXT_NODE
+        | OPCODE_SET_TEXT
+        | OPCODE_REMOVE_ATTRIBUTE
+        | OPCODE_EVENT_LISTENER
+        | OPCODE_PLACEHOLDER => 1,
+        OPCODE_SET_ATTRIBUTE | OPCODE_STYLE => 2,
+        _ => return None,
+    })
+}
+
+/// Names of the operands of `opcode`, as used in diagnostics.
+pub fn opcode_operands(opcode: u8) -> Option<&'static [&'static str]> {
+    Some(match opcode as libc::c_uint {
+        OPCODE_NOP
+        | OPCODE_APPEND_CHILD
+        | OPCODE_REMOVE_CHILD
+        | OPCODE_REPLACE_CHILD
+        | OPCODE_APPEND_SIBLING => &[],
+        OPCODE_CREATE_ELEMENT => &["tag"],
+        OPCODE_TEXT_NODE | OPCODE_SET_TEXT => &["text"],
+        OPCODE_REMOVE_ATTRIBUTE => &["name"],
+        OPCODE_EVENT_LISTENER => &["event"],
+        OPCODE_PLACEHOLDER => &["slot"],
+        OPCODE_SET_ATTRIBUTE | OPCODE_STYLE => &["name", "value"],
+        _ => return None,
+    })
+}
+
+/// Byte strings in programs are 8-bit character codes (see `charCodes` in
+/// `utils.ts`), so each byte maps to exactly one char.
+pub fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+/// Inverse of [`latin1`]; chars outside the 8-bit range are replaced by `?`.
+pub fn to_latin1(s: &str) -> Vec<u8> {
+    s.chars()
+        .map(|c| if (c as u32) <= 0xff { c as u8 } else { b'?' })
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    UnknownOpcode(u8),
+    /// There is no instruction at `offset`; it lies at or past the end.
+    UnexpectedEnd,
+    /// The length prefix at `offset` is missing.
+    MissingLength,
+    /// A length prefix claims more bytes than remain in the program.
+    Truncated {
+        length: usize,
+        remaining: usize,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    /// Offset of the faulting byte.
+    pub offset: usize,
+    /// Offset of the opcode of the instruction being decoded.
+    pub instruction_offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            DecodeErrorKind::UnknownOpcode(op) => {
+                write!(
+                    f,
+                    "unknown opcode 0x{:02x} at offset 0x{:x}",
+                    op, self.offset
+                )
+            }
+            DecodeErrorKind::UnexpectedEnd => {
+                write!(f, "unexpected end of program at offset 0x{:x}", self.offset)
+            }
+            DecodeErrorKind::MissingLength => {
+                write!(f, "missing length prefix at offset 0x{:x}", self.offset)
+            }
+            DecodeErrorKind::Truncated { length, remaining } => write!(
+                f,
+                "length {} exceeds remaining {} bytes at offset 0x{:x}",
+                length, remaining, self.offset
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes the instruction starting at `offset`, returning it together with
+/// its encoded length.
+pub fn decode_at(program: &[u8], offset: usize) -> Result<(Instruction, usize), DecodeError> {
+    let fail = |at: usize, kind| DecodeError {
+        offset: at,
+        instruction_offset: offset,
+        kind,
+    };
+    let opcode = *program
+        .get(offset)
+        .ok_or_else(|| fail(offset, DecodeErrorKind::UnexpectedEnd))?;
+
+    let arity =
+        opcode_arity(opcode).ok_or_else(|| fail(offset, DecodeErrorKind::UnknownOpcode(opcode)))?;
+    let mut pos = offset + 1;
+    let mut payloads: Vec<Vec<u8>> = Vec::with_capacity(arity);
+
+    for _ in 0..arity {
+        let length = *program
+            .get(pos)
+            .ok_or_else(|| fail(pos, DecodeErrorKind::MissingLength))?
+            as usize;
+        let remaining = program.len() - pos - 1;
+
+        if length > remaining {
+            return Err(fail(pos, DecodeErrorKind::Truncated { length, remaining }));
+        }
+
+        payloads.push(program[pos + 1..pos + 1 + length].to_vec());
+        pos += 1 + length;
+    }
+
+    let instruction = Instruction::from_parts(opcode, payloads).unwrap();
+
+    Ok((instruction, pos - offset))
+}
+
+/// Iterator over `(offset, instruction)` pairs of a program. Decoding stops
+/// after the first error.
+pub struct Instructions<'a> {
+    program: &'a [u8],
+    offset: usize,
+    failed: bool,
+}
+
+impl<'a> Instructions<'a> {
+    pub fn new(program: &'a [u8]) -> Self {
+        Instructions {
+            program,
+            offset: 0,
+            failed: false,
+        }
+    }
+}
+
+impl Iterator for Instructions<'_> {
+    type Item = Result<(usize, Instruction), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.offset >= self.program.len() {
+            return None;
+        }
+
+        match decode_at(self.program, self.offset) {
+            Ok((instruction, len)) => {
+                let offset = self.offset;
+                self.offset += len;
+                Some(Ok((offset, instruction)))
+            }
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+pub fn decode(program: &[u8]) -> Result<Vec<Instruction>, DecodeError> {
+    Instructions::new(program)
+        .map(|item| item.map(|(_, instruction)| instruction))
+        .collect()
+}
+
+pub fn encode(instructions: &[Instruction]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(instructions.iter().map(Instruction::encoded_len).sum());
+
+    for instruction in instructions {
+        instruction.encode_into(&mut out);
+    }
+
+    out
+}
+
+/// Push-based decoder for programs that arrive in arbitrary chunks.
+///
+/// Partial instructions are buffered across calls to [`feed`], so a chunk
+/// may end anywhere, including between a length prefix and its payload.
+/// Each byte is looked at exactly once.
+///
+/// [`feed`]: StreamDecoder::feed
+#[derive(Clone, Debug, Default)]
+pub struct StreamDecoder {
+    /// Absolute offset of the next byte fed.
+    offset: usize,
+    /// Offset, opcode and arity of the instruction being decoded.
+    pending: Option<(usize, u8, usize)>,
+    payloads: Vec<Vec<u8>>,
+    /// Offset of the current length prefix and the number of payload bytes
+    /// still missing, once the prefix has been read.
+    missing: Option<(usize, usize)>,
+    error: Option<DecodeError>,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of bytes consumed so far.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// First error encountered. Once set, further input is ignored.
+    pub fn error(&self) -> Option<&DecodeError> {
+        self.error.as_ref()
+    }
+
+    /// Whether the decoder is between instructions.
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_none()
+    }
+
+    /// Decodes every instruction that `chunk` completes.
+    pub fn feed(&mut self, chunk: &[u8]) -> Vec<Instruction> {
+        let mut out = Vec::new();
+        let mut pos = 0;
+
+        while pos < chunk.len() && self.error.is_none() {
+            let consumed = match (self.pending, self.missing) {
+                (None, _) => {
+                    let opcode = chunk[pos];
+                    let Some(arity) = opcode_arity(opcode) else {
+                        self.error = Some(DecodeError {
+                            offset: self.offset,
+                            instruction_offset: self.offset,
+                            kind: DecodeErrorKind::UnknownOpcode(opcode),
+                        });
+                        break;
+                    };
+
+                    self.pending = Some((self.offset, opcode, arity));
+                    1
+                }
+                (Some(_), None) => {
+                    let length = chunk[pos] as usize;
+
+                    self.payloads.push(Vec::with_capacity(length));
+                    self.missing = Some((self.offset, length));
+                    1
+                }
+                (Some(_), Some((prefix, missing))) => {
+                    let take = missing.min(chunk.len() - pos);
+
+                    self.payloads
+                        .last_mut()
+                        .unwrap()
+                        .extend_from_slice(&chunk[pos..pos + take]);
+                    self.missing = Some((prefix, missing - take));
+                    take
+                }
+            };
+
+            pos += consumed;
+            self.offset += consumed;
+
+            if let Some((_, 0)) = self.missing {
+                self.missing = None;
+            }
+
+            if let Some((_, opcode, arity)) = self.pending {
+                if self.missing.is_none() && self.payloads.len() == arity {
+                    let payloads = std::mem::take(&mut self.payloads);
+
+                    out.extend(Instruction::from_parts(opcode, payloads));
+                    self.pending = None;
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Ends the stream, reporting an instruction that was cut short.
+    pub fn finish(self) -> Result<(), DecodeError> {
+        if let Some(err) = self.error {
+            return Err(err);
+        }
+
+        let Some((start, _, _)) = self.pending else {
+            return Ok(());
+        };
+
+        let (offset, kind) = match self.missing {
+            Some((prefix, missing)) => {
+                let remaining = self.payloads.last().map_or(0, Vec::len);
+                let length = remaining + missing;
+
+                (prefix, DecodeErrorKind::Truncated { length, remaining })
+            }
+            None => (self.offset, DecodeErrorKind::MissingLength),
+        };
+
+        Err(DecodeError {
+            offset,
+            instruction_offset: start,
+            kind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    const PROGRAM: &str = r#"
+        create_element "ul"
+        set_attribute "class" "list"
+        create_element "li"
+        style "color" "red"
+        event_listener "click"
+        text_node "one"
+        append_child
+        append_child
+        placeholder "rest"
+        append_child
+    "#;
+
+    #[test]
+    fn encode_inverts_decode() {
+        let program = assemble(PROGRAM).unwrap();
+        assert_eq!(encode(&decode(&program).unwrap()), program);
+    }
+
+    #[test]
+    fn encodes_longest_payload() {
+        let instruction = Instruction::TextNode(vec![b'a'; MAX_PAYLOAD_LENGTH]);
+        let program = encode(std::slice::from_ref(&instruction));
+
+        assert_eq!(program.len(), instruction.encoded_len());
+        assert_eq!(decode(&program).unwrap(), [instruction]);
+    }
+
+    #[test]
+    fn decode_at_reports_offsets_past_the_end() {
+        let program = assemble(PROGRAM).unwrap();
+
+        for offset in [program.len(), program.len() + 1, usize::MAX] {
+            let err = decode_at(&program, offset).unwrap_err();
+
+            assert_eq!(err.kind, DecodeErrorKind::UnexpectedEnd);
+            assert_eq!(err.offset, offset);
+        }
+
+        assert_eq!(
+            decode_at(&[], 0).unwrap_err().to_string(),
+            "unexpected end of program at offset 0x0"
+        );
+    }
+
+    /// Feeds `chunks` in order and returns what the decoder produced.
+    fn stream(chunks: &[&[u8]]) -> Result<Vec<Instruction>, DecodeError> {
+        let mut decoder = StreamDecoder::new();
+        let mut out = Vec::new();
+
+        for chunk in chunks {
+            out.extend(decoder.feed(chunk));
+        }
+
+        decoder.finish().map(|()| out)
+    }
+
+    #[test]
+    fn stream_decoder_matches_decode_at_every_split() {
+        let program = assemble(PROGRAM).unwrap();
+        let expected = decode(&program).unwrap();
+
+        for at in 0..=program.len() {
+            let (a, b) = program.split_at(at);
+            assert_eq!(stream(&[a, b]).unwrap(), expected, "split at {}", at);
+        }
+    }
+
+    #[test]
+    fn stream_decoder_matches_decode_at_every_pair_of_splits() {
+        let program = assemble(PROGRAM).unwrap();
+        let expected = decode(&program).unwrap();
+
+        for i in 0..=program.len() {
+            for j in i..=program.len() {
+                let chunks = [&program[..i], &program[i..j], &program[j..]];
+                assert_eq!(
+                    stream(&chunks).unwrap(),
+                    expected,
+                    "split at {} and {}",
+                    i,
+                    j
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stream_decoder_reports_truncation_like_decode() {
+        let program = assemble(PROGRAM).unwrap();
+
+        for end in 0..program.len() {
+            let prefix = &program[..end];
+
+            for at in 0..=end {
+                let (a, b) = prefix.split_at(at);
+                assert_eq!(
+                    stream(&[a, b]),
+                    decode(prefix),
+                    "prefix {} split at {}",
+                    end,
+                    at
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stream_decoder_stops_at_unknown_opcodes() {
+        let mut program = assemble(PROGRAM).unwrap();
+        program.insert(4, 0xee);
+
+        let err = stream(&[&program]).unwrap_err();
+
+        assert_eq!(err.kind, DecodeErrorKind::UnknownOpcode(0xee));
+        assert_eq!(err, decode(&program).unwrap_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "text_node payload of 256 bytes exceeds 255 bytes")]
+    fn refuses_to_truncate_payloads() {
+        Instruction::TextNode(vec![b'a'; MAX_PAYLOAD_LENGTH + 1]).encode_into(&mut Vec::new());
+    }
+}
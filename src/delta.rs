@@ -0,0 +1,666 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Byte-level deltas between programs.
+//!
+//! A delta turns a cached program (the source) into a new one (the target)
+//! with two operations: copy a range of the source, or insert literal
+//! bytes. Ranges always start and end on instruction boundaries, so a delta
+//! never splits an instruction. Applying a delta gives the same bytes as
+//! replaying the equivalent `librender_insert_byte`/`librender_remove_byte`
+//! calls on the source, which is how [`Delta::edits`] describes it.
+//!
+//! ```text
+//! magic "LRX" | version | varint source length | u64 LE source hash
+//!             | varint target length | u64 LE target hash | varint count | ops
+//! op: 0x00 | varint offset | varint length     copy from the source
+//!     0x01 | varint length | bytes             insert literal bytes
+//! ```
+//!
+//! Hashes are FNV-1a over the program bytes, so applying a delta to the
+//! wrong source fails instead of producing garbage.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hasher;
+
+use crate::compress::{read_varint, write_varint};
+use crate::decode::{decode_at, DecodeError};
+use crate::memo::StableHasher;
+use crate::{buffer_bytes, buffer_from_bytes, librender_bytecode_buffer};
+
+pub const MAGIC: &[u8; 3] = b"LRX";
+pub const VERSION: u8 = 1;
+
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+
+/// Copies shorter than this are inserted instead, since the copy operation
+/// would take about as many bytes.
+const MIN_COPY_LENGTH: usize = 4;
+
+/// Source positions tried when looking for the longest copy.
+const MAX_CANDIDATES: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeltaOp {
+    Copy { offset: usize, length: usize },
+    Insert(Vec<u8>),
+}
+
+impl DeltaOp {
+    /// Number of target bytes the operation produces.
+    pub fn len(&self) -> usize {
+        match self {
+            DeltaOp::Copy { length, .. } => *length,
+            DeltaOp::Insert(bytes) => bytes.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A single byte edit, with the meaning of `librender_insert_byte` and
+/// `librender_remove_byte`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteEdit {
+    Insert { index: usize, byte: u8 },
+    Remove { index: usize },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeltaError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    UnknownOp(u8),
+    /// The program the delta is applied to is not its source.
+    SourceMismatch,
+    /// A copy reads past the end of the source.
+    CopyOutOfRange {
+        offset: usize,
+        length: usize,
+    },
+    /// The ops do not produce the target the delta describes.
+    TargetMismatch,
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeltaError::BadMagic => write!(f, "not a delta"),
+            DeltaError::UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            DeltaError::Truncated => write!(f, "truncated delta"),
+            DeltaError::UnknownOp(op) => write!(f, "unknown delta op 0x{:02x}", op),
+            DeltaError::SourceMismatch => write!(f, "delta does not apply to this program"),
+            DeltaError::CopyOutOfRange { offset, length } => write!(
+                f,
+                "copy of {} bytes at offset 0x{:x} is out of range",
+                length, offset
+            ),
+            DeltaError::TargetMismatch => write!(f, "delta does not produce its target"),
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+/// FNV-1a hash of a program's bytes.
+pub fn program_hash(program: &[u8]) -> u64 {
+    let mut hasher = StableHasher::new();
+    hasher.write(program);
+    hasher.finish()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delta {
+    pub source_len: usize,
+    pub source_hash: u64,
+    pub target_len: usize,
+    pub target_hash: u64,
+    pub ops: Vec<DeltaOp>,
+}
+
+impl Delta {
+    /// Computes a delta from `source` to `target`, matching whole runs of
+    /// instructions.
+    pub fn new(source: &[u8], target: &[u8]) -> Result<Self, DecodeError> {
+        let (source_spans, target_spans) = (spans(source)?, spans(target)?);
+        let mut index: HashMap<&[u8], Vec<usize>> = HashMap::new();
+
+        for (i, span) in source_spans.iter().enumerate() {
+            index.entry(slice(source, span)).or_default().push(i);
+        }
+
+        let mut ops = OpsBuilder::default();
+        let mut next = None;
+        let mut j = 0;
+
+        while j < target_spans.len() {
+            let run = |i: usize| {
+                let mut k = 0;
+
+                while i + k < source_spans.len()
+                    && j + k < target_spans.len()
+                    && slice(source, &source_spans[i + k]) == slice(target, &target_spans[j + k])
+                {
+                    k += 1;
+                }
+
+                (k, i)
+            };
+
+            // Continuing the previous copy is the common case and keeps
+            // copies long.
+            let mut best = next.map(run).unwrap_or((0, 0));
+
+            if best.0 == 0 {
+                if let Some(candidates) = index.get(slice(target, &target_spans[j])) {
+                    for &i in candidates.iter().take(MAX_CANDIDATES) {
+                        let candidate = run(i);
+
+                        if candidate.0 > best.0 {
+                            best = candidate;
+                        }
+                    }
+                }
+            }
+
+            let (count, i) = best;
+            let length = match count {
+                0 => 0,
+                _ => source_spans[i + count - 1].1 - source_spans[i].0,
+            };
+
+            if length >= MIN_COPY_LENGTH {
+                ops.copy(source_spans[i].0, length);
+                next = Some(i + count);
+                j += count;
+            } else {
+                ops.insert(slice(target, &target_spans[j]));
+                next = None;
+                j += 1;
+            }
+        }
+
+        Ok(Self {
+            source_len: source.len(),
+            source_hash: program_hash(source),
+            target_len: target.len(),
+            target_hash: program_hash(target),
+            ops: ops.finish(),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        write_varint(&mut out, self.source_len);
+        out.extend_from_slice(&self.source_hash.to_le_bytes());
+        write_varint(&mut out, self.target_len);
+        out.extend_from_slice(&self.target_hash.to_le_bytes());
+        write_varint(&mut out, self.ops.len());
+
+        for op in &self.ops {
+            match op {
+                DeltaOp::Copy { offset, length } => {
+                    out.push(OP_COPY);
+                    write_varint(&mut out, *offset);
+                    write_varint(&mut out, *length);
+                }
+                DeltaOp::Insert(bytes) => {
+                    out.push(OP_INSERT);
+                    write_varint(&mut out, bytes.len());
+                    out.extend_from_slice(bytes);
+                }
+            }
+        }
+
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DeltaError> {
+        if !data.starts_with(MAGIC) {
+            return Err(DeltaError::BadMagic);
+        }
+
+        match data.get(3) {
+            None => return Err(DeltaError::Truncated),
+            Some(&VERSION) => {}
+            Some(&version) => return Err(DeltaError::UnsupportedVersion(version)),
+        }
+
+        let mut pos = 4;
+        let varint = |pos: &mut usize| read_varint(data, pos).ok_or(DeltaError::Truncated);
+        let bytes = |pos: &mut usize, length: usize| {
+            let bytes = pos
+                .checked_add(length)
+                .and_then(|end| data.get(*pos..end))
+                .ok_or(DeltaError::Truncated)?;
+
+            *pos += length;
+            Ok(bytes)
+        };
+        let hash = |pos: &mut usize| -> Result<u64, DeltaError> {
+            Ok(u64::from_le_bytes(bytes(pos, 8)?.try_into().unwrap()))
+        };
+
+        let source_len = varint(&mut pos)?;
+        let source_hash = hash(&mut pos)?;
+        let target_len = varint(&mut pos)?;
+        let target_hash = hash(&mut pos)?;
+        let count = varint(&mut pos)?;
+        let mut ops = Vec::new();
+
+        for _ in 0..count {
+            let op = *data.get(pos).ok_or(DeltaError::Truncated)?;
+            pos += 1;
+
+            ops.push(match op {
+                OP_COPY => DeltaOp::Copy {
+                    offset: varint(&mut pos)?,
+                    length: varint(&mut pos)?,
+                },
+                OP_INSERT => {
+                    let length = varint(&mut pos)?;
+                    DeltaOp::Insert(bytes(&mut pos, length)?.to_vec())
+                }
+                op => return Err(DeltaError::UnknownOp(op)),
+            });
+        }
+
+        Ok(Self {
+            source_len,
+            source_hash,
+            target_len,
+            target_hash,
+            ops,
+        })
+    }
+
+    /// Builds the target from `source`.
+    pub fn apply(&self, source: &[u8]) -> Result<Vec<u8>, DeltaError> {
+        if source.len() != self.source_len || program_hash(source) != self.source_hash {
+            return Err(DeltaError::SourceMismatch);
+        }
+
+        let mut out = Vec::with_capacity(self.target_len);
+
+        for op in &self.ops {
+            match op {
+                DeltaOp::Copy { offset, length } => {
+                    let range = offset
+                        .checked_add(*length)
+                        .and_then(|end| source.get(*offset..end))
+                        .ok_or(DeltaError::CopyOutOfRange {
+                            offset: *offset,
+                            length: *length,
+                        })?;
+
+                    out.extend_from_slice(range);
+                }
+                DeltaOp::Insert(bytes) => out.extend_from_slice(bytes),
+            }
+        }
+
+        if out.len() != self.target_len || program_hash(&out) != self.target_hash {
+            return Err(DeltaError::TargetMismatch);
+        }
+
+        Ok(out)
+    }
+
+    /// The delta from the target back to `source`. Copies become copies out
+    /// of the target; the source bytes no copy reads become inserts.
+    pub fn invert(&self, source: &[u8]) -> Result<Self, DeltaError> {
+        if source.len() != self.source_len || program_hash(source) != self.source_hash {
+            return Err(DeltaError::SourceMismatch);
+        }
+
+        // (source offset, length, target offset) of every copy.
+        let mut copies = Vec::new();
+        let mut at = 0;
+
+        for op in &self.ops {
+            if let DeltaOp::Copy { offset, length } = *op {
+                if offset
+                    .checked_add(length)
+                    .is_none_or(|end| end > source.len())
+                {
+                    return Err(DeltaError::CopyOutOfRange { offset, length });
+                }
+
+                copies.push((offset, length, at));
+            }
+
+            at += op.len();
+        }
+
+        copies.sort_unstable();
+
+        let mut ops = OpsBuilder::default();
+        let mut pos = 0;
+
+        for (offset, length, target) in copies {
+            let end = offset + length;
+
+            if end <= pos {
+                continue;
+            }
+
+            if offset > pos {
+                ops.insert(&source[pos..offset]);
+            }
+
+            let start = offset.max(pos);
+            ops.copy(target + (start - offset), end - start);
+            pos = end;
+        }
+
+        ops.insert(&source[pos..]);
+
+        Ok(Self {
+            source_len: self.target_len,
+            source_hash: self.target_hash,
+            target_len: self.source_len,
+            target_hash: self.source_hash,
+            ops: ops.finish(),
+        })
+    }
+
+    /// The delta as `librender_insert_byte`/`librender_remove_byte` calls
+    /// that turn `source` into the target when replayed in order. Copies
+    /// that read the source in order keep its bytes in place, the bytes
+    /// between them are removed and everything else is inserted.
+    pub fn edits(&self, source: &[u8]) -> Result<Vec<ByteEdit>, DeltaError> {
+        if source.len() != self.source_len || program_hash(source) != self.source_hash {
+            return Err(DeltaError::SourceMismatch);
+        }
+
+        let mut edits = Vec::new();
+        // The buffer holds the target up to `at`, then the source from `pos`.
+        let (mut at, mut pos) = (0, 0);
+
+        for op in &self.ops {
+            match *op {
+                DeltaOp::Copy { offset, length } => {
+                    let end = offset
+                        .checked_add(length)
+                        .filter(|&end| end <= source.len())
+                        .ok_or(DeltaError::CopyOutOfRange { offset, length })?;
+
+                    if offset >= pos {
+                        remove(&mut edits, at, offset - pos);
+                        at += length;
+                        pos = end;
+                    } else {
+                        insert(&mut edits, &mut at, &source[offset..end]);
+                    }
+                }
+                DeltaOp::Insert(ref bytes) => insert(&mut edits, &mut at, bytes),
+            }
+        }
+
+        remove(&mut edits, at, source.len() - pos);
+        Ok(edits)
+    }
+}
+
+fn insert(edits: &mut Vec<ByteEdit>, at: &mut usize, bytes: &[u8]) {
+    for &byte in bytes {
+        edits.push(ByteEdit::Insert { index: *at, byte });
+        *at += 1;
+    }
+}
+
+fn remove(edits: &mut Vec<ByteEdit>, index: usize, count: usize) {
+    edits.extend((0..count).map(|_| ByteEdit::Remove { index }));
+}
+
+#[derive(Default)]
+struct OpsBuilder {
+    ops: Vec<DeltaOp>,
+}
+
+impl OpsBuilder {
+    fn copy(&mut self, offset: usize, length: usize) {
+        if length == 0 {
+            return;
+        }
+
+        match self.ops.last_mut() {
+            Some(DeltaOp::Copy {
+                offset: last,
+                length: last_length,
+            }) if *last + *last_length == offset => *last_length += length,
+            _ => self.ops.push(DeltaOp::Copy { offset, length }),
+        }
+    }
+
+    fn insert(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        match self.ops.last_mut() {
+            Some(DeltaOp::Insert(last)) => last.extend_from_slice(bytes),
+            _ => self.ops.push(DeltaOp::Insert(bytes.to_vec())),
+        }
+    }
+
+    fn finish(self) -> Vec<DeltaOp> {
+        self.ops
+    }
+}
+
+fn slice<'a>(program: &'a [u8], span: &(usize, usize)) -> &'a [u8] {
+    &program[span.0..span.1]
+}
+
+/// Byte ranges of the instructions of `program`.
+fn spans(program: &[u8]) -> Result<Vec<(usize, usize)>, DecodeError> {
+    let mut spans = Vec::new();
+    let mut offset = 0;
+
+    while offset < program.len() {
+        let (_, len) = decode_at(program, offset)?;
+        spans.push((offset, offset + len));
+        offset += len;
+    }
+
+    Ok(spans)
+}
+
+/// Encoded delta from `prev` to `next`.
+pub fn delta(prev: &[u8], next: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    Ok(Delta::new(prev, next)?.encode())
+}
+
+/// Applies an encoded delta to `prev`.
+pub fn apply_delta(prev: &[u8], delta: &[u8]) -> Result<Vec<u8>, DeltaError> {
+    Delta::decode(delta)?.apply(prev)
+}
+
+/// Encoded delta that undoes `delta`, which must have been computed from
+/// `prev`. Applied to the program `delta` produces, it gives back `prev`.
+pub fn invert(delta: &[u8], prev: &[u8]) -> Result<Vec<u8>, DeltaError> {
+    Ok(Delta::decode(delta)?.invert(prev)?.encode())
+}
+
+/// Computes the delta from `prev` to `next` into a new buffer. Returns null
+/// if either program does not decode.
+///
+/// # Safety
+///
+/// `prev` and `next` must each be null or point to a valid buffer.
+#[no_mangle]
+pub unsafe extern "C" fn librender_delta_buffer(
+    prev: *const librender_bytecode_buffer,
+    next: *const librender_bytecode_buffer,
+) -> *mut librender_bytecode_buffer {
+    match delta(buffer_bytes(prev), buffer_bytes(next)) {
+        Ok(delta) => buffer_from_bytes(&delta),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Applies the delta in `delta` to `prev` into a new buffer. Returns null
+/// if the delta is malformed or was not computed from `prev`.
+///
+/// # Safety
+///
+/// `prev` and `delta` must each be null or point to a valid buffer.
+#[no_mangle]
+pub unsafe extern "C" fn librender_apply_delta(
+    prev: *const librender_bytecode_buffer,
+    delta: *const librender_bytecode_buffer,
+) -> *mut librender_bytecode_buffer {
+    match apply_delta(buffer_bytes(prev), buffer_bytes(delta)) {
+        Ok(next) => buffer_from_bytes(&next),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::{
+        librender_create_buffer, librender_free_buffer, librender_insert_byte,
+        librender_remove_byte, size_t,
+    };
+
+    const PREV: &str = r#"
+        create_element "ul"
+        create_element "li"
+        text_node "one"
+        append_child
+        append_child
+        create_element "li"
+        text_node "two"
+        append_child
+        append_child
+    "#;
+
+    const NEXT: &str = r#"
+        create_element "ul"
+        set_attribute "class" "list"
+        create_element "li"
+        text_node "two"
+        append_child
+        append_child
+        create_element "li"
+        text_node "one"
+        append_child
+        append_child
+        create_element "li"
+        text_node "three"
+        append_child
+        append_child
+    "#;
+
+    fn programs() -> (Vec<u8>, Vec<u8>) {
+        (assemble(PREV).unwrap(), assemble(NEXT).unwrap())
+    }
+
+    #[test]
+    fn applies_to_its_source() {
+        let (prev, next) = programs();
+        let d = delta(&prev, &next).unwrap();
+
+        assert_eq!(apply_delta(&prev, &d).unwrap(), next);
+        assert_eq!(
+            apply_delta(&prev, &delta(&prev, &prev).unwrap()).unwrap(),
+            prev
+        );
+        assert_eq!(apply_delta(&[], &delta(&[], &next).unwrap()).unwrap(), next);
+    }
+
+    #[test]
+    fn copies_whole_instructions() {
+        let (prev, next) = programs();
+        let d = Delta::new(&prev, &next).unwrap();
+        let starts: Vec<_> = crate::decode::Instructions::new(&prev)
+            .map(|r| r.unwrap().0)
+            .chain([prev.len()])
+            .collect();
+
+        for op in &d.ops {
+            if let DeltaOp::Copy { offset, length } = *op {
+                assert!(starts.contains(&offset), "copy starts at {}", offset);
+                assert!(starts.contains(&(offset + length)));
+            }
+        }
+    }
+
+    #[test]
+    fn inverts() {
+        let (prev, next) = programs();
+        let d = delta(&prev, &next).unwrap();
+
+        assert_eq!(
+            apply_delta(&next, &invert(&d, &prev).unwrap()).unwrap(),
+            prev
+        );
+    }
+
+    #[test]
+    fn rejects_the_wrong_source() {
+        let (prev, next) = programs();
+        let d = delta(&prev, &next).unwrap();
+
+        assert_eq!(apply_delta(&next, &d), Err(DeltaError::SourceMismatch));
+        assert_eq!(apply_delta(&prev, b"LRY"), Err(DeltaError::BadMagic));
+        assert_eq!(
+            apply_delta(&prev, &d[..d.len() - 1]),
+            Err(DeltaError::Truncated)
+        );
+    }
+
+    #[test]
+    fn edits_replay_through_the_byte_emitters() {
+        let (prev, next) = programs();
+        let d = Delta::new(&prev, &next).unwrap();
+
+        unsafe {
+            let buf = buffer_from_bytes(&prev);
+
+            for edit in d.edits(&prev).unwrap() {
+                match edit {
+                    ByteEdit::Insert { index, byte } => {
+                        librender_insert_byte(buf, index as size_t, byte)
+                    }
+                    ByteEdit::Remove { index } => librender_remove_byte(buf, index as size_t),
+                }
+            }
+
+            assert_eq!(buffer_bytes(buf), next);
+            librender_free_buffer(buf);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_the_c_functions() {
+        let (prev, next) = programs();
+
+        unsafe {
+            let prev = buffer_from_bytes(&prev);
+            let next_buf = buffer_from_bytes(&next);
+            let d = librender_delta_buffer(prev, next_buf);
+            let applied = librender_apply_delta(prev, d);
+
+            assert_eq!(buffer_bytes(applied), next);
+            assert!(librender_apply_delta(next_buf, d).is_null());
+
+            let empty = librender_create_buffer(0);
+            assert!(librender_apply_delta(prev, empty).is_null());
+
+            for buf in [prev, next_buf, d, applied, empty] {
+                librender_free_buffer(buf);
+            }
+        }
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Diagnostics that show the bytes around a fault.
+//!
+//! ```text
+//! error: set_attribute value length 40 exceeds remaining 12 bytes at offset 0x1f6
+//!   --> in Card (card.tsx:10)
+//!      |
+//! 01e0 | 0a 05 63 6c 69 63 6b 03 03 01 03 64 69 76 00 02  |..click....div..|
+//! 01f0 | 05 63 6c 61 73 73 28 6f 6b 2d 62 74 6e 20 70 72  |.class(ok-btn pr|
+//!      | ~~ ~~ ~~ ~~ ~~ ~~ ^^
+//!      = instruction at 0x01ef: set_attribute (truncated)
+//! ```
+
+use std::fmt::{self, Write};
+
+use crate::debug::DebugInfo;
+use crate::decode::{
+    decode_at, opcode_arity, opcode_mnemonic, opcode_operands, DecodeError, DecodeErrorKind,
+};
+use crate::interp::{interpret, InterpretError, InterpretErrorKind};
+
+/// Bytes per row of the hex dump.
+pub const ROW_LENGTH: usize = 16;
+
+/// Rows shown before the faulting row.
+const CONTEXT_ROWS: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Offset of the faulting byte.
+    pub offset: usize,
+    /// Offset of the instruction containing the faulting byte.
+    pub instruction_offset: Option<usize>,
+    /// Where the offset is in the source, from debug info.
+    pub context: Option<String>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>, offset: usize) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+            offset,
+            instruction_offset: None,
+            context: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn error(message: impl Into<String>, offset: usize) -> Self {
+        Self::new(Severity::Error, message, offset)
+    }
+
+    pub fn warning(message: impl Into<String>, offset: usize) -> Self {
+        Self::new(Severity::Warning, message, offset)
+    }
+
+    pub fn with_instruction(mut self, offset: usize) -> Self {
+        self.instruction_offset = Some(offset);
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Looks up the component containing the offset.
+    pub fn with_debug_info(mut self, debug: Option<&DebugInfo>) -> Self {
+        self.context = debug.and_then(|debug| debug.context(self.offset));
+        self
+    }
+
+    /// Builds a diagnostic naming the instruction and operand that failed to
+    /// decode.
+    pub fn decode(program: &[u8], err: &DecodeError) -> Self {
+        let opcode = program.get(err.instruction_offset).copied().unwrap_or(0);
+        let mnemonic = opcode_mnemonic(opcode).unwrap_or("unknown");
+        let operand = operand_at(program, err);
+
+        let message = match err.kind {
+            DecodeErrorKind::UnknownOpcode(op) => {
+                format!("unknown opcode 0x{:02x} at offset 0x{:x}", op, err.offset)
+            }
+            DecodeErrorKind::UnexpectedEnd => {
+                format!("unexpected end of program at offset 0x{:x}", err.offset)
+            }
+            DecodeErrorKind::MissingLength => format!(
+                "{} {} length is missing at offset 0x{:x}",
+                mnemonic, operand, err.offset
+            ),
+            DecodeErrorKind::Truncated { length, remaining } => format!(
+                "{} {} length {} exceeds remaining {} bytes at offset 0x{:x}",
+                mnemonic, operand, length, remaining, err.offset
+            ),
+        };
+
+        Self::error(message, err.offset).with_instruction(err.instruction_offset)
+    }
+
+    pub fn interpret(program: &[u8], err: &InterpretError) -> Self {
+        match &err.kind {
+            InterpretErrorKind::Decode(err) => Self::decode(program, err),
+            _ => Self::error(err.to_string(), err.offset).with_instruction(err.offset),
+        }
+    }
+
+    /// Renders the message, the source context, a hex dump of the bytes
+    /// leading up to the offset with a caret under the faulting byte, and
+    /// the instruction at fault.
+    pub fn render(&self, program: &[u8]) -> String {
+        let mut out = String::new();
+        let width = format!("{:x}", program.len().max(1)).len().max(4);
+        let gutter = " ".repeat(width);
+
+        let _ = writeln!(out, "{}", self);
+
+        if !program.is_empty() {
+            let offset = self.offset.min(program.len() - 1);
+            let start = self.instruction_offset.unwrap_or(offset).min(offset);
+            let row = offset / ROW_LENGTH;
+            let first = (start / ROW_LENGTH).max(row.saturating_sub(CONTEXT_ROWS));
+
+            let _ = writeln!(out, "{} |", gutter);
+
+            for r in first..=row {
+                let begin = r * ROW_LENGTH;
+                let bytes = &program[begin..program.len().min(begin + ROW_LENGTH)];
+                let _ = writeln!(
+                    out,
+                    "{:0width$x} | {}",
+                    begin,
+                    hex_row(bytes),
+                    width = width
+                );
+            }
+
+            let mut marks = String::new();
+
+            for i in row * ROW_LENGTH..=offset {
+                marks.push_str(match i {
+                    i if i == offset => "^^",
+                    i if i >= start => "~~ ",
+                    _ => "   ",
+                });
+            }
+
+            let _ = writeln!(out, "{} | {}", gutter, marks);
+        }
+
+        if let Some(at) = self.instruction_offset.filter(|&at| at < program.len()) {
+            let instruction = match decode_at(program, at) {
+                Ok((instruction, _)) => instruction.to_string(),
+                Err(_) => match opcode_mnemonic(program[at]) {
+                    Some(mnemonic) => format!("{} (truncated)", mnemonic),
+                    None => format!("unknown opcode 0x{:02x}", program[at]),
+                },
+            };
+
+            let _ = writeln!(
+                out,
+                "{} = instruction at 0x{:04x}: {}",
+                gutter, at, instruction
+            );
+        }
+
+        for note in &self.notes {
+            let _ = writeln!(out, "{} = note: {}", gutter, note);
+        }
+
+        out
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)?;
+
+        if let Some(context) = &self.context {
+            write!(f, "\n  --> {}", context)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+fn hex_row(bytes: &[u8]) -> String {
+    let mut out = String::new();
+
+    for i in 0..ROW_LENGTH {
+        match bytes.get(i) {
+            Some(b) => {
+                let _ = write!(out, "{:02x} ", b);
+            }
+            None => out.push_str("   "),
+        }
+    }
+
+    out.push_str(" |");
+    out.extend(bytes.iter().map(|&b| match b {
+        0x20..=0x7e => b as char,
+        _ => '.',
+    }));
+    out.push('|');
+
+    out
+}
+
+/// Name of the operand whose length prefix is at `err.offset`.
+fn operand_at(program: &[u8], err: &DecodeError) -> &'static str {
+    let Some(&opcode) = program.get(err.instruction_offset) else {
+        return "operand";
+    };
+    let names = opcode_operands(opcode).unwrap_or(&[]);
+    let mut pos = err.instruction_offset + 1;
+
+    for index in 0..opcode_arity(opcode).unwrap_or(0) {
+        if pos >= err.offset {
+            return names.get(index).copied().unwrap_or("operand");
+        }
+
+        pos += 1 + program.get(pos).copied().unwrap_or(0) as usize;
+    }
+
+    "operand"
+}
+
+/// Checks that `program` decodes and runs, warning about nodes that are
+/// never attached. Diagnostics are in offset order.
+pub fn validate(program: &[u8], debug: Option<&DebugInfo>) -> Vec<Diagnostic> {
+    let tree = match interpret(program) {
+        Ok(tree) => tree,
+        Err(err) => return vec![Diagnostic::interpret(program, &err).with_debug_info(debug)],
+    };
+
+    let mut diagnostics: Vec<Diagnostic> = tree
+        .orphans
+        .iter()
+        .map(|&id| {
+            let offset = tree.nodes[id].offset;
+
+            Diagnostic::warning(format!("node {} is never attached", id), offset)
+                .with_instruction(offset)
+                .with_note("nodes left on the stack are not rendered")
+                .with_debug_info(debug)
+        })
+        .collect();
+
+    diagnostics.sort_by_key(|d| d.offset);
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::debug::{DebugEntry, SourceLocation};
+    use crate::decode::decode;
+
+    /// The program of the example in the module documentation.
+    fn example() -> Vec<u8> {
+        let mut program = assemble("create_element \"div\"").unwrap();
+
+        program.resize(0x1e0, 0);
+        program.extend_from_slice(b"\x0a\x05click\x03\x03\x01\x03div\x00");
+        program.extend_from_slice(b"\x02\x05class\x28ok-btn prima");
+        program
+    }
+
+    #[test]
+    fn renders_the_documented_example() {
+        let program = example();
+        let debug = DebugInfo::new(vec![DebugEntry {
+            range: 0x1e0..program.len(),
+            name: "Card".to_string(),
+            location: Some(SourceLocation {
+                file: "card.tsx".to_string(),
+                line: 10,
+            }),
+        }]);
+        let diagnostic = Diagnostic::decode(&program, &decode(&program).unwrap_err())
+            .with_debug_info(Some(&debug));
+
+        assert_eq!(
+            diagnostic.render(&program),
+            concat!(
+                "error: set_attribute value length 40 exceeds remaining 12 bytes at offset 0x1f6\n",
+                "  --> in Card (card.tsx:10)\n",
+                "     |\n",
+                "01e0 | 0a 05 63 6c 69 63 6b 03 03 01 03 64 69 76 00 02  |..click....div..|\n",
+                "01f0 | 05 63 6c 61 73 73 28 6f 6b 2d 62 74 6e 20 70 72  |.class(ok-btn pr|\n",
+                "     | ~~ ~~ ~~ ~~ ~~ ~~ ^^\n",
+                "     = instruction at 0x01ef: set_attribute (truncated)\n",
+            )
+        );
+    }
+
+    #[test]
+    fn validate_reports_the_first_error() {
+        let mut program = assemble("create_element \"a\"\ncreate_element \"b\"").unwrap();
+
+        program.extend_from_slice(b"\x02\x05cl");
+
+        let diagnostics = validate(&program, None);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(
+            (diagnostics[0].offset, diagnostics[0].instruction_offset),
+            (7, Some(6))
+        );
+
+        program.insert(0, crate::OPCODE_APPEND_CHILD as u8);
+
+        let diagnostics = validate(&program, None);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].offset, 0);
+    }
+
+    #[test]
+    fn validate_warns_about_orphans_in_offset_order() {
+        let program =
+            assemble("create_element \"a\"\ncreate_element \"b\"\ntext_node \"c\"").unwrap();
+        let diagnostics = validate(&program, None);
+        let offsets: Vec<_> = diagnostics.iter().map(|d| (d.severity, d.offset)).collect();
+
+        assert_eq!(offsets, [(Severity::Warning, 3), (Severity::Warning, 6)]);
+    }
+}
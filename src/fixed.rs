@@ -0,0 +1,382 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Building programs into caller-provided memory.
+//!
+//! A [`FixedBuilder`] writes into a `&mut [u8]` through the same
+//! `librender_*` emitters as a heap buffer, set up with
+//! `librender_init_fixed_buffer`, and never allocates. An instruction that
+//! does not fit is not written at all and fails with
+//! [`FixedError::Capacity`], leaving the bytes written so far a valid
+//! program. Every later instruction then fails with
+//! [`FixedError::Overflowed`] until [`FixedBuilder::clear`], so the program
+//! never skips an instruction in the middle. This suits stack buffers,
+//! arenas and memory shared with another process.
+//!
+//! From C, initialize a `librender_bytecode_buffer` with
+//! `librender_init_fixed_buffer` and check `librender_buffer_overflowed`
+//! after emitting.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+
+use crate::decode::MAX_PAYLOAD_LENGTH;
+use crate::{
+    buffer_bytes, librender_add_event_listener, librender_append_child, librender_append_sibling,
+    librender_buffer_overflowed, librender_bytecode_buffer, librender_clear_buffer,
+    librender_create_element, librender_init_fixed_buffer, librender_nop, librender_placeholder,
+    librender_remove_attribute, librender_remove_child, librender_replace_child,
+    librender_set_attribute, librender_set_style, librender_set_text, librender_text_node, size_t,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FixedError {
+    /// The instruction needs `required` bytes but only `available` are left.
+    Capacity {
+        required: usize,
+        available: usize,
+    },
+    PayloadTooLong(usize),
+    /// The `librender_*` emitters do not write empty payloads.
+    EmptyPayload,
+    /// Payloads are Latin-1, so they cannot hold this character.
+    NotLatin1(char),
+    /// An earlier instruction did not fit, so nothing more is written until
+    /// the builder is cleared.
+    Overflowed,
+}
+
+impl fmt::Display for FixedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FixedError::Capacity {
+                required,
+                available,
+            } => write!(
+                f,
+                "instruction of {} bytes does not fit in the {} bytes left",
+                required, available
+            ),
+            FixedError::PayloadTooLong(length) => write!(
+                f,
+                "payload of {} bytes exceeds {} bytes",
+                length, MAX_PAYLOAD_LENGTH
+            ),
+            FixedError::EmptyPayload => write!(f, "payload is empty"),
+            FixedError::NotLatin1(c) => write!(f, "{:?} is not an 8-bit character", c),
+            FixedError::Overflowed => write!(f, "buffer overflowed earlier"),
+        }
+    }
+}
+
+impl std::error::Error for FixedError {}
+
+pub struct FixedBuilder<'a> {
+    buf: librender_bytecode_buffer,
+    data: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> FixedBuilder<'a> {
+    pub fn new(data: &'a mut [u8]) -> Self {
+        let mut buf = MaybeUninit::uninit();
+
+        // SAFETY: `librender_init_fixed_buffer` sets every field, and `data`
+        // stays borrowed for as long as the buffer points into it.
+        let buf = unsafe {
+            librender_init_fixed_buffer(buf.as_mut_ptr(), data.as_mut_ptr(), data.len() as size_t);
+            buf.assume_init()
+        };
+
+        Self {
+            buf,
+            data: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.size as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.size == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity as usize
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.capacity() - self.len()
+    }
+
+    /// The program written so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { buffer_bytes(&self.buf) }
+    }
+
+    /// Ends the builder and returns the program as a prefix of the slice it
+    /// was created with.
+    pub fn into_bytes(self) -> &'a [u8] {
+        unsafe { buffer_bytes(&self.buf) }
+    }
+
+    pub fn clear(&mut self) {
+        unsafe { librender_clear_buffer(&mut self.buf) }
+    }
+
+    /// Whether an instruction did not fit. Nothing more is written until
+    /// [`FixedBuilder::clear`].
+    pub fn overflowed(&self) -> bool {
+        unsafe { librender_buffer_overflowed(&self.buf) != 0 }
+    }
+
+    /// Runs a `librender_*` emitter for an instruction of `payloads`, which
+    /// it receives encoded as Latin-1 like the interpreter reads them,
+    /// mapping an overflow of the buffer to [`FixedError::Capacity`].
+    fn emit<const N: usize>(
+        &mut self,
+        payloads: [&str; N],
+        write: impl FnOnce(*mut librender_bytecode_buffer, [&[u8]; N]),
+    ) -> Result<(), FixedError> {
+        let mut data = [[0u8; MAX_PAYLOAD_LENGTH]; N];
+        let mut lengths = [0usize; N];
+
+        for (i, payload) in payloads.iter().enumerate() {
+            for c in payload.chars() {
+                if c as u32 > 0xff {
+                    return Err(FixedError::NotLatin1(c));
+                }
+
+                if lengths[i] == MAX_PAYLOAD_LENGTH {
+                    return Err(FixedError::PayloadTooLong(payload.chars().count()));
+                }
+
+                data[i][lengths[i]] = c as u8;
+                lengths[i] += 1;
+            }
+
+            if lengths[i] == 0 {
+                return Err(FixedError::EmptyPayload);
+            }
+        }
+
+        if self.overflowed() {
+            return Err(FixedError::Overflowed);
+        }
+
+        let available = self.remaining();
+        let payloads: [&[u8]; N] = std::array::from_fn(|i| &data[i][..lengths[i]]);
+
+        write(&mut self.buf, payloads);
+
+        // The flag stays set, as it does for C callers, so that a smaller
+        // instruction cannot be written after the one that was dropped.
+        if self.overflowed() {
+            Err(FixedError::Capacity {
+                required: 1 + lengths.iter().map(|n| 1 + n).sum::<usize>(),
+                available,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn create_element(&mut self, tag: &str) -> Result<(), FixedError> {
+        self.emit([tag], |buf, [tag]| unsafe {
+            librender_create_element(buf, tag.as_ptr().cast(), tag.len() as u8)
+        })
+    }
+
+    pub fn set_attribute(&mut self, name: &str, value: &str) -> Result<(), FixedError> {
+        self.emit([name, value], |buf, [name, value]| unsafe {
+            librender_set_attribute(
+                buf,
+                name.as_ptr().cast(),
+                name.len() as u8,
+                value.as_ptr().cast(),
+                value.len() as u8,
+            )
+        })
+    }
+
+    pub fn append_child(&mut self) -> Result<(), FixedError> {
+        self.emit([], |buf, []| unsafe { librender_append_child(buf) })
+    }
+
+    pub fn append_sibling(&mut self) -> Result<(), FixedError> {
+        self.emit([], |buf, []| unsafe { librender_append_sibling(buf) })
+    }
+
+    pub fn remove_child(&mut self) -> Result<(), FixedError> {
+        self.emit([], |buf, []| unsafe { librender_remove_child(buf) })
+    }
+
+    pub fn replace_child(&mut self) -> Result<(), FixedError> {
+        self.emit([], |buf, []| unsafe { librender_replace_child(buf) })
+    }
+
+    pub fn text_node(&mut self, text: &str) -> Result<(), FixedError> {
+        self.emit([text], |buf, [text]| unsafe {
+            librender_text_node(buf, text.as_ptr().cast(), text.len() as u8)
+        })
+    }
+
+    pub fn set_text(&mut self, text: &str) -> Result<(), FixedError> {
+        self.emit([text], |buf, [text]| unsafe {
+            librender_set_text(buf, text.as_ptr().cast(), text.len() as u8)
+        })
+    }
+
+    pub fn remove_attribute(&mut self, name: &str) -> Result<(), FixedError> {
+        self.emit([name], |buf, [name]| unsafe {
+            librender_remove_attribute(buf, name.as_ptr().cast(), name.len() as u8)
+        })
+    }
+
+    pub fn set_style(&mut self, name: &str, value: &str) -> Result<(), FixedError> {
+        self.emit([name, value], |buf, [name, value]| unsafe {
+            librender_set_style(
+                buf,
+                name.as_ptr().cast(),
+                name.len() as u8,
+                value.as_ptr().cast(),
+                value.len() as u8,
+            )
+        })
+    }
+
+    pub fn add_event_listener(&mut self, event: &str) -> Result<(), FixedError> {
+        self.emit([event], |buf, [event]| unsafe {
+            librender_add_event_listener(buf, event.as_ptr().cast(), event.len() as u8)
+        })
+    }
+
+    pub fn placeholder(&mut self, slot: &str) -> Result<(), FixedError> {
+        self.emit([slot], |buf, [slot]| unsafe {
+            librender_placeholder(buf, slot.as_ptr().cast(), slot.len() as u8)
+        })
+    }
+
+    pub fn nop(&mut self) -> Result<(), FixedError> {
+        self.emit([], |buf, []| unsafe { librender_nop(buf) })
+    }
+}
+
+impl fmt::Debug for FixedBuilder<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FixedBuilder")
+            .field("len", &self.len())
+            .field("capacity", &self.capacity())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::decode;
+    use crate::io::Builder;
+
+    #[test]
+    fn fills_the_buffer_exactly() {
+        let mut data = [0u8; 5];
+        let mut builder = FixedBuilder::new(&mut data);
+
+        builder.create_element("div").unwrap();
+        assert_eq!(builder.remaining(), 0);
+        assert!(!builder.overflowed());
+
+        assert_eq!(
+            builder.nop(),
+            Err(FixedError::Capacity {
+                required: 1,
+                available: 0
+            })
+        );
+        assert_eq!(builder.into_bytes(), b"\x01\x03div");
+    }
+
+    #[test]
+    fn writes_nothing_of_an_instruction_one_byte_short() {
+        let mut data = [0u8; 4];
+        let mut builder = FixedBuilder::new(&mut data);
+
+        assert_eq!(
+            builder.create_element("div"),
+            Err(FixedError::Capacity {
+                required: 5,
+                available: 4
+            })
+        );
+        assert!(builder.is_empty());
+    }
+
+    #[test]
+    fn refuses_everything_after_an_overflow() {
+        let mut data = [0u8; 10];
+        let mut builder = FixedBuilder::new(&mut data);
+
+        builder.create_element("div").unwrap();
+        assert_eq!(
+            builder.text_node("hello world"),
+            Err(FixedError::Capacity {
+                required: 13,
+                available: 5
+            })
+        );
+
+        // Both would fit, but writing them would drop the text from the
+        // middle of the program.
+        assert_eq!(builder.append_child(), Err(FixedError::Overflowed));
+        assert_eq!(builder.text_node("hi"), Err(FixedError::Overflowed));
+        assert!(builder.overflowed());
+        assert_eq!(builder.as_bytes(), b"\x01\x03div");
+
+        builder.clear();
+        assert!(!builder.overflowed());
+        builder.text_node("hi").unwrap();
+        builder.nop().unwrap();
+        assert_eq!(decode(builder.into_bytes()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn checks_payloads_before_writing() {
+        let mut data = [0u8; 600];
+        let mut builder = FixedBuilder::new(&mut data);
+        let longest = "\u{e9}".repeat(MAX_PAYLOAD_LENGTH);
+
+        assert_eq!(builder.text_node(""), Err(FixedError::EmptyPayload));
+        assert_eq!(
+            builder.text_node(&"a".repeat(MAX_PAYLOAD_LENGTH + 1)),
+            Err(FixedError::PayloadTooLong(MAX_PAYLOAD_LENGTH + 1))
+        );
+        assert_eq!(
+            builder.set_attribute("title", "\u{2713}"),
+            Err(FixedError::NotLatin1('\u{2713}'))
+        );
+        assert!(builder.is_empty());
+
+        builder.text_node(&longest).unwrap();
+        assert_eq!(builder.len(), 2 + MAX_PAYLOAD_LENGTH);
+    }
+
+    #[test]
+    fn matches_the_io_builder() {
+        let mut data = [0u8; 64];
+        let mut fixed = FixedBuilder::new(&mut data);
+        let mut builder = Builder::new(Vec::new());
+
+        fixed.create_element("p").unwrap();
+        fixed.set_style("font-family", "caf\u{e9}").unwrap();
+        fixed.text_node("na\u{ef}ve").unwrap();
+        fixed.append_child().unwrap();
+
+        builder.create_element("p").unwrap();
+        builder.set_style("font-family", "caf\u{e9}").unwrap();
+        builder.text_node("na\u{ef}ve").unwrap();
+        builder.append_child().unwrap();
+
+        let (expected, _) = builder.finish().unwrap();
+        assert_eq!(fixed.into_bytes(), expected);
+    }
+}
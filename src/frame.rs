@@ -0,0 +1,572 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Framed instruction blocks for streaming.
+//!
+//! `unsafe_streamBytecodeToVM` in `stream.ts` runs whatever bytes a chunk
+//! happens to contain. Frames fix the boundaries instead: every frame holds
+//! only complete instructions, and [`split`] only cuts a program where the
+//! node stack is back at the root, so each frame appends whole subtrees.
+//!
+//! ```text
+//! length: u32 LE | sequence: u32 LE | kind: u8 | payload (length bytes)
+//! ```
+//!
+//! Fill frames carry the contents of a slot reserved by a `placeholder`
+//! instruction, so slow sections can be streamed after the rest of the
+//! program. Their payload is prefixed with the slot ID:
+//!
+//! ```text
+//! slot length: u8 | slot | instructions
+//! ```
+
+use std::fmt;
+
+use crate::decode::{decode, encode, DecodeError, Instruction, MAX_PAYLOAD_LENGTH};
+use crate::interp::{InterpretError, Interpreter, Tree};
+use crate::{buffer_bytes, buffer_from_bytes, librender_bytecode_buffer, size_t};
+
+pub const FRAME_HEADER_LENGTH: usize = 9;
+
+/// Default payload size [`split`] aims for.
+pub const DEFAULT_BLOCK_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum FrameKind {
+    /// Payload is a run of complete instructions.
+    Instructions = 0,
+    /// Payload is a slot ID followed by the program that fills the slot.
+    Fill = 1,
+}
+
+impl FrameKind {
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(FrameKind::Instructions),
+            1 => Some(FrameKind::Fill),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub sequence: u32,
+    pub kind: FrameKind,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn encoded_len(&self) -> usize {
+        FRAME_HEADER_LENGTH + self.payload.len()
+    }
+
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.sequence.to_le_bytes());
+        out.push(self.kind as u8);
+        out.extend_from_slice(&self.payload);
+    }
+
+    /// Splits a fill frame payload into slot ID and program.
+    pub fn fill(&self) -> Option<(&[u8], &[u8])> {
+        if self.kind != FrameKind::Fill {
+            return None;
+        }
+
+        let (&length, rest) = self.payload.split_first()?;
+        (rest.len() >= length as usize).then(|| rest.split_at(length as usize))
+    }
+
+    /// Instructions carried by the frame, without the slot ID of fill frames.
+    pub fn program(&self) -> &[u8] {
+        match self.kind {
+            FrameKind::Instructions => &self.payload,
+            FrameKind::Fill => self.fill().map_or(&[], |(_, program)| program),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The input ends inside the header or payload of the frame at `offset`.
+    Truncated {
+        offset: usize,
+    },
+    UnknownKind {
+        offset: usize,
+        kind: u8,
+    },
+    /// A frame payload does not consist of complete instructions.
+    Payload {
+        sequence: u32,
+        error: DecodeError,
+    },
+    /// Frames are missing or repeated; `expected` is the next sequence number.
+    Sequence {
+        expected: u32,
+        found: u32,
+    },
+    /// A fill frame has no complete slot ID.
+    MissingSlot {
+        sequence: u32,
+    },
+    /// Fill frames cannot be flattened into a single program.
+    Fill {
+        sequence: u32,
+    },
+    Interpret {
+        sequence: u32,
+        error: InterpretError,
+    },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated { offset } => {
+                write!(f, "truncated frame at offset 0x{:x}", offset)
+            }
+            FrameError::UnknownKind { offset, kind } => {
+                write!(f, "unknown frame kind {} at offset 0x{:x}", kind, offset)
+            }
+            FrameError::Payload { sequence, error } => write!(f, "frame {}: {}", sequence, error),
+            FrameError::Sequence { expected, found } => {
+                write!(f, "expected frame {}, found frame {}", expected, found)
+            }
+            FrameError::MissingSlot { sequence } => {
+                write!(f, "frame {}: missing slot ID", sequence)
+            }
+            FrameError::Fill { sequence } => {
+                write!(f, "frame {}: fill frames cannot be joined", sequence)
+            }
+            FrameError::Interpret { sequence, error } => {
+                write!(f, "frame {}: {}", sequence, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Reads the frame at the start of `data`, returning it with its encoded
+/// length, or `None` if `data` does not hold a complete frame yet.
+pub fn read_frame(data: &[u8]) -> Result<Option<(Frame, usize)>, FrameError> {
+    if data.len() < FRAME_HEADER_LENGTH {
+        return Ok(None);
+    }
+
+    let length = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+    let sequence = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    let kind = FrameKind::from_u8(data[8]).ok_or(FrameError::UnknownKind {
+        offset: 0,
+        kind: data[8],
+    })?;
+
+    let Some(payload) = data.get(FRAME_HEADER_LENGTH..FRAME_HEADER_LENGTH + length) else {
+        return Ok(None);
+    };
+
+    let frame = Frame {
+        sequence,
+        kind,
+        payload: payload.to_vec(),
+    };
+
+    Ok(Some((frame, FRAME_HEADER_LENGTH + length)))
+}
+
+/// Reads all frames in `data` and checks that each payload is a complete
+/// instruction block.
+pub fn read_frames(data: &[u8]) -> Result<Vec<Frame>, FrameError> {
+    let mut frames = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let (frame, length) = read_frame(&data[offset..])
+            .map_err(|err| match err {
+                FrameError::UnknownKind { kind, .. } => FrameError::UnknownKind { offset, kind },
+                err => err,
+            })?
+            .ok_or(FrameError::Truncated { offset })?;
+
+        if frame.kind == FrameKind::Fill && frame.fill().is_none() {
+            return Err(FrameError::MissingSlot {
+                sequence: frame.sequence,
+            });
+        }
+
+        decode(frame.program()).map_err(|error| FrameError::Payload {
+            sequence: frame.sequence,
+            error,
+        })?;
+
+        frames.push(frame);
+        offset += length;
+    }
+
+    Ok(frames)
+}
+
+pub fn write_frames(frames: &[Frame]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(frames.iter().map(Frame::encoded_len).sum());
+
+    for frame in frames {
+        frame.encode_into(&mut out);
+    }
+
+    out
+}
+
+fn check_sequence(frames: &[Frame]) -> Result<(), FrameError> {
+    for (i, frame) in frames.iter().enumerate() {
+        let expected = frames[0].sequence.wrapping_add(i as u32);
+
+        if frame.sequence != expected {
+            return Err(FrameError::Sequence {
+                expected,
+                found: frame.sequence,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Concatenates instruction frame payloads back into a program, requiring
+/// consecutive sequence numbers starting at the first frame's.
+pub fn join(frames: &[Frame]) -> Result<Vec<u8>, FrameError> {
+    check_sequence(frames)?;
+
+    let mut program = Vec::new();
+
+    for frame in frames {
+        if frame.kind == FrameKind::Fill {
+            return Err(FrameError::Fill {
+                sequence: frame.sequence,
+            });
+        }
+
+        program.extend_from_slice(&frame.payload);
+    }
+
+    Ok(program)
+}
+
+/// Interprets frames one at a time as they arrive, checking that sequence
+/// numbers are consecutive.
+#[derive(Clone, Debug, Default)]
+pub struct FrameRunner {
+    interpreter: Interpreter,
+    next: Option<u32>,
+    offset: usize,
+}
+
+impl FrameRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn interpreter(&self) -> &Interpreter {
+        &self.interpreter
+    }
+
+    /// Runs `frame`, filling its slot if it is a fill frame. A frame that
+    /// fails leaves the runner as it was, so none of it is applied.
+    pub fn push(&mut self, frame: &Frame) -> Result<(), FrameError> {
+        let sequence = frame.sequence;
+        let expected = self.next.unwrap_or(sequence);
+
+        if sequence != expected {
+            return Err(FrameError::Sequence {
+                expected,
+                found: sequence,
+            });
+        }
+
+        let fail = |error| FrameError::Interpret { sequence, error };
+        let mut interpreter = self.interpreter.clone();
+        let mut offset = self.offset;
+
+        match frame.kind {
+            FrameKind::Instructions => {
+                for instruction in decode(&frame.payload).map_err(|e| fail(e.into()))? {
+                    interpreter.step(&instruction, offset).map_err(fail)?;
+                    offset += instruction.encoded_len();
+                }
+            }
+            FrameKind::Fill => {
+                let (slot, program) = frame.fill().ok_or(FrameError::MissingSlot { sequence })?;
+                interpreter.fill(slot, program).map_err(fail)?;
+            }
+        }
+
+        self.interpreter = interpreter;
+        self.offset = offset;
+        self.next = Some(sequence.wrapping_add(1));
+        Ok(())
+    }
+
+    pub fn finish(self) -> Tree {
+        self.interpreter.finish()
+    }
+}
+
+/// Interprets a stream of frames in order, filling slots as fill frames
+/// arrive.
+pub fn run_frames(frames: &[Frame]) -> Result<Tree, FrameError> {
+    let mut runner = FrameRunner::new();
+
+    for frame in frames {
+        runner.push(frame)?;
+    }
+
+    Ok(runner.finish())
+}
+
+/// Encodes a placeholder for `slot` whose fallback is `fallback`, which may
+/// be empty. The placeholder still needs to be appended to its parent.
+pub fn placeholder(slot: &[u8], fallback: &[u8]) -> Vec<u8> {
+    let mut program = encode(&[Instruction::Placeholder(slot.to_vec())]);
+
+    if !fallback.is_empty() {
+        program.extend_from_slice(fallback);
+        Instruction::AppendChild.encode_into(&mut program);
+    }
+
+    program
+}
+
+/// Hands out consecutive sequence numbers to the frames of one stream.
+#[derive(Clone, Debug, Default)]
+pub struct FrameWriter {
+    sequence: u32,
+}
+
+impl FrameWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn frame(&mut self, kind: FrameKind, payload: Vec<u8>) -> Frame {
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        Frame {
+            sequence,
+            kind,
+            payload,
+        }
+    }
+
+    /// Frames a block of complete instructions.
+    pub fn instructions(&mut self, program: &[u8]) -> Frame {
+        self.frame(FrameKind::Instructions, program.to_vec())
+    }
+
+    /// Frames the real contents of `slot`, to be sent whenever they are
+    /// ready.
+    ///
+    /// # Panics
+    ///
+    /// If `slot` is longer than [`MAX_PAYLOAD_LENGTH`], as no placeholder
+    /// can name it.
+    pub fn fill(&mut self, slot: &[u8], program: &[u8]) -> Frame {
+        assert!(
+            slot.len() <= MAX_PAYLOAD_LENGTH,
+            "slot ID of {} bytes exceeds {} bytes",
+            slot.len(),
+            MAX_PAYLOAD_LENGTH
+        );
+
+        let mut payload = Vec::with_capacity(1 + slot.len() + program.len());
+
+        payload.push(slot.len() as u8);
+        payload.extend_from_slice(slot);
+        payload.extend_from_slice(program);
+
+        self.frame(FrameKind::Fill, payload)
+    }
+
+    /// Frames `program` as in [`split`], continuing this stream's sequence.
+    pub fn split(
+        &mut self,
+        program: &[u8],
+        block_size: usize,
+    ) -> Result<Vec<Frame>, InterpretError> {
+        let instructions = decode(program)?;
+        let mut interpreter = Interpreter::new();
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut payload: Vec<u8> = Vec::new();
+        let mut offset = 0;
+
+        for instruction in &instructions {
+            interpreter.step(instruction, offset)?;
+            offset += instruction.encoded_len();
+            instruction.encode_into(&mut payload);
+
+            if interpreter.stack().len() <= 1 && payload.len() >= block_size {
+                frames.push(self.frame(FrameKind::Instructions, std::mem::take(&mut payload)));
+            }
+        }
+
+        if !payload.is_empty() {
+            frames.push(self.frame(FrameKind::Instructions, payload));
+        }
+
+        Ok(frames)
+    }
+}
+
+/// Splits `program` into frames of roughly `block_size` payload bytes. Cuts
+/// are only made between instructions where the node stack holds just the
+/// root, so a subtree larger than `block_size` ends up in a single frame.
+pub fn split(program: &[u8], block_size: usize) -> Result<Vec<Frame>, InterpretError> {
+    FrameWriter::new().split(program, block_size)
+}
+
+/// Frames the contents of `buf` into a new buffer, cutting at subtree
+/// boundaries every `block_size` bytes (or [`DEFAULT_BLOCK_SIZE`] if zero).
+/// Returns null if the program does not interpret.
+///
+/// # Safety
+///
+/// `buf` must be null or point to a valid buffer.
+#[no_mangle]
+pub unsafe extern "C" fn librender_frame_buffer(
+    buf: *const librender_bytecode_buffer,
+    block_size: size_t,
+) -> *mut librender_bytecode_buffer {
+    if buf.is_null() {
+        return std::ptr::null_mut();
+    }
+
+    let block_size = match block_size {
+        0 => DEFAULT_BLOCK_SIZE,
+        n => n as usize,
+    };
+
+    match split(buffer_bytes(buf), block_size) {
+        Ok(frames) => buffer_from_bytes(&write_frames(&frames)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::interp::interpret;
+    use crate::librender_free_buffer;
+
+    /// A list of `items` items, each a subtree of about 20 bytes.
+    fn list(items: usize) -> Vec<u8> {
+        let mut source = String::from("create_element \"ul\"\n");
+
+        for i in 0..items {
+            source += &format!(
+                "create_element \"li\"\ntext_node \"item {}\"\nappend_child\nappend_child\n",
+                i
+            );
+        }
+
+        assemble(&source).unwrap()
+    }
+
+    #[test]
+    fn split_round_trips_through_bytes() {
+        let program = list(50);
+        let frames = split(&program, 64).unwrap();
+        let read = read_frames(&write_frames(&frames)).unwrap();
+
+        assert!(frames.len() > 1);
+        assert_eq!(read, frames);
+        assert_eq!(join(&read).unwrap(), program);
+        assert!(run_frames(&read)
+            .unwrap()
+            .equivalent(&interpret(&program).unwrap()));
+    }
+
+    #[test]
+    fn split_cuts_only_at_the_root() {
+        for frame in split(&list(50), 1).unwrap().iter().skip(1) {
+            let first = decode(&frame.payload).unwrap().remove(0);
+            assert_eq!(first, Instruction::CreateElement(b"li".to_vec()));
+        }
+    }
+
+    #[test]
+    fn fill_frames_replace_placeholders() {
+        let mut program = assemble("create_element \"main\"").unwrap();
+
+        program.extend(placeholder(
+            b"feed",
+            &assemble("text_node \"loading\"").unwrap(),
+        ));
+        program.extend(assemble("append_child").unwrap());
+
+        let mut writer = FrameWriter::new();
+        let frames = [
+            writer.instructions(&program),
+            writer.fill(b"feed", &list(2)),
+        ];
+
+        let mut filled = assemble("create_element \"main\"").unwrap();
+
+        filled.extend(list(2));
+        filled.extend(assemble("append_child").unwrap());
+
+        assert!(run_frames(&frames)
+            .unwrap()
+            .equivalent(&interpret(&filled).unwrap()));
+    }
+
+    #[test]
+    fn rejects_gaps_in_sequence() {
+        let mut frames = split(&list(50), 64).unwrap();
+        frames.remove(1);
+
+        assert!(matches!(
+            join(&frames),
+            Err(FrameError::Sequence {
+                expected: 1,
+                found: 2
+            })
+        ));
+        assert!(matches!(
+            run_frames(&frames),
+            Err(FrameError::Sequence {
+                expected: 1,
+                found: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn frames_buffers_from_c() {
+        let program = list(50);
+
+        unsafe {
+            let buf = buffer_from_bytes(&program);
+            let framed = librender_frame_buffer(buf, 64);
+            let invalid = buffer_from_bytes(&assemble("append_child").unwrap());
+
+            assert_eq!(
+                read_frames(buffer_bytes(framed)).unwrap(),
+                split(&program, 64).unwrap()
+            );
+            assert!(librender_frame_buffer(std::ptr::null(), 0).is_null());
+            assert!(librender_frame_buffer(invalid, 0).is_null());
+
+            librender_free_buffer(framed);
+            librender_free_buffer(buf);
+            librender_free_buffer(invalid);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "slot ID of 256 bytes exceeds 255 bytes")]
+    fn refuses_to_truncate_slot_ids() {
+        FrameWriter::new().fill(&[b's'; MAX_PAYLOAD_LENGTH + 1], &[]);
+    }
+}
@@ -0,0 +1,123 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Server-side rendering of interpreted programs to HTML.
+//!
+//! Slots are rendered with their current contents (the fallback, until a
+//! fill arrives) between comment markers, so a client can swap in the
+//! `<template>` produced by [`render_slot_fill`] once the real subtree is
+//! streamed:
+//!
+//! ```text
+//! <!--$slot:ID-->fallback<!--/$slot-->
+//! <template data-librender-fill="ID">contents</template>
+//! ```
+//!
+//! Event listeners have no HTML representation and are left out.
+
+use std::fmt::Write;
+
+use crate::decode::latin1;
+use crate::interp::{interpret, InterpretError, NodeId, NodeKind, Tree};
+
+/// Elements that never have a closing tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+fn escape(out: &mut String, s: &str, attribute: bool) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' if attribute => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn render_node(tree: &Tree, id: NodeId, out: &mut String) {
+    let node = &tree.nodes[id];
+
+    match &node.kind {
+        NodeKind::Text(text) => escape(out, text, false),
+        NodeKind::Slot(slot) => {
+            out.push_str("<!--$slot:");
+            escape(out, &latin1(slot), true);
+            out.push_str("-->");
+            render_children(tree, id, out);
+            out.push_str("<!--/$slot-->");
+        }
+        NodeKind::Element {
+            tag,
+            attributes,
+            styles,
+            ..
+        } => {
+            let _ = write!(out, "<{}", tag);
+
+            for (name, value) in attributes {
+                let _ = write!(out, " {}=\"", name);
+                escape(out, value, true);
+                out.push('"');
+            }
+
+            if !styles.is_empty() {
+                let style = styles
+                    .iter()
+                    .map(|(name, value)| format!("{}: {}", name, value))
+                    .collect::<Vec<_>>()
+                    .join("; ");
+
+                out.push_str(" style=\"");
+                escape(out, &style, true);
+                out.push('"');
+            }
+
+            out.push('>');
+
+            if VOID_ELEMENTS.contains(&tag.as_str()) && node.children.is_empty() {
+                return;
+            }
+
+            render_children(tree, id, out);
+            let _ = write!(out, "</{}>", tag);
+        }
+    }
+}
+
+fn render_children(tree: &Tree, id: NodeId, out: &mut String) {
+    for &child in &tree.nodes[id].children {
+        render_node(tree, child, out);
+    }
+}
+
+/// Renders the top-level nodes of `tree`.
+pub fn render_html(tree: &Tree) -> String {
+    let mut out = String::new();
+
+    for &root in &tree.roots {
+        render_node(tree, root, &mut out);
+    }
+
+    out
+}
+
+/// Renders the current contents of `slot` as a fill template, or `None` if
+/// the tree has no such slot.
+pub fn render_slot_fill(tree: &Tree, slot: &[u8]) -> Option<String> {
+    let &id = tree.slots.get(slot)?;
+    let mut out = String::from("<template data-librender-fill=\"");
+
+    escape(&mut out, &latin1(slot), true);
+    out.push_str("\">");
+    render_children(tree, id, &mut out);
+    out.push_str("</template>");
+
+    Some(out)
+}
+
+pub fn render_program(program: &[u8]) -> Result<String, InterpretError> {
+    Ok(render_html(&interpret(program)?))
+}
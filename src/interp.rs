@@ -0,0 +1,591 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Reference interpreter for librender programs.
+//!
+//! This follows the node-stack model of the VM in `vm.ts`: creating an
+//! element or text node pushes it, `append_child` pops the most recent node
+//! into the node below it and `append_sibling` pops it and inserts it right
+//! after the node below it. Attributes, styles and listeners apply to the
+//! most recent element on the stack. Node IDs are creation ordinals, event
+//! IDs are listener ordinals.
+//!
+//! Placeholders create slot nodes that render as their children, which are
+//! the fallback until [`Interpreter::fill`] replaces them with the output of
+//! a later program.
+//!
+//! The resulting [`Tree`] is the semantic ground truth used by the optimizer
+//! and the other program transforms to check equivalence.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::decode::{latin1, DecodeError, Instruction, Instructions};
+
+pub type NodeId = usize;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Listener {
+    pub event: String,
+    pub id: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    Element {
+        tag: String,
+        attributes: BTreeMap<String, String>,
+        /// Inline style declarations in insertion order. The `style`
+        /// attribute is kept here rather than in `attributes`.
+        styles: Vec<(String, String)>,
+        listeners: Vec<Listener>,
+    },
+    Text(String),
+    /// Placeholder reserving a slot, see [`Interpreter::fill`].
+    Slot(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub kind: NodeKind,
+    pub parent: Option<NodeId>,
+    pub children: Vec<NodeId>,
+    /// Offset of the instruction that created the node.
+    pub offset: usize,
+}
+
+impl Node {
+    pub fn is_element(&self) -> bool {
+        matches!(self.kind, NodeKind::Element { .. })
+    }
+
+    /// Whether children can be appended to the node.
+    pub fn is_container(&self) -> bool {
+        matches!(self.kind, NodeKind::Element { .. } | NodeKind::Slot(_))
+    }
+}
+
+/// Arena of every node a program created, together with the nodes that form
+/// the rendered output.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Tree {
+    pub nodes: Vec<Node>,
+    /// Top-level nodes in document order: the first node of the program and
+    /// any nodes appended to the top level as its siblings.
+    pub roots: Vec<NodeId>,
+    /// Nodes left on the stack at the end of the program that were never
+    /// attached to the tree.
+    pub orphans: Vec<NodeId>,
+    /// Nodes detached by `remove_child` or `replace_child`.
+    pub removed: Vec<NodeId>,
+    pub listener_count: usize,
+    pub slots: HashMap<Vec<u8>, NodeId>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InterpretErrorKind {
+    Decode(DecodeError),
+    /// The instruction needs more nodes on the stack than are present.
+    StackUnderflow,
+    /// No element on the stack to apply an attribute, style or listener to.
+    NoElement,
+    /// Children can only be appended to elements.
+    NotAnElement(NodeId),
+    /// `append_sibling` target is neither attached nor top-level.
+    DetachedSibling(NodeId),
+    /// `remove_child`/`replace_child` on a node without children.
+    NoChild(NodeId),
+    DuplicateSlot(Vec<u8>),
+    UnknownSlot(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InterpretError {
+    pub offset: usize,
+    pub kind: InterpretErrorKind,
+}
+
+impl fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            InterpretErrorKind::Decode(err) => err.fmt(f),
+            InterpretErrorKind::StackUnderflow => {
+                write!(f, "node stack underflow at offset 0x{:x}", self.offset)
+            }
+            InterpretErrorKind::NoElement => {
+                write!(f, "no element on the stack at offset 0x{:x}", self.offset)
+            }
+            InterpretErrorKind::NotAnElement(id) => write!(
+                f,
+                "node {} is not an element at offset 0x{:x}",
+                id, self.offset
+            ),
+            InterpretErrorKind::DetachedSibling(id) => write!(
+                f,
+                "sibling target node {} is detached at offset 0x{:x}",
+                id, self.offset
+            ),
+            InterpretErrorKind::NoChild(id) => write!(
+                f,
+                "node {} has no children at offset 0x{:x}",
+                id, self.offset
+            ),
+            InterpretErrorKind::DuplicateSlot(slot) => write!(
+                f,
+                "slot {:?} is already reserved at offset 0x{:x}",
+                latin1(slot),
+                self.offset
+            ),
+            InterpretErrorKind::UnknownSlot(slot) => write!(f, "no slot {:?}", latin1(slot)),
+        }
+    }
+}
+
+impl std::error::Error for InterpretError {}
+
+impl From<DecodeError> for InterpretError {
+    fn from(err: DecodeError) -> Self {
+        InterpretError {
+            offset: err.offset,
+            kind: InterpretErrorKind::Decode(err),
+        }
+    }
+}
+
+struct ElementMut<'a> {
+    attributes: &'a mut BTreeMap<String, String>,
+    styles: &'a mut Vec<(String, String)>,
+    listeners: &'a mut Vec<Listener>,
+}
+
+/// Incremental interpreter state. Most callers want [`interpret`].
+#[derive(Clone, Debug, Default)]
+pub struct Interpreter {
+    tree: Tree,
+    stack: Vec<NodeId>,
+    /// Slot whose contents are being filled, see [`Interpreter::fill`].
+    filling: Option<NodeId>,
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stack(&self) -> &[NodeId] {
+        &self.stack
+    }
+
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Most recent element on the node stack, skipping text nodes.
+    pub fn target(&self) -> Option<NodeId> {
+        self.stack
+            .iter()
+            .rev()
+            .copied()
+            .find(|&id| self.tree.nodes[id].is_element())
+    }
+
+    fn create(&mut self, kind: NodeKind, offset: usize) -> NodeId {
+        let id = self.tree.nodes.len();
+
+        self.tree.nodes.push(Node {
+            id,
+            kind,
+            parent: None,
+            children: Vec::new(),
+            offset,
+        });
+
+        if self.stack.is_empty() {
+            match self.filling {
+                Some(slot) => {
+                    self.tree.nodes[id].parent = Some(slot);
+                    self.tree.nodes[slot].children.push(id);
+                }
+                None if self.tree.roots.is_empty() => self.tree.roots.push(id),
+                None => {}
+            }
+        }
+
+        self.stack.push(id);
+        id
+    }
+
+    fn pop2(&mut self) -> Result<(NodeId, NodeId), InterpretErrorKind> {
+        if self.stack.len() < 2 {
+            return Err(InterpretErrorKind::StackUnderflow);
+        }
+
+        let child = self.stack.pop().unwrap();
+        Ok((*self.stack.last().unwrap(), child))
+    }
+
+    fn element_mut(&mut self) -> Result<ElementMut<'_>, InterpretErrorKind> {
+        let id = self.target().ok_or(InterpretErrorKind::NoElement)?;
+
+        match &mut self.tree.nodes[id].kind {
+            NodeKind::Element {
+                attributes,
+                styles,
+                listeners,
+                ..
+            } => Ok(ElementMut {
+                attributes,
+                styles,
+                listeners,
+            }),
+            NodeKind::Text(_) | NodeKind::Slot(_) => Err(InterpretErrorKind::NoElement),
+        }
+    }
+
+    fn detach_last_child(&mut self, parent: NodeId) -> Result<NodeId, InterpretErrorKind> {
+        let old = self.tree.nodes[parent]
+            .children
+            .pop()
+            .ok_or(InterpretErrorKind::NoChild(parent))?;
+
+        self.tree.nodes[old].parent = None;
+        self.tree.removed.push(old);
+        Ok(old)
+    }
+
+    /// Executes a single instruction located at `offset`.
+    pub fn step(&mut self, instruction: &Instruction, offset: usize) -> Result<(), InterpretError> {
+        self.exec(instruction, offset)
+            .map_err(|kind| InterpretError { offset, kind })
+    }
+
+    fn exec(&mut self, instruction: &Instruction, offset: usize) -> Result<(), InterpretErrorKind> {
+        match instruction {
+            Instruction::Nop => {}
+            Instruction::CreateElement(tag) => {
+                self.create(
+                    NodeKind::Element {
+                        tag: latin1(tag),
+                        attributes: BTreeMap::new(),
+                        styles: Vec::new(),
+                        listeners: Vec::new(),
+                    },
+                    offset,
+                );
+            }
+            Instruction::TextNode(text) => {
+                self.create(NodeKind::Text(latin1(text)), offset);
+            }
+            Instruction::Placeholder(slot) => {
+                if self.tree.slots.contains_key(slot) {
+                    return Err(InterpretErrorKind::DuplicateSlot(slot.clone()));
+                }
+
+                let id = self.create(NodeKind::Slot(slot.clone()), offset);
+                self.tree.slots.insert(slot.clone(), id);
+            }
+            Instruction::SetAttribute(name, value) => {
+                let element = self.element_mut()?;
+                let (name, value) = (latin1(name), latin1(value));
+
+                if name == "style" {
+                    *element.styles = parse_style(&value);
+                } else {
+                    element.attributes.insert(name, value);
+                }
+            }
+            Instruction::RemoveAttribute(name) => {
+                let element = self.element_mut()?;
+                let name = latin1(name);
+
+                if name == "style" {
+                    element.styles.clear();
+                } else {
+                    element.attributes.remove(&name);
+                }
+            }
+            Instruction::Style(name, value) => {
+                let element = self.element_mut()?;
+                set_style(element.styles, latin1(name), latin1(value));
+            }
+            Instruction::EventListener(event) => {
+                let id = self.tree.listener_count;
+                let element = self.element_mut()?;
+
+                element.listeners.push(Listener {
+                    event: latin1(event),
+                    id,
+                });
+
+                self.tree.listener_count += 1;
+            }
+            Instruction::AppendChild => {
+                let (parent, child) = self.pop2()?;
+
+                if !self.tree.nodes[parent].is_container() {
+                    self.stack.push(child);
+                    return Err(InterpretErrorKind::NotAnElement(parent));
+                }
+
+                self.tree.nodes[child].parent = Some(parent);
+                self.tree.nodes[parent].children.push(child);
+            }
+            Instruction::AppendSibling => {
+                let (target, sibling) = self.pop2()?;
+
+                if let Some(parent) = self.tree.nodes[target].parent {
+                    let children = &mut self.tree.nodes[parent].children;
+                    let at = children.iter().position(|&c| c == target).unwrap();
+
+                    children.insert(at + 1, sibling);
+                    self.tree.nodes[sibling].parent = Some(parent);
+                } else if let Some(at) = self.tree.roots.iter().position(|&r| r == target) {
+                    self.tree.roots.insert(at + 1, sibling);
+                } else {
+                    self.stack.push(sibling);
+                    return Err(InterpretErrorKind::DetachedSibling(target));
+                }
+            }
+            Instruction::RemoveChild => {
+                let parent = *self
+                    .stack
+                    .last()
+                    .ok_or(InterpretErrorKind::StackUnderflow)?;
+                self.detach_last_child(parent)?;
+            }
+            Instruction::ReplaceChild => {
+                let (parent, child) = self.pop2()?;
+
+                if let Err(err) = self.detach_last_child(parent) {
+                    self.stack.push(child);
+                    return Err(err);
+                }
+
+                self.tree.nodes[child].parent = Some(parent);
+                self.tree.nodes[parent].children.push(child);
+            }
+            Instruction::SetText(text) => {
+                let id = *self
+                    .stack
+                    .last()
+                    .ok_or(InterpretErrorKind::StackUnderflow)?;
+                let text = latin1(text);
+
+                match &mut self.tree.nodes[id].kind {
+                    NodeKind::Text(value) => {
+                        *value = text;
+                        return Ok(());
+                    }
+                    NodeKind::Slot(_) => return Err(InterpretErrorKind::NotAnElement(id)),
+                    NodeKind::Element { .. } => {}
+                }
+
+                while !self.tree.nodes[id].children.is_empty() {
+                    self.detach_last_child(id)?;
+                }
+
+                let text_id = self.tree.nodes.len();
+
+                self.tree.nodes.push(Node {
+                    id: text_id,
+                    kind: NodeKind::Text(text),
+                    parent: Some(id),
+                    children: Vec::new(),
+                    offset,
+                });
+
+                self.tree.nodes[id].children.push(text_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the contents of `slot` with the output of `program`, which
+    /// runs on a fresh node stack: its first node and that node's top-level
+    /// siblings become the children of the slot. Listener IDs continue from
+    /// the main program.
+    pub fn fill(&mut self, slot: &[u8], program: &[u8]) -> Result<(), InterpretError> {
+        let id = *self.tree.slots.get(slot).ok_or_else(|| InterpretError {
+            offset: 0,
+            kind: InterpretErrorKind::UnknownSlot(slot.to_vec()),
+        })?;
+
+        while !self.tree.nodes[id].children.is_empty() {
+            self.detach_last_child(id)
+                .map_err(|kind| InterpretError { offset: 0, kind })?;
+        }
+
+        let stack = std::mem::take(&mut self.stack);
+        let filling = self.filling.replace(id);
+        let result = Instructions::new(program).try_for_each(|item| {
+            let (offset, instruction) = item?;
+            self.step(&instruction, offset)
+        });
+
+        let first = self.tree.nodes[id].children.first().copied();
+        let fill_stack = std::mem::replace(&mut self.stack, stack);
+
+        self.tree
+            .orphans
+            .extend(fill_stack.into_iter().filter(|&n| Some(n) != first));
+        self.filling = filling;
+
+        result
+    }
+
+    /// Ends execution. Nodes above the first node on the stack are orphans.
+    pub fn finish(mut self) -> Tree {
+        let roots = &self.tree.roots;
+        let orphans = self.stack.into_iter().filter(|id| !roots.contains(id));
+
+        self.tree.orphans.extend(orphans);
+        self.tree
+    }
+}
+
+fn set_style(styles: &mut Vec<(String, String)>, name: String, value: String) {
+    match styles.iter_mut().find(|(n, _)| *n == name) {
+        Some(decl) => decl.1 = value,
+        None => styles.push((name, value)),
+    }
+}
+
+/// Parses a `style` attribute value (`a: b; c: d`) into declarations.
+pub fn parse_style(value: &str) -> Vec<(String, String)> {
+    let mut styles = Vec::new();
+
+    for decl in value.split(';') {
+        if let Some((name, value)) = decl.split_once(':') {
+            let name = name.trim();
+
+            if !name.is_empty() {
+                set_style(&mut styles, name.to_string(), value.trim().to_string());
+            }
+        }
+    }
+
+    styles
+}
+
+pub fn run(instructions: &[Instruction]) -> Result<Tree, InterpretError> {
+    let mut interpreter = Interpreter::new();
+    let mut offset = 0;
+
+    for instruction in instructions {
+        interpreter.step(instruction, offset)?;
+        offset += instruction.encoded_len();
+    }
+
+    Ok(interpreter.finish())
+}
+
+pub fn interpret(program: &[u8]) -> Result<Tree, InterpretError> {
+    let mut interpreter = Interpreter::new();
+
+    for item in Instructions::new(program) {
+        let (offset, instruction) = item?;
+        interpreter.step(&instruction, offset)?;
+    }
+
+    Ok(interpreter.finish())
+}
+
+/// Owned, ID-free view of a rendered node, with adjacent text nodes merged
+/// and empty text nodes dropped the way `Node.normalize()` would.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum VNode {
+    Element {
+        tag: String,
+        attributes: BTreeMap<String, String>,
+        styles: Vec<(String, String)>,
+        listeners: Vec<(String, usize)>,
+        children: Vec<VNode>,
+    },
+    Text(String),
+}
+
+impl Tree {
+    /// Rendered output of `id`: a single node, or the contents of a slot.
+    pub fn vnodes(&self, id: NodeId) -> Vec<VNode> {
+        let mut out = Vec::new();
+        self.push_vnodes(id, &mut out);
+        normalize(out.into_iter())
+    }
+
+    fn push_vnodes(&self, id: NodeId, out: &mut Vec<VNode>) {
+        let node = &self.nodes[id];
+
+        match &node.kind {
+            NodeKind::Text(text) => out.push(VNode::Text(text.clone())),
+            NodeKind::Slot(_) => {
+                for &child in &node.children {
+                    self.push_vnodes(child, out);
+                }
+            }
+            NodeKind::Element {
+                tag,
+                attributes,
+                styles,
+                listeners,
+            } => {
+                let mut children = Vec::new();
+
+                for &child in &node.children {
+                    self.push_vnodes(child, &mut children);
+                }
+
+                out.push(VNode::Element {
+                    tag: tag.clone(),
+                    attributes: attributes.clone(),
+                    styles: styles.clone(),
+                    listeners: listeners.iter().map(|l| (l.event.clone(), l.id)).collect(),
+                    children: normalize(children.into_iter()),
+                });
+            }
+        }
+    }
+
+    /// The rendered output: top-level nodes and their subtrees, with slots
+    /// replaced by their contents.
+    pub fn render(&self) -> Vec<VNode> {
+        let mut out = Vec::new();
+
+        for &root in &self.roots {
+            self.push_vnodes(root, &mut out);
+        }
+
+        normalize(out.into_iter())
+    }
+
+    /// Whether both trees render the same output.
+    pub fn equivalent(&self, other: &Tree) -> bool {
+        self.render() == other.render()
+    }
+
+    /// Depth of `id` below its top-level ancestor (roots have depth 0).
+    pub fn depth(&self, mut id: NodeId) -> usize {
+        let mut depth = 0;
+
+        while let Some(parent) = self.nodes[id].parent {
+            id = parent;
+            depth += 1;
+        }
+
+        depth
+    }
+}
+
+fn normalize(nodes: impl Iterator<Item = VNode>) -> Vec<VNode> {
+    let mut out: Vec<VNode> = Vec::new();
+
+    for node in nodes {
+        match (out.last_mut(), node) {
+            (_, VNode::Text(text)) if text.is_empty() => {}
+            (Some(VNode::Text(prev)), VNode::Text(text)) => prev.push_str(&text),
+            (_, node) => out.push(node),
+        }
+    }
+
+    out
+}
@@ -0,0 +1,719 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Reading and writing programs through `std::io`.
+//!
+//! [`Builder`] emits the same bytes as the `librender_*` functions but
+//! streams them into any writer instead of a heap buffer. Writes go through
+//! `write_all`, so partial writes are retried and a writer that stops
+//! accepting bytes surfaces as `ErrorKind::WriteZero`. Wrap unbuffered
+//! writers such as files or sockets in a `BufWriter`. Payload strings are
+//! written one byte per char, as the interpreter reads them, so only chars
+//! up to U+00FF can be written.
+//!
+//! A builder created with [`Builder::with_debug_info`] also records which
+//! component emitted each instruction, see [`crate::debug`].
+//!
+//! [`Builder::memoized`] reuses the bytes of components whose props have
+//! not changed, see [`crate::memo`].
+//!
+//! [`Builder::parallel_children`] encodes sibling subtrees on several
+//! threads, see [`crate::parallel`].
+//!
+//! [`Builder::checkpoint`] starts a transaction that
+//! [`Builder::rollback`] undoes, for example to replace the partial output
+//! of a failed component with a fallback.
+//!
+//! A builder writing into memory can be frozen into a shareable
+//! [`Program`], see [`crate::program`].
+//!
+//! To build into a fixed slice without allocating, see [`crate::fixed`].
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::debug::{split, DebugCheckpoint, DebugInfo, DebugInfoBuilder, SourceLocation};
+use crate::decode::{decode, to_latin1, DecodeError, Instruction, MAX_PAYLOAD_LENGTH};
+use crate::memo::MemoCache;
+use crate::parallel::encode_subtrees;
+use crate::program::Program;
+
+pub struct Builder<W: Write> {
+    writer: W,
+    written: usize,
+    scratch: Vec<u8>,
+    debug: Option<DebugInfoBuilder>,
+    /// Bytes held back from the writer while checkpoints are open.
+    staged: Vec<u8>,
+    checkpoints: Vec<Saved>,
+    next_checkpoint: u64,
+}
+
+/// An open transaction of a [`Builder`], see [`Builder::checkpoint`].
+#[must_use = "a checkpoint holds output back until it is committed"]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    id: u64,
+}
+
+#[derive(Debug)]
+struct Saved {
+    id: u64,
+    written: usize,
+    staged: usize,
+    debug: Option<DebugCheckpoint>,
+}
+
+impl<W: Write> Builder<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            written: 0,
+            scratch: Vec::new(),
+            debug: None,
+            staged: Vec::new(),
+            checkpoints: Vec::new(),
+            next_checkpoint: 0,
+        }
+    }
+
+    pub fn with_debug_info(writer: W) -> Self {
+        Self {
+            debug: Some(DebugInfoBuilder::new()),
+            ..Self::new(writer)
+        }
+    }
+
+    /// Starts a component at the current offset. Does nothing unless the
+    /// builder records debug info.
+    pub fn begin_component(&mut self, name: &str, location: Option<SourceLocation>) {
+        if let Some(debug) = &mut self.debug {
+            debug.begin(self.written, name, location);
+        }
+    }
+
+    /// Ends the innermost component started with
+    /// [`Builder::begin_component`].
+    pub fn end_component(&mut self) {
+        if let Some(debug) = &mut self.debug {
+            debug.end(self.written);
+        }
+    }
+
+    /// Flushes and returns the writer together with the debug info, if it
+    /// was recorded. Components still open end at the end of the program
+    /// and checkpoints still open are committed.
+    pub fn finish(mut self) -> io::Result<(W, Option<DebugInfo>)> {
+        self.release()?;
+        self.writer.flush()?;
+
+        let written = self.written;
+        Ok((self.writer, self.debug.map(|debug| debug.finish(written))))
+    }
+
+    /// Like [`Builder::finish`], but appends the debug info to the program
+    /// as a trailer.
+    pub fn finish_with_trailer(self) -> io::Result<W> {
+        let (mut writer, debug) = self.finish()?;
+
+        if let Some(debug) = debug {
+            let mut trailer = Vec::new();
+            debug.append_to(&mut trailer);
+            writer.write_all(&trailer)?;
+            writer.flush()?;
+        }
+
+        Ok(writer)
+    }
+
+    /// Number of bytes emitted so far, including bytes held back by open
+    /// checkpoints.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Flushes and returns the writer, dropping any debug info. Checkpoints
+    /// still open are committed.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.release()?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Flushes the writer. Bytes held back by open checkpoints are not
+    /// written.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Starts a transaction at the current offset. Until it is committed,
+    /// instructions are held back from the writer so that
+    /// [`Builder::rollback`] can discard them. Checkpoints nest, and only the
+    /// innermost open one can be rolled back or committed.
+    pub fn checkpoint(&mut self) -> Checkpoint {
+        let id = self.next_checkpoint;
+
+        self.next_checkpoint += 1;
+        self.checkpoints.push(Saved {
+            id,
+            written: self.written,
+            staged: self.staged.len(),
+            debug: self.debug.as_ref().map(DebugInfoBuilder::checkpoint),
+        });
+
+        Checkpoint { id }
+    }
+
+    /// Removes `checkpoint` from the stack, returning it. Fails unless it is
+    /// the innermost open checkpoint.
+    fn close(&mut self, checkpoint: Checkpoint) -> io::Result<Saved> {
+        match self.checkpoints.last() {
+            Some(saved) if saved.id == checkpoint.id => Ok(self.checkpoints.pop().unwrap()),
+            Some(_)
+                if self
+                    .checkpoints
+                    .iter()
+                    .any(|saved| saved.id == checkpoint.id) =>
+            {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "checkpoint is not the innermost one",
+                ))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "checkpoint is no longer open",
+            )),
+        }
+    }
+
+    /// Discards everything emitted since `checkpoint`, which must be the
+    /// innermost open one, and restores the components that were open at
+    /// that point.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) -> io::Result<()> {
+        let saved = self.close(checkpoint)?;
+
+        self.written = saved.written;
+        self.staged.truncate(saved.staged);
+
+        if let (Some(debug), Some(state)) = (&mut self.debug, saved.debug) {
+            debug.restore(state);
+        }
+
+        Ok(())
+    }
+
+    /// Keeps everything emitted since `checkpoint`, which must be the
+    /// innermost open one. Committing the outermost checkpoint writes the
+    /// held back bytes.
+    pub fn commit(&mut self, checkpoint: Checkpoint) -> io::Result<()> {
+        self.close(checkpoint)?;
+
+        if self.checkpoints.is_empty() {
+            self.release()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Commits every open checkpoint.
+    fn release(&mut self) -> io::Result<()> {
+        self.checkpoints.clear();
+        self.writer.write_all(&self.staged)?;
+        self.staged.clear();
+
+        Ok(())
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.checkpoints.is_empty() {
+            self.writer.write_all(bytes)?;
+        } else {
+            self.staged.extend_from_slice(bytes);
+        }
+
+        self.written += bytes.len();
+        Ok(())
+    }
+
+    /// Writes a single instruction. Payloads longer than 255 bytes are
+    /// rejected with `ErrorKind::InvalidInput` before anything is written.
+    pub fn instruction(&mut self, instruction: &Instruction) -> io::Result<()> {
+        if let Some(payload) = instruction
+            .payloads()
+            .into_iter()
+            .find(|p| p.len() > MAX_PAYLOAD_LENGTH)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} payload of {} bytes exceeds {} bytes",
+                    instruction.mnemonic(),
+                    payload.len(),
+                    MAX_PAYLOAD_LENGTH
+                ),
+            ));
+        }
+
+        let mut scratch = std::mem::take(&mut self.scratch);
+
+        scratch.clear();
+        instruction.encode_into(&mut scratch);
+
+        let result = self.emit(&scratch);
+        self.scratch = scratch;
+        result
+    }
+
+    /// Writes an already encoded program after checking that it decodes.
+    pub fn program(&mut self, program: &[u8]) -> io::Result<()> {
+        decode(program).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        self.emit(program)
+    }
+
+    /// Writes component `component` with props hash `props` from `cache`,
+    /// or runs `build` on a fresh builder and caches what it writes. The
+    /// component is recorded as a debug component either way.
+    pub fn memoized<F>(
+        &mut self,
+        cache: &mut MemoCache,
+        component: &str,
+        props: u64,
+        build: F,
+    ) -> io::Result<()>
+    where
+        F: FnOnce(&mut Builder<Vec<u8>>) -> io::Result<()>,
+    {
+        self.begin_component(component, None);
+
+        // The component ends even if it fails, so that the components
+        // written after it are not recorded inside it.
+        let result = match cache.get(component, props) {
+            Some((bytes, debug)) => self.splice(bytes, debug),
+            None => self.memoize(cache, component, props, build),
+        };
+
+        self.end_component();
+        result
+    }
+
+    /// Runs `build` on a fresh builder, then writes and caches its output.
+    fn memoize<F>(
+        &mut self,
+        cache: &mut MemoCache,
+        component: &str,
+        props: u64,
+        build: F,
+    ) -> io::Result<()>
+    where
+        F: FnOnce(&mut Builder<Vec<u8>>) -> io::Result<()>,
+    {
+        let mut builder = match self.debug {
+            Some(_) => Builder::with_debug_info(Vec::new()),
+            None => Builder::new(Vec::new()),
+        };
+
+        build(&mut builder)?;
+
+        let (bytes, debug) = builder.finish()?;
+
+        self.splice(&bytes, debug.as_ref())?;
+        cache.insert(component, props, bytes, debug);
+        Ok(())
+    }
+
+    /// Appends a subtree for each of `items` to the element on top of the
+    /// stack, encoding them with `build` on up to `threads` threads (`0`
+    /// for one per core). Writes the same bytes as calling `build` and
+    /// [`Builder::append_child`] for each item in turn, or nothing if an
+    /// item fails or does not write exactly one subtree.
+    pub fn parallel_children<T, F>(
+        &mut self,
+        items: &[T],
+        threads: usize,
+        build: F,
+    ) -> io::Result<()>
+    where
+        T: Sync,
+        F: Fn(&mut Builder<Vec<u8>>, &T) -> io::Result<()> + Sync,
+    {
+        let subtrees = encode_subtrees(items, threads, self.debug.is_some(), build)?;
+
+        for subtree in subtrees {
+            self.splice(&subtree.bytes, subtree.debug.as_ref())?;
+            self.append_child()?;
+        }
+
+        Ok(())
+    }
+
+    fn splice(&mut self, bytes: &[u8], debug: Option<&DebugInfo>) -> io::Result<()> {
+        if let (Some(builder), Some(debug)) = (&mut self.debug, debug) {
+            builder.extend(self.written, debug);
+        }
+
+        self.emit(bytes)
+    }
+
+    pub fn create_element(&mut self, tag: &str) -> io::Result<()> {
+        self.instruction(&Instruction::CreateElement(payload(tag)?))
+    }
+
+    pub fn set_attribute(&mut self, name: &str, value: &str) -> io::Result<()> {
+        self.instruction(&Instruction::SetAttribute(payload(name)?, payload(value)?))
+    }
+
+    pub fn append_child(&mut self) -> io::Result<()> {
+        self.instruction(&Instruction::AppendChild)
+    }
+
+    pub fn append_sibling(&mut self) -> io::Result<()> {
+        self.instruction(&Instruction::AppendSibling)
+    }
+
+    pub fn remove_child(&mut self) -> io::Result<()> {
+        self.instruction(&Instruction::RemoveChild)
+    }
+
+    pub fn replace_child(&mut self) -> io::Result<()> {
+        self.instruction(&Instruction::ReplaceChild)
+    }
+
+    pub fn text_node(&mut self, text: &str) -> io::Result<()> {
+        self.instruction(&Instruction::TextNode(payload(text)?))
+    }
+
+    pub fn set_text(&mut self, text: &str) -> io::Result<()> {
+        self.instruction(&Instruction::SetText(payload(text)?))
+    }
+
+    pub fn remove_attribute(&mut self, name: &str) -> io::Result<()> {
+        self.instruction(&Instruction::RemoveAttribute(payload(name)?))
+    }
+
+    pub fn set_style(&mut self, name: &str, value: &str) -> io::Result<()> {
+        self.instruction(&Instruction::Style(payload(name)?, payload(value)?))
+    }
+
+    pub fn add_event_listener(&mut self, event: &str) -> io::Result<()> {
+        self.instruction(&Instruction::EventListener(payload(event)?))
+    }
+
+    pub fn placeholder(&mut self, slot: &str) -> io::Result<()> {
+        self.instruction(&Instruction::Placeholder(payload(slot)?))
+    }
+
+    pub fn nop(&mut self) -> io::Result<()> {
+        self.instruction(&Instruction::Nop)
+    }
+}
+
+/// Encodes a payload one byte per char, the way the interpreter decodes
+/// it. Chars outside the 8-bit range are rejected with
+/// `ErrorKind::InvalidInput`.
+fn payload(s: &str) -> io::Result<Vec<u8>> {
+    match s.chars().find(|&c| c as u32 > 0xff) {
+        Some(c) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is not an 8-bit character", c),
+        )),
+        None => Ok(to_latin1(s)),
+    }
+}
+
+impl<W: Write + Into<Program>> Builder<W> {
+    /// Turns the written bytes into an immutable program, dropping any
+    /// debug info. Checkpoints still open are committed.
+    pub fn freeze(mut self) -> Program {
+        self.release().expect("in-memory writers do not fail");
+        self.writer.into()
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    /// The bytes read are not a well-formed program.
+    Decode(DecodeError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "{}", err),
+            LoadError::Decode(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(err) => Some(err),
+            LoadError::Decode(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
+
+impl From<DecodeError> for LoadError {
+    fn from(err: DecodeError) -> Self {
+        LoadError::Decode(err)
+    }
+}
+
+/// Reads `reader` to the end and checks that the bytes decode.
+pub fn read_program<R: Read>(mut reader: R) -> Result<Vec<u8>, LoadError> {
+    let mut program = Vec::new();
+
+    reader.read_to_end(&mut program)?;
+    decode(&program)?;
+
+    Ok(program)
+}
+
+/// Like [`read_program`], splitting off a debug info trailer if there is
+/// one.
+pub fn read_program_with_debug<R: Read>(
+    mut reader: R,
+) -> Result<(Vec<u8>, Option<DebugInfo>), LoadError> {
+    let mut data = Vec::new();
+
+    reader.read_to_end(&mut data)?;
+
+    let (program, debug) = split(&data);
+    let program = program.to_vec();
+
+    decode(&program)?;
+
+    Ok((program, debug))
+}
+
+pub fn write_program<W: Write>(mut writer: W, program: &[u8]) -> io::Result<()> {
+    writer.write_all(program)?;
+    writer.flush()
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, LoadError> {
+    read_program(File::open(path)?)
+}
+
+pub fn save<P: AsRef<Path>>(path: P, program: &[u8]) -> io::Result<()> {
+    write_program(File::create(path)?, program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::html::render_program;
+    use crate::{
+        buffer_bytes, librender_append_child, librender_create_buffer, librender_create_element,
+        librender_free_buffer, librender_set_attribute, librender_text_node,
+    };
+
+    #[test]
+    fn matches_the_c_emitters() {
+        let mut builder = Builder::new(Vec::new());
+
+        builder.create_element("div").unwrap();
+        builder.set_attribute("class", "row").unwrap();
+        builder.text_node("hello").unwrap();
+        builder.append_child().unwrap();
+
+        let (program, _) = builder.finish().unwrap();
+
+        unsafe {
+            let buf = librender_create_buffer(0);
+
+            librender_create_element(buf, c"div".as_ptr(), 3);
+            librender_set_attribute(buf, c"class".as_ptr(), 5, c"row".as_ptr(), 3);
+            librender_text_node(buf, c"hello".as_ptr(), 5);
+            librender_append_child(buf);
+
+            assert_eq!(buffer_bytes(buf), program);
+            librender_free_buffer(buf);
+        }
+    }
+
+    #[test]
+    fn writes_latin1_payloads() {
+        let mut builder = Builder::new(Vec::new());
+
+        builder.create_element("p").unwrap();
+        builder.set_attribute("title", "Crème brûlée").unwrap();
+        builder.text_node("café").unwrap();
+        builder.append_child().unwrap();
+
+        let (program, _) = builder.finish().unwrap();
+
+        assert_eq!(&program[program.len() - 5..program.len() - 1], b"caf\xe9");
+        assert_eq!(
+            render_program(&program).unwrap(),
+            "<p title=\"Crème brûlée\">café</p>"
+        );
+    }
+
+    #[test]
+    fn rejects_payloads_it_cannot_encode() {
+        let mut builder = Builder::new(Vec::new());
+        let errors = [
+            builder.text_node("→").unwrap_err(),
+            builder.text_node(&"é".repeat(256)).unwrap_err(),
+        ];
+
+        for err in errors {
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        // 255 chars that are two bytes each in UTF-8 still fit.
+        builder.text_node(&"é".repeat(255)).unwrap();
+        assert_eq!(builder.written(), 257);
+    }
+
+    #[test]
+    fn reports_writers_that_stop_accepting_bytes() {
+        let mut buf = [0u8; 8];
+        let mut builder = Builder::new(&mut buf[..]);
+
+        builder.create_element("div").unwrap();
+
+        let err = builder.text_node("hello").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn reads_back_programs_with_debug_trailers() {
+        let mut builder = Builder::with_debug_info(Vec::new());
+
+        builder.begin_component("Card", None);
+        builder.create_element("div").unwrap();
+        builder.end_component();
+
+        let data = builder.finish_with_trailer().unwrap();
+        let (program, debug) = read_program_with_debug(&data[..]).unwrap();
+
+        assert_eq!(program, b"\x01\x03div");
+        assert_eq!(debug.unwrap().entries().len(), 1);
+        assert!(matches!(
+            read_program(&b"\x01\x09div"[..]),
+            Err(LoadError::Decode(_))
+        ));
+    }
+
+    /// Writes a `Card` component whose title comes from `props`.
+    fn card(builder: &mut Builder<Vec<u8>>, cache: &mut MemoCache, title: &str) {
+        builder
+            .memoized(cache, "Card", crate::memo::stable_hash(title), |b| {
+                b.create_element("div")?;
+                b.text_node(title)?;
+                b.append_child()
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn memoized_reuses_cached_components() {
+        let mut cache = MemoCache::new();
+        let mut cold = Builder::new(Vec::new());
+        let mut warm = Builder::new(Vec::new());
+
+        card(&mut cold, &mut cache, "a");
+        card(&mut cold, &mut cache, "b");
+        assert_eq!((cache.hits(), cache.misses(), cache.len()), (0, 2, 2));
+
+        card(&mut warm, &mut cache, "a");
+        card(&mut warm, &mut cache, "b");
+        assert_eq!((cache.hits(), cache.misses(), cache.len()), (2, 2, 2));
+
+        let (cold, _) = cold.finish().unwrap();
+        let (warm, _) = warm.finish().unwrap();
+        assert_eq!(cold, warm);
+
+        // A hit must not run the emitters again.
+        Builder::new(Vec::new())
+            .memoized(&mut cache, "Card", crate::memo::stable_hash("a"), |_| {
+                panic!("cached component was rebuilt")
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn sweep_drops_components_not_used_since_the_last_sweep() {
+        let mut cache = MemoCache::new();
+        let mut builder = Builder::new(Vec::new());
+
+        card(&mut builder, &mut cache, "a");
+        card(&mut builder, &mut cache, "b");
+        cache.sweep();
+        assert_eq!(cache.len(), 2);
+
+        card(&mut builder, &mut cache, "a");
+        cache.sweep();
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get("Card", crate::memo::stable_hash("a")).is_some());
+        assert!(cache.get("Card", crate::memo::stable_hash("b")).is_none());
+
+        cache.sweep();
+        cache.sweep();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn memoized_ends_components_that_fail() {
+        let mut cache = MemoCache::new();
+        let mut builder = Builder::with_debug_info(Vec::new());
+
+        let err = builder
+            .memoized(&mut cache, "Broken", 0, |b| b.create_element("\u{2713}"))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(cache.is_empty());
+
+        builder.begin_component("Card", None);
+        builder.create_element("div").unwrap();
+        builder.end_component();
+
+        let (_, debug) = builder.finish().unwrap();
+        let debug = debug.unwrap();
+        let names: Vec<_> = debug.lookup(0).iter().map(|e| e.name.as_str()).collect();
+
+        assert_eq!(names, ["Card"]);
+    }
+
+    #[test]
+    fn closes_checkpoints_innermost_first() {
+        let mut builder = Builder::new(Vec::new());
+
+        builder.create_element("a").unwrap();
+        let outer = builder.checkpoint();
+        builder.create_element("b").unwrap();
+        let inner = builder.checkpoint();
+        builder.create_element("c").unwrap();
+        assert_eq!(builder.get_ref(), b"\x01\x01a");
+
+        let err = builder.rollback(Checkpoint { id: outer.id }).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(builder.written(), 9);
+
+        builder.rollback(inner).unwrap();
+        assert_eq!(builder.written(), 6);
+        assert!(builder.commit(Checkpoint { id: 1 }).is_err());
+
+        builder.commit(outer).unwrap();
+        assert_eq!(builder.get_ref(), b"\x01\x01a\x01\x01b");
+    }
+}
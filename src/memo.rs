@@ -0,0 +1,410 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Structural subtree hashes and memoized component encoding.
+//!
+//! A structural hash covers what a subtree renders: tags, attributes,
+//! styles, listener events, text and slots, but not node or listener IDs,
+//! which depend on where the subtree is in the program. Two subtrees with
+//! equal hashes encode to interchangeable instructions. Hashes use 64-bit
+//! FNV-1a with integers written little-endian, so they are stable across
+//! runs and platforms and can be stored.
+//!
+//! [`MemoCache`] keeps the encoded bytes of components by name and props
+//! hash. [`crate::io::Builder::memoized`] splices them back in instead of
+//! running the component's emitters again when its props are unchanged.
+
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use crate::debug::DebugInfo;
+use crate::interp::{interpret, InterpretError, NodeId, NodeKind, Tree};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// FNV-1a hasher whose output does not depend on the platform.
+#[derive(Clone, Copy, Debug)]
+pub struct StableHasher {
+    state: u64,
+}
+
+impl StableHasher {
+    pub fn new() -> Self {
+        Self {
+            state: FNV_OFFSET_BASIS,
+        }
+    }
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.state
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.state ^= b as u64;
+            self.state = self.state.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn write_u16(&mut self, n: u16) {
+        self.write(&n.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.write(&n.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.write(&n.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, n: u128) {
+        self.write(&n.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+}
+
+/// Hashes `value` with a [`StableHasher`], for example to key component
+/// props in a [`MemoCache`].
+pub fn stable_hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = StableHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Structural hashes of every rendered node of a tree.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeHashes {
+    /// Indexed by node ID; `None` for nodes that are not rendered.
+    hashes: Vec<Option<u64>>,
+    roots: Vec<NodeId>,
+}
+
+impl TreeHashes {
+    pub fn new(tree: &Tree) -> Self {
+        let mut hashes = vec![None; tree.nodes.len()];
+
+        for &root in &tree.roots {
+            hash_node(tree, root, &mut hashes);
+        }
+
+        Self {
+            hashes,
+            roots: tree.roots.clone(),
+        }
+    }
+
+    /// Hash of the subtree rooted at `node`.
+    pub fn get(&self, node: NodeId) -> Option<u64> {
+        self.hashes.get(node).copied().flatten()
+    }
+
+    /// Hash of the whole rendered output.
+    pub fn tree_hash(&self) -> u64 {
+        let mut hasher = StableHasher::new();
+
+        for &root in &self.roots {
+            hasher.write_u64(self.hashes[root].unwrap_or(0));
+        }
+
+        hasher.finish()
+    }
+
+    /// Rendered nodes whose subtree has hash `hash`, in creation order.
+    pub fn find(&self, hash: u64) -> impl Iterator<Item = NodeId> + '_ {
+        self.hashes
+            .iter()
+            .enumerate()
+            .filter(move |(_, h)| **h == Some(hash))
+            .map(|(id, _)| id)
+    }
+}
+
+pub fn hash_program(program: &[u8]) -> Result<TreeHashes, InterpretError> {
+    Ok(TreeHashes::new(&interpret(program)?))
+}
+
+fn hash_node(tree: &Tree, id: NodeId, hashes: &mut [Option<u64>]) -> u64 {
+    let node = &tree.nodes[id];
+    let mut hasher = StableHasher::new();
+
+    match &node.kind {
+        NodeKind::Text(text) => {
+            hasher.write_u8(0);
+            text.hash(&mut hasher);
+        }
+        NodeKind::Slot(slot) => {
+            hasher.write_u8(1);
+            slot.hash(&mut hasher);
+        }
+        NodeKind::Element {
+            tag,
+            attributes,
+            styles,
+            listeners,
+        } => {
+            hasher.write_u8(2);
+            tag.hash(&mut hasher);
+            attributes.hash(&mut hasher);
+            styles.hash(&mut hasher);
+            hasher.write_usize(listeners.len());
+
+            for listener in listeners {
+                listener.event.hash(&mut hasher);
+            }
+        }
+    }
+
+    // Adjacent text children render as one text node, so they are hashed
+    // as one.
+    let mut text: Option<String> = None;
+    let mut count = 0;
+
+    for &child in &node.children {
+        let child_hash = hash_node(tree, child, hashes);
+
+        match &tree.nodes[child].kind {
+            NodeKind::Text(value) => text.get_or_insert_with(String::new).push_str(value),
+            _ => {
+                count += hash_text(&mut hasher, text.take());
+                hasher.write_u64(child_hash);
+                count += 1;
+            }
+        }
+    }
+
+    count += hash_text(&mut hasher, text);
+    hasher.write_usize(count);
+
+    let hash = hasher.finish();
+    hashes[id] = Some(hash);
+    hash
+}
+
+fn hash_text(hasher: &mut StableHasher, text: Option<String>) -> usize {
+    match text.filter(|t| !t.is_empty()) {
+        Some(text) => {
+            let mut child = StableHasher::new();
+            child.write_u8(0);
+            text.hash(&mut child);
+            child.write_usize(0);
+            hasher.write_u64(child.finish());
+            1
+        }
+        None => 0,
+    }
+}
+
+#[derive(Clone, Debug)]
+struct MemoEntry {
+    bytes: Vec<u8>,
+    debug: Option<DebugInfo>,
+    used: bool,
+}
+
+/// Encoded components keyed by component name and props hash.
+#[derive(Clone, Debug, Default)]
+pub struct MemoCache {
+    entries: HashMap<(String, u64), MemoEntry>,
+    hits: usize,
+    misses: usize,
+}
+
+impl MemoCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encoded bytes of `component` with props hash `props`, and the debug
+    /// info recorded for them, with offsets relative to the first byte.
+    pub fn get(&mut self, component: &str, props: u64) -> Option<(&[u8], Option<&DebugInfo>)> {
+        match self.entries.get_mut(&(component.to_string(), props)) {
+            Some(entry) => {
+                entry.used = true;
+                self.hits += 1;
+                Some((&entry.bytes, entry.debug.as_ref()))
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        component: &str,
+        props: u64,
+        bytes: Vec<u8>,
+        debug: Option<DebugInfo>,
+    ) {
+        self.entries.insert(
+            (component.to_string(), props),
+            MemoEntry {
+                bytes,
+                debug,
+                used: true,
+            },
+        );
+    }
+
+    /// Drops the entries of `component`, whatever their props.
+    pub fn invalidate(&mut self, component: &str) {
+        self.entries.retain(|(name, _), _| name != component);
+    }
+
+    /// Drops the entries not used since the last sweep. Call it once per
+    /// render so that props no longer in use do not accumulate.
+    pub fn sweep(&mut self) {
+        self.entries
+            .retain(|_, entry| std::mem::take(&mut entry.used));
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Lookups that found an entry.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    /// Bytes of encoded components held by the cache.
+    pub fn size(&self) -> usize {
+        self.entries.values().map(|e| e.bytes.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    fn hashes(source: &str) -> TreeHashes {
+        hash_program(&assemble(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn hashes_are_stable() {
+        let mut hasher = StableHasher::new();
+
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+        assert_eq!(stable_hash(&1usize), stable_hash(&1u64));
+        assert_eq!(
+            hashes("create_element \"p\"\ntext_node \"hi\"\nappend_child").tree_hash(),
+            0x6f8f90979e07c264
+        );
+    }
+
+    #[test]
+    fn equal_subtrees_hash_equal() {
+        let tree = hashes(
+            r#"
+            create_element "ul"
+            create_element "li"
+            text_node "a"
+            append_child
+            append_child
+            create_element "li"
+            text_node "a"
+            append_child
+            append_child
+            create_element "li"
+            text_node "b"
+            append_child
+            append_child
+            "#,
+        );
+
+        assert_eq!(tree.get(1), tree.get(3));
+        assert_ne!(tree.get(1), tree.get(5));
+        assert_ne!(tree.get(1), tree.get(0));
+    }
+
+    #[test]
+    fn ignores_node_and_listener_ids() {
+        let alone = hashes("create_element \"a\"\nevent_listener \"click\"");
+        let nested = hashes(
+            r#"
+            create_element "div"
+            event_listener "focus"
+            text_node "x"
+            append_child
+            create_element "a"
+            event_listener "click"
+            append_child
+            "#,
+        );
+
+        assert_eq!(nested.get(2), alone.get(0));
+        assert_ne!(
+            nested.get(2),
+            hashes("create_element \"a\"\nevent_listener \"focus\"").get(0)
+        );
+    }
+
+    #[test]
+    fn merges_adjacent_text() {
+        let split = hashes(
+            r#"
+            create_element "p"
+            text_node "a"
+            append_child
+            text_node ""
+            append_child
+            text_node "b"
+            append_child
+            "#,
+        );
+        let joined = hashes("create_element \"p\"\ntext_node \"ab\"\nappend_child");
+
+        assert_eq!(split.tree_hash(), joined.tree_hash());
+        assert_ne!(
+            split.tree_hash(),
+            hashes("create_element \"p\"\ntext_node \"a\"\nappend_child").tree_hash()
+        );
+    }
+
+    #[test]
+    fn finds_nodes_by_hash() {
+        let tree = hashes(
+            r#"
+            create_element "div"
+            create_element "br"
+            append_child
+            create_element "br"
+            append_child
+            "#,
+        );
+        let br = tree.get(1).unwrap();
+
+        assert_eq!(tree.find(br).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(tree.find(tree.get(0).unwrap()).collect::<Vec<_>>(), [0]);
+        assert_eq!(tree.find(br ^ 1).count(), 0);
+        assert_eq!(tree.get(3), None);
+    }
+}
@@ -0,0 +1,367 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Mounting component programs under nodes of a parent program.
+//!
+//! `librender_merge_bytecode` concatenates programs, which leaves each one a
+//! detached root. [`merge_tree`] instead splices each child program in at
+//! the last point where its target node is on top of the node stack,
+//! followed by `append_child`, so it becomes the last child of that node.
+//!
+//! Node IDs are creation ordinals and event IDs listener ordinals, so both
+//! shift when instructions are spliced in before the ones that create them.
+//! The [`Relocation`]s returned with the program map the IDs of each input
+//! to the IDs of the merged program, for example to move event callbacks
+//! registered by ID.
+
+use std::fmt;
+
+use crate::decode::{Instruction, Instructions};
+use crate::interp::{interpret, InterpretError, Interpreter, NodeId, Tree};
+use crate::parallel::{check_subtree, SubtreeError};
+use crate::{buffer_bytes, buffer_from_bytes, librender_bytecode_buffer, size_t};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MergeError {
+    /// The parent program does not run.
+    Parent(InterpretError),
+    /// Child `index` does not run on its own.
+    Child(usize, InterpretError),
+    /// Child `index` is not a single subtree.
+    NotASubtree(usize, SubtreeError),
+    UnknownNode(NodeId),
+    /// The target node cannot have children.
+    NotAContainer(NodeId),
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeError::Parent(err) => write!(f, "parent: {}", err),
+            MergeError::Child(index, err) => write!(f, "child {}: {}", index, err),
+            MergeError::NotASubtree(index, err) => write!(f, "child {} {}", index, err),
+            MergeError::UnknownNode(id) => write!(f, "parent has no node {}", id),
+            MergeError::NotAContainer(id) => write!(f, "node {} cannot have children", id),
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+/// New node and event IDs of the nodes and listeners of one input program,
+/// indexed by their old IDs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Relocation {
+    pub nodes: Vec<NodeId>,
+    pub events: Vec<usize>,
+}
+
+impl Relocation {
+    pub fn node(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes.get(id).copied()
+    }
+
+    pub fn event(&self, id: usize) -> Option<usize> {
+        self.events.get(id).copied()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Merged {
+    pub program: Vec<u8>,
+    pub parent: Relocation,
+    /// One per mounted child, in the order given.
+    pub children: Vec<Relocation>,
+}
+
+/// Offsets of the instructions that create nodes and add listeners.
+struct Ordinals {
+    nodes: Vec<usize>,
+    events: Vec<usize>,
+}
+
+impl Ordinals {
+    fn new(program: &[u8]) -> Self {
+        let mut ordinals = Ordinals {
+            nodes: Vec::new(),
+            events: Vec::new(),
+        };
+
+        for (offset, instruction) in Instructions::new(program).map_while(Result::ok) {
+            if instruction.creates_node() {
+                ordinals.nodes.push(offset);
+            } else if let Instruction::EventListener(_) = instruction {
+                ordinals.events.push(offset);
+            }
+        }
+
+        ordinals
+    }
+}
+
+/// Runs `parent` and returns its tree together with the offset at which
+/// each node is last on top of the stack: right before the instruction
+/// that attaches it, or the end of the program.
+fn mount_points(parent: &[u8]) -> Result<(Tree, Vec<usize>), InterpretError> {
+    let mut interpreter = Interpreter::new();
+    let mut points = Vec::new();
+
+    for item in Instructions::new(parent) {
+        let (offset, instruction) = item?;
+
+        if let Some(&top) = interpreter.stack().last() {
+            points[top] = offset;
+        }
+
+        interpreter.step(&instruction, offset)?;
+        points.resize(interpreter.tree().nodes.len(), offset);
+    }
+
+    if let Some(&top) = interpreter.stack().last() {
+        points[top] = parent.len();
+    }
+
+    Ok((interpreter.finish(), points))
+}
+
+/// New IDs of the parent's nodes or listeners created at `offsets`, given
+/// how many each mount, sorted by point, adds before them.
+fn relocate(offsets: &[usize], mounts: &[(usize, usize)]) -> Vec<usize> {
+    let mut shift = 0;
+    let mut next = 0;
+
+    offsets
+        .iter()
+        .enumerate()
+        .map(|(id, &offset)| {
+            while next < mounts.len() && mounts[next].0 <= offset {
+                shift += mounts[next].1;
+                next += 1;
+            }
+
+            id + shift
+        })
+        .collect()
+}
+
+/// Mounts each `(node, program)` of `children` as the last child of `node`
+/// in `parent`. Children mounted on the same node keep their order. Each
+/// child must run on its own and leave a single subtree.
+pub fn merge_tree(parent: &[u8], children: &[(NodeId, &[u8])]) -> Result<Merged, MergeError> {
+    let (tree, points) = mount_points(parent).map_err(MergeError::Parent)?;
+    let mut mounts = Vec::with_capacity(children.len());
+
+    for (index, &(node, program)) in children.iter().enumerate() {
+        match tree.nodes.get(node) {
+            None => return Err(MergeError::UnknownNode(node)),
+            Some(target) if !target.is_container() => return Err(MergeError::NotAContainer(node)),
+            Some(_) => {}
+        }
+
+        interpret(program).map_err(|err| MergeError::Child(index, err))?;
+        check_subtree(program).map_err(|err| MergeError::NotASubtree(index, err))?;
+
+        mounts.push((points[node], index, Ordinals::new(program)));
+    }
+
+    // Stable, so children of the same node stay in the given order.
+    mounts.sort_by_key(|&(point, _, _)| point);
+
+    let ordinals = Ordinals::new(parent);
+    let added_nodes: Vec<_> = mounts.iter().map(|(p, _, o)| (*p, o.nodes.len())).collect();
+    let added_events: Vec<_> = mounts
+        .iter()
+        .map(|(p, _, o)| (*p, o.events.len()))
+        .collect();
+    let inserted: usize = children.iter().map(|(_, program)| program.len() + 1).sum();
+
+    let mut merged = Merged {
+        program: Vec::with_capacity(parent.len() + inserted),
+        parent: Relocation {
+            nodes: relocate(&ordinals.nodes, &added_nodes),
+            events: relocate(&ordinals.events, &added_events),
+        },
+        children: vec![Relocation::default(); children.len()],
+    };
+
+    let mut copied = 0;
+    let (mut nodes, mut events) = (0, 0);
+
+    for (point, index, child) in &mounts {
+        let node = ordinals.nodes.partition_point(|&o| o < *point) + nodes;
+        let event = ordinals.events.partition_point(|&o| o < *point) + events;
+
+        merged.children[*index] = Relocation {
+            nodes: (node..node + child.nodes.len()).collect(),
+            events: (event..event + child.events.len()).collect(),
+        };
+        nodes += child.nodes.len();
+        events += child.events.len();
+
+        merged.program.extend_from_slice(&parent[copied..*point]);
+        merged.program.extend_from_slice(children[*index].1);
+        Instruction::AppendChild.encode_into(&mut merged.program);
+        copied = *point;
+    }
+
+    merged.program.extend_from_slice(&parent[copied..]);
+    Ok(merged)
+}
+
+/// Mounts `count` child programs under the parent nodes in `nodes`, see
+/// [`merge_tree`]. Returns a new buffer the caller frees, or null if the
+/// programs cannot be merged.
+///
+/// # Safety
+///
+/// `parent` must be null or point to a valid buffer. Unless `count` is 0,
+/// `nodes` and `children` must point to `count` elements, each child a
+/// valid buffer.
+#[no_mangle]
+pub unsafe extern "C" fn librender_merge_tree(
+    parent: *const librender_bytecode_buffer,
+    nodes: *const size_t,
+    children: *const *const librender_bytecode_buffer,
+    count: size_t,
+) -> *mut librender_bytecode_buffer {
+    if parent.is_null() || count != 0 && (nodes.is_null() || children.is_null()) {
+        return std::ptr::null_mut();
+    }
+
+    let mounts: Vec<(NodeId, &[u8])> = (0..count as usize)
+        .map(|i| (*nodes.add(i) as NodeId, buffer_bytes(*children.add(i))))
+        .collect();
+
+    match merge_tree(buffer_bytes(parent), &mounts) {
+        Ok(merged) => buffer_from_bytes(&merged.program),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    const PARENT: &str = r#"
+        create_element "ul"
+        create_element "li"
+        event_listener "click"
+        append_child
+        create_element "li"
+        append_child
+    "#;
+
+    fn programs() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        (
+            assemble(PARENT).unwrap(),
+            assemble("create_element \"span\"\ntext_node \"hi\"\nappend_child").unwrap(),
+            assemble("create_element \"b\"\nevent_listener \"focus\"").unwrap(),
+        )
+    }
+
+    #[test]
+    fn mounts_children_as_last_children() {
+        let (parent, span, b) = programs();
+        let merged = merge_tree(&parent, &[(1, &span), (0, &b)]).unwrap();
+        let expected = assemble(
+            r#"
+            create_element "ul"
+            create_element "li"
+            event_listener "click"
+            create_element "span"
+            text_node "hi"
+            append_child
+            append_child
+            append_child
+            create_element "li"
+            append_child
+            create_element "b"
+            event_listener "focus"
+            append_child
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(merged.program, expected);
+        assert_eq!(merged.parent.nodes, [0, 1, 4]);
+        assert_eq!(merged.parent.events, [0]);
+        assert_eq!(merged.children[0].nodes, [2, 3]);
+        assert_eq!(merged.children[1].nodes, [5]);
+        assert_eq!(merged.children[1].event(0), Some(1));
+    }
+
+    #[test]
+    fn keeps_the_order_of_children_of_one_node() {
+        let (parent, span, b) = programs();
+        let merged = merge_tree(&parent, &[(2, &b), (2, &span)]).unwrap();
+        let tree = interpret(&merged.program).unwrap();
+        let li = merged.parent.node(2).unwrap();
+
+        assert_eq!(
+            tree.nodes[li].children,
+            [merged.children[0].nodes[0], merged.children[1].nodes[0]]
+        );
+    }
+
+    #[test]
+    fn rejects_children_that_cannot_be_mounted() {
+        let (parent, span, _) = programs();
+        let two = assemble("create_element \"a\"\ncreate_element \"b\"").unwrap();
+        let escapes = assemble("create_element \"a\"\nappend_child").unwrap();
+        let text = assemble("create_element \"p\"\ntext_node \"t\"").unwrap();
+
+        assert_eq!(
+            merge_tree(&parent, &[(3, &span)]),
+            Err(MergeError::UnknownNode(3))
+        );
+        assert_eq!(
+            merge_tree(&parent, &[(0, &span), (1, &two)]),
+            Err(MergeError::NotASubtree(1, SubtreeError::TopLevelNodes(2)))
+        );
+        assert!(matches!(
+            merge_tree(&parent, &[(0, &escapes)]),
+            Err(MergeError::Child(0, _))
+        ));
+        assert!(matches!(
+            merge_tree(&b"\x03"[..], &[]),
+            Err(MergeError::Parent(_))
+        ));
+
+        assert_eq!(
+            merge_tree(&text, &[(1, &span)]),
+            Err(MergeError::NotAContainer(1))
+        );
+    }
+
+    #[test]
+    fn merges_through_the_c_function() {
+        let (parent, span, b) = programs();
+
+        unsafe {
+            let parent = buffer_from_bytes(&parent);
+            let children = [
+                buffer_from_bytes(&span) as *const _,
+                buffer_from_bytes(&b) as *const _,
+            ];
+            let nodes: [size_t; 2] = [1, 0];
+            let merged = librender_merge_tree(parent, nodes.as_ptr(), children.as_ptr(), 2);
+
+            assert_eq!(
+                buffer_bytes(merged),
+                merge_tree(buffer_bytes(parent), &[(1, &span), (0, &b)])
+                    .unwrap()
+                    .program
+            );
+            assert!(librender_merge_tree(parent, [9].as_ptr(), children.as_ptr(), 1).is_null());
+            assert!(librender_merge_tree(parent, std::ptr::null(), children.as_ptr(), 1).is_null());
+
+            for buf in [parent, merged] {
+                crate::librender_free_buffer(buf);
+            }
+
+            for child in children {
+                crate::librender_free_buffer(child as *mut _);
+            }
+        }
+    }
+}
@@ -0,0 +1,415 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Multiplexing frames from several programs over one transport.
+//!
+//! Each microfrontend writes its own frame stream, and a mux stream
+//! interleaves them with a channel ID in front of every frame:
+//!
+//! ```text
+//! magic "LRM" | version | (channel: u16 LE | frame)*
+//! ```
+//!
+//! Channels never share state. Every channel is demultiplexed into its own
+//! interpreter, so node IDs, listener IDs, slots and sequence numbers all
+//! start over per channel.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::frame::{read_frame, Frame, FrameError, FrameRunner, FrameWriter};
+use crate::interp::Tree;
+
+pub const MAGIC: &[u8; 3] = b"LRM";
+pub const VERSION: u8 = 1;
+
+pub const MUX_HEADER_LENGTH: usize = 4;
+pub const CHANNEL_LENGTH: usize = 2;
+
+pub type ChannelId = u16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MuxError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The input ends inside the record at `offset`.
+    Truncated {
+        offset: usize,
+    },
+    /// A frame on `channel` is malformed or does not run.
+    Frame {
+        channel: ChannelId,
+        error: FrameError,
+    },
+}
+
+impl fmt::Display for MuxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MuxError::BadMagic => write!(f, "not a multiplexed stream"),
+            MuxError::UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            MuxError::Truncated { offset } => {
+                write!(f, "truncated record at offset 0x{:x}", offset)
+            }
+            MuxError::Frame { channel, error } => write!(f, "channel {}: {}", channel, error),
+        }
+    }
+}
+
+impl std::error::Error for MuxError {}
+
+/// Checks the stream header, returning `false` if `data` is too short to
+/// hold it yet.
+fn read_header(data: &[u8]) -> Result<bool, MuxError> {
+    let magic = &data[..data.len().min(MAGIC.len())];
+
+    if !MAGIC.starts_with(magic) {
+        return Err(MuxError::BadMagic);
+    }
+
+    match data.get(MAGIC.len()) {
+        None => Ok(false),
+        Some(&VERSION) => Ok(true),
+        Some(&version) => Err(MuxError::UnsupportedVersion(version)),
+    }
+}
+
+/// Rebases the offset of a [`read_frame`] error onto the mux stream.
+fn rebase(error: FrameError, offset: usize) -> FrameError {
+    match error {
+        FrameError::UnknownKind { kind, .. } => FrameError::UnknownKind { offset, kind },
+        error => error,
+    }
+}
+
+/// Interleaves frames from several channels into one stream. Each channel
+/// keeps its own sequence numbers.
+#[derive(Clone, Debug)]
+pub struct MuxWriter {
+    writers: BTreeMap<ChannelId, FrameWriter>,
+    out: Vec<u8>,
+}
+
+impl Default for MuxWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MuxWriter {
+    pub fn new() -> Self {
+        let mut out = Vec::with_capacity(MUX_HEADER_LENGTH);
+
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+
+        Self {
+            writers: BTreeMap::new(),
+            out,
+        }
+    }
+
+    fn writer(&mut self, channel: ChannelId) -> &mut FrameWriter {
+        self.writers.entry(channel).or_default()
+    }
+
+    /// Appends an already numbered frame to `channel`.
+    pub fn push(&mut self, channel: ChannelId, frame: &Frame) {
+        self.out.extend_from_slice(&channel.to_le_bytes());
+        frame.encode_into(&mut self.out);
+    }
+
+    /// Appends a block of complete instructions to `channel`.
+    pub fn instructions(&mut self, channel: ChannelId, program: &[u8]) {
+        let frame = self.writer(channel).instructions(program);
+        self.push(channel, &frame);
+    }
+
+    /// Appends the contents of `slot` on `channel`, see [`FrameWriter::fill`].
+    pub fn fill(&mut self, channel: ChannelId, slot: &[u8], program: &[u8]) {
+        let frame = self.writer(channel).fill(slot, program);
+        self.push(channel, &frame);
+    }
+
+    /// Bytes written so far, which can be flushed to the transport and
+    /// cleared with [`MuxWriter::take`].
+    pub fn bytes(&self) -> &[u8] {
+        &self.out
+    }
+
+    /// Takes the bytes written so far, leaving channel state intact.
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.out)
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.out
+    }
+}
+
+/// Interleaves whole frame streams round-robin, one frame per channel at a
+/// time.
+pub fn mux(streams: &[(ChannelId, Vec<Frame>)]) -> Vec<u8> {
+    let mut writer = MuxWriter::new();
+    let longest = streams.iter().map(|(_, frames)| frames.len()).max();
+
+    for i in 0..longest.unwrap_or(0) {
+        for (channel, frames) in streams {
+            if let Some(frame) = frames.get(i) {
+                writer.push(*channel, frame);
+            }
+        }
+    }
+
+    writer.finish()
+}
+
+/// Push-based demultiplexer that runs each channel as its frames arrive.
+#[derive(Clone, Debug, Default)]
+pub struct Demux {
+    pending: Vec<u8>,
+    /// Stream offset of `pending[0]`.
+    offset: usize,
+    channels: BTreeMap<ChannelId, FrameRunner>,
+}
+
+impl Demux {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Channels seen so far, with their interpreter state.
+    pub fn channels(&self) -> &BTreeMap<ChannelId, FrameRunner> {
+        &self.channels
+    }
+
+    /// Feeds the next chunk of the stream, running every complete frame in
+    /// it. Returns the channels that received frames. When a frame fails,
+    /// the frames before it stay consumed and the failing record is the next
+    /// one read.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<ChannelId>, MuxError> {
+        self.pending.extend_from_slice(data);
+
+        if self.offset == 0 {
+            if !read_header(&self.pending)? {
+                return Ok(Vec::new());
+            }
+
+            self.pending.drain(..MUX_HEADER_LENGTH);
+            self.offset = MUX_HEADER_LENGTH;
+        }
+
+        let mut updated = Vec::new();
+        let mut pos = 0;
+        let result = self.run_records(&mut pos, &mut updated);
+
+        self.pending.drain(..pos);
+        self.offset += pos;
+
+        result.map(|()| updated)
+    }
+
+    /// Runs the complete records in `pending` from `pos`, advancing `pos`
+    /// past each one that ran.
+    fn run_records(
+        &mut self,
+        pos: &mut usize,
+        updated: &mut Vec<ChannelId>,
+    ) -> Result<(), MuxError> {
+        while self.pending.len() - *pos >= CHANNEL_LENGTH {
+            let channel = ChannelId::from_le_bytes([self.pending[*pos], self.pending[*pos + 1]]);
+            let record = &self.pending[*pos + CHANNEL_LENGTH..];
+            let frame_offset = self.offset + *pos + CHANNEL_LENGTH;
+
+            let read = read_frame(record).map_err(|error| MuxError::Frame {
+                channel,
+                error: rebase(error, frame_offset),
+            })?;
+
+            let Some((frame, length)) = read else {
+                break;
+            };
+
+            self.channels
+                .entry(channel)
+                .or_default()
+                .push(&frame)
+                .map_err(|error| MuxError::Frame { channel, error })?;
+
+            if !updated.contains(&channel) {
+                updated.push(channel);
+            }
+
+            *pos += CHANNEL_LENGTH + length;
+        }
+
+        Ok(())
+    }
+
+    /// Ends the stream, returning the tree built by each channel.
+    pub fn finish(self) -> Result<BTreeMap<ChannelId, Tree>, MuxError> {
+        if !self.pending.is_empty() || self.offset == 0 {
+            return Err(MuxError::Truncated {
+                offset: self.offset,
+            });
+        }
+
+        Ok(self
+            .channels
+            .into_iter()
+            .map(|(channel, runner)| (channel, runner.finish()))
+            .collect())
+    }
+}
+
+/// Demultiplexes a complete stream into one tree per channel.
+pub fn demux(data: &[u8]) -> Result<BTreeMap<ChannelId, Tree>, MuxError> {
+    let mut demux = Demux::new();
+
+    demux.feed(data)?;
+    demux.finish()
+}
+
+/// Splits a complete stream back into the frames of each channel without
+/// running them.
+pub fn demux_frames(data: &[u8]) -> Result<BTreeMap<ChannelId, Vec<Frame>>, MuxError> {
+    if !read_header(data)? {
+        return Err(MuxError::Truncated { offset: 0 });
+    }
+
+    let mut channels: BTreeMap<ChannelId, Vec<Frame>> = BTreeMap::new();
+    let mut offset = MUX_HEADER_LENGTH;
+
+    while offset < data.len() {
+        let record = &data[offset..];
+        let truncated = MuxError::Truncated { offset };
+
+        if record.len() < CHANNEL_LENGTH {
+            return Err(truncated);
+        }
+
+        let channel = ChannelId::from_le_bytes([record[0], record[1]]);
+        let (frame, length) = read_frame(&record[CHANNEL_LENGTH..])
+            .map_err(|error| MuxError::Frame {
+                channel,
+                error: rebase(error, offset + CHANNEL_LENGTH),
+            })?
+            .ok_or(truncated)?;
+
+        channels.entry(channel).or_default().push(frame);
+        offset += CHANNEL_LENGTH + length;
+    }
+
+    Ok(channels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::frame::split;
+    use crate::interp::interpret;
+
+    fn program(tag: &str, items: usize) -> Vec<u8> {
+        let mut source = format!("create_element \"{}\"\n", tag);
+
+        for i in 0..items {
+            source += &format!("text_node \"{} {}\"\nappend_child\n", tag, i);
+        }
+
+        assemble(&source).unwrap()
+    }
+
+    #[test]
+    fn demuxes_interleaved_channels() {
+        let (header, sidebar) = (program("header", 20), program("aside", 30));
+        let stream = mux(&[
+            (1, split(&header, 32).unwrap()),
+            (2, split(&sidebar, 32).unwrap()),
+        ]);
+        let trees = demux(&stream).unwrap();
+
+        assert!(trees[&1].equivalent(&interpret(&header).unwrap()));
+        assert!(trees[&2].equivalent(&interpret(&sidebar).unwrap()));
+        assert_eq!(
+            demux_frames(&stream).unwrap()[&2],
+            split(&sidebar, 32).unwrap()
+        );
+    }
+
+    #[test]
+    fn feeds_byte_by_byte() {
+        let stream = mux(&[
+            (1, split(&program("header", 20), 32).unwrap()),
+            (2, split(&program("aside", 30), 32).unwrap()),
+        ]);
+        let mut demux = Demux::new();
+
+        for byte in &stream {
+            demux.feed(std::slice::from_ref(byte)).unwrap();
+        }
+
+        let trees = demux.finish().unwrap();
+
+        assert!(trees[&1].equivalent(&interpret(&program("header", 20)).unwrap()));
+        assert!(trees[&2].equivalent(&interpret(&program("aside", 30)).unwrap()));
+    }
+
+    #[test]
+    fn failed_frame_does_not_replay_earlier_frames() {
+        let mut writer = MuxWriter::new();
+
+        writer.instructions(1, &program("header", 2));
+        writer.instructions(2, &program("aside", 2));
+        writer.instructions(1, &assemble("append_child").unwrap());
+
+        let stream = writer.finish();
+        let mut demux = Demux::new();
+        let err = demux.feed(&stream).unwrap_err();
+
+        assert!(matches!(
+            err,
+            MuxError::Frame {
+                channel: 1,
+                error: FrameError::Interpret { sequence: 1, .. }
+            }
+        ));
+        assert_eq!(demux.channels().len(), 2);
+
+        // The failing record is read again, not the frames before it.
+        assert_eq!(demux.feed(&[]).unwrap_err(), err);
+    }
+
+    #[test]
+    fn frame_failing_midway_applies_nothing() {
+        let mut writer = MuxWriter::new();
+
+        writer.instructions(1, &program("header", 2));
+        writer.instructions(
+            1,
+            &assemble("create_element \"p\"\nappend_child\nappend_child").unwrap(),
+        );
+
+        let mut demux = Demux::new();
+
+        demux.feed(&writer.take()).unwrap_err();
+
+        let nodes = |demux: &Demux| demux.channels()[&1].interpreter().tree().nodes.len();
+        let stack = |demux: &Demux| demux.channels()[&1].interpreter().stack().len();
+
+        assert_eq!((nodes(&demux), stack(&demux)), (3, 1));
+
+        // Feeding more runs the failing frame again, from the same state.
+        writer.instructions(2, &program("aside", 1));
+        demux.feed(&writer.take()).unwrap_err();
+        demux.feed(&[]).unwrap_err();
+
+        assert_eq!((nodes(&demux), stack(&demux)), (3, 1));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert_eq!(demux(b"LRZ\x01"), Err(MuxError::BadMagic));
+        assert_eq!(demux(b"LRM\x02"), Err(MuxError::UnsupportedVersion(2)));
+        assert_eq!(demux(b"LR"), Err(MuxError::Truncated { offset: 0 }));
+    }
+}
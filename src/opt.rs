@@ -0,0 +1,464 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Bytecode optimizer built from composable passes.
+//!
+//! Every pass rewrites a decoded instruction list and must leave the output
+//! of the reference interpreter (`interp::Tree::render`) unchanged. Passes
+//! that depend on which element an instruction targets only fire when the
+//! input interprets without errors.
+
+use crate::analysis;
+use crate::decode::{decode, encode, to_latin1, DecodeError, Instruction, MAX_PAYLOAD_LENGTH};
+use crate::interp::{self, Interpreter, NodeId, NodeKind};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OptLevel {
+    /// No rewriting, the program is only re-encoded.
+    O0,
+    /// Removes `nop`s, orphaned subtrees and attribute writes that are
+    /// overwritten later.
+    #[default]
+    O1,
+    /// Additionally merges adjacent text nodes and coalesces style runs.
+    O2,
+}
+
+pub trait Pass {
+    fn name(&self) -> &'static str;
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction>;
+}
+
+#[derive(Default)]
+pub struct Pipeline {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn for_level(level: OptLevel) -> Self {
+        let pipeline = Pipeline::new();
+
+        match level {
+            OptLevel::O0 => pipeline,
+            OptLevel::O1 => pipeline
+                .with(StripNops)
+                .with(StripOrphans)
+                .with(DedupeAttributes),
+            OptLevel::O2 => pipeline
+                .with(StripNops)
+                .with(StripOrphans)
+                .with(DedupeAttributes)
+                .with(CoalesceStyles)
+                .with(MergeText),
+        }
+    }
+
+    pub fn with(mut self, pass: impl Pass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    pub fn passes(&self) -> impl Iterator<Item = &str> {
+        self.passes.iter().map(|pass| pass.name())
+    }
+
+    pub fn run(&self, mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+        for pass in &self.passes {
+            let before = cfg!(debug_assertions).then(|| interp::run(&instructions).ok());
+            instructions = pass.run(instructions);
+
+            if let Some(Some(before)) = before {
+                debug_assert!(
+                    interp::run(&instructions).is_ok_and(|after| before.equivalent(&after)),
+                    "pass `{}` changed the rendered tree",
+                    pass.name()
+                );
+            }
+        }
+
+        instructions
+    }
+}
+
+pub fn optimize(program: &[u8], level: OptLevel) -> Result<Vec<u8>, DecodeError> {
+    let instructions = decode(program)?;
+    Ok(encode(&Pipeline::for_level(level).run(instructions)))
+}
+
+/// Per-instruction facts gathered by running the reference interpreter.
+struct Step {
+    /// Element targeted by attribute, style and listener instructions.
+    target: Option<NodeId>,
+    /// Whether the target had no style declarations before this step.
+    unstyled: bool,
+}
+
+fn analyze(instructions: &[Instruction]) -> Option<Vec<Step>> {
+    let mut interpreter = Interpreter::new();
+    let mut steps = Vec::with_capacity(instructions.len());
+    let mut offset = 0;
+
+    for instruction in instructions {
+        let target = interpreter.target();
+        let unstyled = target.is_some_and(|id| {
+            matches!(&interpreter.tree().nodes[id].kind, NodeKind::Element { styles, .. } if styles.is_empty())
+        });
+
+        steps.push(Step { target, unstyled });
+        interpreter.step(instruction, offset).ok()?;
+        offset += instruction.encoded_len();
+    }
+
+    Some(steps)
+}
+
+/// Removes `nop` instructions.
+pub struct StripNops;
+
+impl Pass for StripNops {
+    fn name(&self) -> &'static str {
+        "strip-nops"
+    }
+
+    fn run(&self, mut instructions: Vec<Instruction>) -> Vec<Instruction> {
+        instructions.retain(|instruction| *instruction != Instruction::Nop);
+        instructions
+    }
+}
+
+/// Removes subtrees that are never attached, see [`analysis::strip_orphans`].
+pub struct StripOrphans;
+
+impl Pass for StripOrphans {
+    fn name(&self) -> &'static str {
+        "strip-orphans"
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        match analysis::analyze_instructions(&instructions) {
+            Ok(shape) => analysis::strip_dead(instructions, &shape),
+            Err(_) => instructions,
+        }
+    }
+}
+
+/// Drops `set_attribute`/`remove_attribute` instructions whose effect is
+/// overwritten by a later write of the same attribute on the same element.
+/// `style` is left alone since `style` instructions build on it.
+pub struct DedupeAttributes;
+
+impl Pass for DedupeAttributes {
+    fn name(&self) -> &'static str {
+        "dedupe-attributes"
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        let Some(steps) = analyze(&instructions) else {
+            return instructions;
+        };
+
+        let mut written = std::collections::HashSet::new();
+        let mut keep = vec![true; instructions.len()];
+
+        for (i, instruction) in instructions.iter().enumerate().rev() {
+            let name = match instruction {
+                Instruction::SetAttribute(name, _) | Instruction::RemoveAttribute(name) => name,
+                _ => continue,
+            };
+
+            if name.as_slice() != b"style" && !written.insert((steps[i].target, name.clone())) {
+                keep[i] = false;
+            }
+        }
+
+        instructions
+            .into_iter()
+            .zip(keep)
+            .filter_map(|(instruction, keep)| keep.then_some(instruction))
+            .collect()
+    }
+}
+
+/// Replaces a run of `style` instructions on an element without prior
+/// declarations by a single `style` attribute, when that encodes no larger
+/// than the run itself (in practice, runs of eight or more properties).
+pub struct CoalesceStyles;
+
+impl CoalesceStyles {
+    fn declaration(name: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        let simple = |s: &[u8]| {
+            !s.first().is_some_and(u8::is_ascii_whitespace)
+                && !s.last().is_some_and(u8::is_ascii_whitespace)
+                && !s.contains(&b';')
+        };
+
+        if name.is_empty() || name.contains(&b':') || !simple(name) || !simple(value) {
+            return None;
+        }
+
+        Some([name, b":", value].concat())
+    }
+
+    fn coalesce(run: &[Instruction]) -> Option<Instruction> {
+        let mut declarations = Vec::with_capacity(run.len());
+
+        for instruction in run {
+            let Instruction::Style(name, value) = instruction else {
+                return None;
+            };
+
+            declarations.push(Self::declaration(name, value)?);
+        }
+
+        let value = declarations.join(&b';');
+        let attribute = Instruction::SetAttribute(to_latin1("style"), value);
+        let run_len: usize = run.iter().map(Instruction::encoded_len).sum();
+
+        (attribute.payloads()[1].len() <= MAX_PAYLOAD_LENGTH && attribute.encoded_len() <= run_len)
+            .then_some(attribute)
+    }
+}
+
+impl Pass for CoalesceStyles {
+    fn name(&self) -> &'static str {
+        "coalesce-styles"
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        let Some(steps) = analyze(&instructions) else {
+            return instructions;
+        };
+
+        let mut out = Vec::with_capacity(instructions.len());
+        let mut i = 0;
+
+        while i < instructions.len() {
+            let end = instructions[i..]
+                .iter()
+                .position(|instruction| !matches!(instruction, Instruction::Style(..)))
+                .map_or(instructions.len(), |n| i + n);
+
+            if end - i >= 2 && steps[i].unstyled {
+                if let Some(attribute) = Self::coalesce(&instructions[i..end]) {
+                    out.push(attribute);
+                    i = end;
+                    continue;
+                }
+            }
+
+            out.push(instructions[i].clone());
+            i += 1;
+        }
+
+        out
+    }
+}
+
+/// Merges `text_node a; append_child; text_node b; append_child` into a
+/// single text node. Skipped for programs that remove or replace children,
+/// since those observe how many children an element has.
+pub struct MergeText;
+
+impl Pass for MergeText {
+    fn name(&self) -> &'static str {
+        "merge-text"
+    }
+
+    fn run(&self, instructions: Vec<Instruction>) -> Vec<Instruction> {
+        let observes_children = instructions
+            .iter()
+            .any(|i| matches!(i, Instruction::RemoveChild | Instruction::ReplaceChild));
+
+        if observes_children {
+            return instructions;
+        }
+
+        let mut out: Vec<Instruction> = Vec::with_capacity(instructions.len());
+
+        for instruction in instructions {
+            if let [.., Instruction::TextNode(prev), Instruction::AppendChild, Instruction::TextNode(next)] =
+                out.as_mut_slice()
+            {
+                if instruction == Instruction::AppendChild
+                    && prev.len() + next.len() <= MAX_PAYLOAD_LENGTH
+                {
+                    let next = std::mem::take(next);
+                    prev.extend_from_slice(&next);
+                    out.pop();
+                    continue;
+                }
+            }
+
+            out.push(instruction);
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_instructions;
+
+    const PAGE: &str = r#"
+        create_element "main"
+        nop
+        set_attribute "class" "old"
+        create_element "h1"
+        text_node "Hello, "
+        append_child
+        text_node "world"
+        append_child
+        append_child
+        set_attribute "class" "page"
+        create_element "p"
+        style "color" "red"
+        style "margin" "0"
+        style "padding" "4px"
+        style "display" "flex"
+        style "gap" "1em"
+        style "font-size" "12px"
+        style "line-height" "1.5"
+        style "border" "none"
+        nop
+        text_node "Body"
+        append_child
+        append_child
+        create_element "aside"
+        text_node "never attached"
+        append_child
+    "#;
+
+    /// Runs `pass` over `source` and checks that the rendered tree is
+    /// unchanged.
+    fn run_pass(pass: impl Pass, source: &str) -> Vec<Instruction> {
+        let before = assemble_instructions(source).unwrap();
+        let after = pass.run(before.clone());
+        let (expected, actual) = (interp::run(&before).unwrap(), interp::run(&after).unwrap());
+
+        assert!(
+            expected.equivalent(&actual),
+            "`{}` changed the rendered tree",
+            pass.name()
+        );
+
+        after
+    }
+
+    fn count(instructions: &[Instruction], f: impl Fn(&Instruction) -> bool) -> usize {
+        instructions.iter().filter(|i| f(i)).count()
+    }
+
+    #[test]
+    fn strip_nops() {
+        let out = run_pass(StripNops, PAGE);
+        assert_eq!(count(&out, |i| *i == Instruction::Nop), 0);
+    }
+
+    #[test]
+    fn strip_orphans() {
+        let out = run_pass(StripOrphans, PAGE);
+
+        assert!(!out.contains(&Instruction::CreateElement(to_latin1("aside"))));
+        assert!(!out.contains(&Instruction::TextNode(to_latin1("never attached"))));
+    }
+
+    #[test]
+    fn dedupe_attributes() {
+        let out = run_pass(DedupeAttributes, PAGE);
+
+        assert!(!out.contains(&Instruction::SetAttribute(
+            to_latin1("class"),
+            to_latin1("old")
+        )));
+        assert!(out.contains(&Instruction::SetAttribute(
+            to_latin1("class"),
+            to_latin1("page")
+        )));
+    }
+
+    #[test]
+    fn dedupe_attributes_keeps_style_before_style_instructions() {
+        let source = r#"
+            create_element "div"
+            set_attribute "style" "color:red"
+            style "margin" "0"
+            set_attribute "style" "color:blue"
+        "#;
+
+        assert_eq!(
+            run_pass(DedupeAttributes, source),
+            assemble_instructions(source).unwrap()
+        );
+    }
+
+    #[test]
+    fn coalesce_styles() {
+        let out = run_pass(CoalesceStyles, PAGE);
+
+        assert_eq!(count(&out, |i| matches!(i, Instruction::Style(..))), 0);
+        assert!(out.iter().any(
+            |i| matches!(i, Instruction::SetAttribute(name, _) if name.as_slice() == b"style")
+        ));
+    }
+
+    #[test]
+    fn coalesce_styles_leaves_short_runs() {
+        let source = r#"
+            create_element "div"
+            style "color" "red"
+            style "margin" "0"
+        "#;
+
+        assert_eq!(
+            run_pass(CoalesceStyles, source),
+            assemble_instructions(source).unwrap()
+        );
+    }
+
+    #[test]
+    fn merge_text() {
+        let out = run_pass(MergeText, PAGE);
+
+        assert!(out.contains(&Instruction::TextNode(to_latin1("Hello, world"))));
+        assert_eq!(count(&out, |i| matches!(i, Instruction::TextNode(_))), 3);
+    }
+
+    #[test]
+    fn merge_text_skips_programs_that_observe_children() {
+        let source = r#"
+            create_element "p"
+            text_node "a"
+            append_child
+            text_node "b"
+            append_child
+            remove_child
+        "#;
+
+        assert_eq!(
+            run_pass(MergeText, source),
+            assemble_instructions(source).unwrap()
+        );
+    }
+
+    #[test]
+    fn levels_preserve_rendering() {
+        let program = encode(&assemble_instructions(PAGE).unwrap());
+        let expected = interp::interpret(&program).unwrap();
+        let mut sizes = Vec::new();
+
+        for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2] {
+            let optimized = optimize(&program, level).unwrap();
+
+            assert!(interp::interpret(&optimized).unwrap().equivalent(&expected));
+            sizes.push(optimized.len());
+        }
+
+        assert_eq!(sizes[0], program.len());
+        assert!(sizes[1] < sizes[0] && sizes[2] < sizes[1]);
+    }
+}
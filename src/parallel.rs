@@ -0,0 +1,314 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Encoding sibling subtrees on several threads.
+//!
+//! Programs carry no absolute node or event IDs: both are ordinals of the
+//! instructions that create them. A subtree therefore encodes to the same
+//! bytes wherever it ends up, and sibling subtrees can be encoded
+//! independently and written in order. [`encode_subtrees`] runs a builder
+//! per item on a pool of scoped threads;
+//! [`crate::io::Builder::parallel_children`] appends the results to a
+//! parent element and writes exactly the bytes a sequential loop would.
+//!
+//! Each fragment is checked to be a single subtree: one top-level node,
+//! nothing else left on the stack and no instruction reaching below it.
+//! Anything else, such as a fragment that appends siblings to its own root,
+//! would attach differently once spliced under a parent.
+
+use std::fmt;
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+use crate::debug::DebugInfo;
+use crate::decode::{DecodeError, Instructions};
+use crate::io::Builder;
+use crate::{
+    OPCODE_APPEND_CHILD, OPCODE_APPEND_SIBLING, OPCODE_CREATE_ELEMENT, OPCODE_NOP,
+    OPCODE_PLACEHOLDER, OPCODE_REPLACE_CHILD, OPCODE_TEXT_NODE,
+};
+
+/// Encoded bytes of one subtree and its debug info, with offsets relative to
+/// its first byte.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subtree {
+    pub bytes: Vec<u8>,
+    pub debug: Option<DebugInfo>,
+}
+
+/// Number of threads used when `0` is requested.
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Runs `build` on a fresh builder for each of `items`, on up to `threads`
+/// threads, and returns the subtrees in the order of `items`. With `debug`,
+/// the builders record debug info. Stops at the first error, returning the
+/// error of the earliest failed item.
+pub fn encode_subtrees<T, F>(
+    items: &[T],
+    threads: usize,
+    debug: bool,
+    build: F,
+) -> io::Result<Vec<Subtree>>
+where
+    T: Sync,
+    F: Fn(&mut Builder<Vec<u8>>, &T) -> io::Result<()> + Sync,
+{
+    let threads = match threads {
+        0 => default_threads(),
+        n => n,
+    }
+    .min(items.len());
+
+    let encode = |index: usize| -> io::Result<Subtree> {
+        let mut builder = if debug {
+            Builder::with_debug_info(Vec::new())
+        } else {
+            Builder::new(Vec::new())
+        };
+
+        build(&mut builder, &items[index])?;
+
+        let (bytes, debug) = builder.finish()?;
+
+        check_subtree(&bytes).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("item {} {}", index, err),
+            )
+        })?;
+
+        Ok(Subtree { bytes, debug })
+    };
+
+    if threads <= 1 {
+        return (0..items.len()).map(encode).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+
+    let mut results: Vec<(usize, io::Result<Subtree>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+
+                    while !failed.load(Ordering::Relaxed) {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+
+                        if index >= items.len() {
+                            break;
+                        }
+
+                        let result = encode(index);
+
+                        if result.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+
+                        done.push((index, result));
+                    }
+
+                    done
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+
+    results.sort_by_key(|(index, _)| *index);
+
+    // Items are claimed in order, so when nothing failed every index is
+    // present and the first error is the earliest one claimed.
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Why a fragment is not a single subtree, see [`check_subtree`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SubtreeError {
+    Decode(DecodeError),
+    NoNodes,
+    /// A sibling is appended to the top-level node.
+    SiblingOfRoot,
+    /// An instruction applies to the node stack below the top-level node.
+    OutsideSubtree,
+    TopLevelNodes(usize),
+}
+
+impl fmt::Display for SubtreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubtreeError::Decode(err) => write!(f, "does not decode: {}", err),
+            SubtreeError::NoNodes => write!(f, "has no nodes"),
+            SubtreeError::SiblingOfRoot => write!(f, "appends a sibling to its top-level node"),
+            SubtreeError::OutsideSubtree => write!(f, "edits a node outside of its subtree"),
+            SubtreeError::TopLevelNodes(n) => {
+                write!(f, "has {} top-level nodes instead of one", n)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SubtreeError {}
+
+/// Checks the stack effect of a fragment: its first node must stay on the
+/// stack and be the only node left, and no instruction may reach below it.
+/// Everything else behaves the same once the fragment is spliced in.
+pub fn check_subtree(fragment: &[u8]) -> Result<(), SubtreeError> {
+    let mut depth = 0usize;
+
+    for item in Instructions::new(fragment) {
+        let (_, instruction) = item.map_err(SubtreeError::Decode)?;
+
+        match instruction.opcode() as libc::c_uint {
+            OPCODE_NOP => {}
+            OPCODE_CREATE_ELEMENT | OPCODE_TEXT_NODE | OPCODE_PLACEHOLDER => depth += 1,
+            OPCODE_APPEND_SIBLING if depth == 2 => return Err(SubtreeError::SiblingOfRoot),
+            OPCODE_APPEND_CHILD | OPCODE_APPEND_SIBLING | OPCODE_REPLACE_CHILD if depth >= 2 => {
+                depth -= 1
+            }
+            // Below two nodes these pop the top-level node itself and attach
+            // it to whatever the fragment is spliced under.
+            OPCODE_APPEND_CHILD | OPCODE_APPEND_SIBLING | OPCODE_REPLACE_CHILD => {
+                return Err(SubtreeError::OutsideSubtree)
+            }
+            _ if depth >= 1 => {}
+            _ => return Err(SubtreeError::OutsideSubtree),
+        }
+    }
+
+    match depth {
+        1 => Ok(()),
+        0 => Err(SubtreeError::NoNodes),
+        n => Err(SubtreeError::TopLevelNodes(n)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    fn row(builder: &mut Builder<Vec<u8>>, n: &usize) -> io::Result<()> {
+        builder.create_element("li")?;
+        builder.set_attribute("data-n", &n.to_string())?;
+        builder.add_event_listener("click")?;
+
+        for _ in 0..n % 3 {
+            builder.create_element("span")?;
+            builder.text_node("x")?;
+            builder.append_child()?;
+            builder.append_child()?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn matches_a_sequential_loop() {
+        let items: Vec<usize> = (0..200).collect();
+        let mut sequential = Builder::new(Vec::new());
+
+        sequential.create_element("ul").unwrap();
+
+        for item in &items {
+            row(&mut sequential, item).unwrap();
+            sequential.append_child().unwrap();
+        }
+
+        let (expected, _) = sequential.finish().unwrap();
+
+        for threads in [0, 1, 2, 7] {
+            let mut parallel = Builder::new(Vec::new());
+
+            parallel.create_element("ul").unwrap();
+            parallel.parallel_children(&items, threads, row).unwrap();
+
+            let (bytes, _) = parallel.finish().unwrap();
+            assert_eq!(bytes, expected, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn reports_the_earliest_failing_item() {
+        let items: Vec<usize> = (0..50).collect();
+        let err = encode_subtrees(&items, 4, false, |builder, &n| match n {
+            13 | 40 => builder.append_child(),
+            _ => row(builder, &n),
+        })
+        .unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "item 13 edits a node outside of its subtree"
+        );
+    }
+
+    fn check(source: &str) -> Result<(), SubtreeError> {
+        check_subtree(&assemble(source).unwrap())
+    }
+
+    #[test]
+    fn accepts_single_subtrees() {
+        assert_eq!(check("create_element \"div\""), Ok(()));
+        assert_eq!(
+            check(
+                "create_element \"div\"\ntext_node \"a\"\nappend_child\n\
+                 text_node \"b\"\nreplace_child\nremove_child\nnop"
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                "create_element \"ul\"\ncreate_element \"li\"\n\
+                 create_element \"li\"\nappend_sibling\nappend_child"
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_fragments_reaching_outside_their_subtree() {
+        for pop in ["append_child", "append_sibling", "replace_child"] {
+            assert_eq!(
+                check(&format!("create_element \"div\"\n{}", pop)),
+                Err(SubtreeError::OutsideSubtree),
+                "{}",
+                pop
+            );
+        }
+
+        assert_eq!(
+            check("set_attribute \"id\" \"x\"\ncreate_element \"div\""),
+            Err(SubtreeError::OutsideSubtree)
+        );
+        assert_eq!(
+            check("create_element \"a\"\ncreate_element \"b\"\nappend_sibling"),
+            Err(SubtreeError::SiblingOfRoot)
+        );
+        assert_eq!(
+            check("create_element \"a\"\ncreate_element \"b\""),
+            Err(SubtreeError::TopLevelNodes(2))
+        );
+        assert_eq!(check("nop"), Err(SubtreeError::NoNodes));
+    }
+
+    #[test]
+    fn rejects_fragments_that_do_not_decode() {
+        for fragment in [&b"\x01"[..], b"\x01\x05ab", b"\x01\x01a\x02\x01", b"\xee"] {
+            assert!(
+                matches!(check_subtree(fragment), Err(SubtreeError::Decode(_))),
+                "{:?}",
+                fragment
+            );
+        }
+    }
+}
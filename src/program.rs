@@ -0,0 +1,335 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Immutable programs that can be shared across threads.
+//!
+//! A locked `librender_bytecode_buffer` is only immutable by convention:
+//! anyone holding it can unlock it again. A [`Program`] is frozen for good.
+//! It is a reference-counted `Arc<[u8]>`, so clones are cheap and it is
+//! `Send + Sync`. [`Program::thaw`] returns a [`Thawed`] buffer that reads
+//! the shared bytes and copies them only when it is first edited.
+//!
+//! From C, `librender_freeze` turns a buffer into a `librender_program`
+//! handle counted with `librender_program_retain` and
+//! `librender_program_release`.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+use crate::cursor::ProgramBuffer;
+use crate::{
+    buffer_bytes, buffer_from_bytes, librender_bytecode_buffer, librender_free_buffer, size_t,
+    uint8_t,
+};
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Program(Arc<[u8]>);
+
+impl Program {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Whether `self` and `other` share the same bytes, rather than just
+    /// equal ones.
+    pub fn ptr_eq(&self, other: &Program) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// Returns a buffer for editing the program. Until it is edited, it
+    /// shares the bytes of `self`.
+    pub fn thaw(&self) -> Thawed {
+        Thawed::Frozen(self.clone())
+    }
+}
+
+impl Deref for Program {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Program {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Program({} bytes)", self.0.len())
+    }
+}
+
+impl From<Vec<u8>> for Program {
+    fn from(bytes: Vec<u8>) -> Self {
+        Program(bytes.into())
+    }
+}
+
+impl From<&[u8]> for Program {
+    fn from(bytes: &[u8]) -> Self {
+        Program(bytes.into())
+    }
+}
+
+impl From<Thawed> for Program {
+    fn from(thawed: Thawed) -> Self {
+        thawed.freeze()
+    }
+}
+
+/// A copy-on-write buffer made by [`Program::thaw`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Thawed {
+    /// Not edited yet; reads the frozen bytes.
+    Frozen(Program),
+    Owned(Vec<u8>),
+}
+
+impl Thawed {
+    pub fn is_modified(&self) -> bool {
+        matches!(self, Thawed::Owned(_))
+    }
+
+    /// The bytes, copied out of the frozen program on first use.
+    pub fn to_mut(&mut self) -> &mut Vec<u8> {
+        if let Thawed::Frozen(program) = self {
+            *self = Thawed::Owned(program.to_vec());
+        }
+
+        match self {
+            Thawed::Owned(bytes) => bytes,
+            Thawed::Frozen(_) => unreachable!(),
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            Thawed::Frozen(program) => program.to_vec(),
+            Thawed::Owned(bytes) => bytes,
+        }
+    }
+
+    /// Freezes the buffer again. A buffer that was not edited returns the
+    /// program it was thawed from without copying.
+    pub fn freeze(self) -> Program {
+        match self {
+            Thawed::Frozen(program) => program,
+            Thawed::Owned(bytes) => bytes.into(),
+        }
+    }
+}
+
+impl ProgramBuffer for Thawed {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Thawed::Frozen(program) => program,
+            Thawed::Owned(bytes) => bytes,
+        }
+    }
+
+    fn splice(&mut self, range: Range<usize>, bytes: &[u8]) -> bool {
+        self.to_mut().splice(range, bytes.iter().copied());
+        true
+    }
+}
+
+impl Write for Thawed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.to_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub type librender_program = Program;
+
+/// Moves the program in `buf` into a new frozen program with a reference
+/// count of 1, and frees `buf`.
+///
+/// # Safety
+///
+/// `buf` must be null or a buffer from `librender_create_buffer` that is
+/// not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn librender_freeze(
+    buf: *mut librender_bytecode_buffer,
+) -> *const librender_program {
+    if buf.is_null() {
+        return std::ptr::null();
+    }
+
+    let program = Program::from(buffer_bytes(buf));
+
+    librender_free_buffer(buf);
+    Arc::into_raw(Arc::new(program))
+}
+
+/// Adds a reference to `program` and returns it.
+///
+/// # Safety
+///
+/// `program` must be null or a live handle from `librender_freeze`.
+#[no_mangle]
+pub unsafe extern "C" fn librender_program_retain(
+    program: *const librender_program,
+) -> *const librender_program {
+    if !program.is_null() {
+        Arc::increment_strong_count(program);
+    }
+
+    program
+}
+
+/// Drops a reference to `program`, freeing it with the last one.
+///
+/// # Safety
+///
+/// `program` must be null or a live handle from `librender_freeze`, and
+/// the reference released must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn librender_program_release(program: *const librender_program) {
+    if !program.is_null() {
+        Arc::decrement_strong_count(program);
+    }
+}
+
+/// Bytes of `program`, valid while a reference to it is held.
+///
+/// # Safety
+///
+/// `program` must be null or a live handle from `librender_freeze`.
+#[no_mangle]
+pub unsafe extern "C" fn librender_program_bytes(
+    program: *const librender_program,
+) -> *const uint8_t {
+    match program.as_ref() {
+        Some(program) => program.as_ptr(),
+        None => std::ptr::null(),
+    }
+}
+
+/// # Safety
+///
+/// `program` must be null or a live handle from `librender_freeze`.
+#[no_mangle]
+pub unsafe extern "C" fn librender_program_size(program: *const librender_program) -> size_t {
+    match program.as_ref() {
+        Some(program) => program.len() as size_t,
+        None => 0,
+    }
+}
+
+/// Copies `program` into a new unlocked buffer the caller frees.
+///
+/// # Safety
+///
+/// `program` must be null or a live handle from `librender_freeze`.
+#[no_mangle]
+pub unsafe extern "C" fn librender_program_thaw(
+    program: *const librender_program,
+) -> *mut librender_bytecode_buffer {
+    match program.as_ref() {
+        Some(program) => buffer_from_bytes(program),
+        None => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::ManuallyDrop;
+
+    use crate::cursor::Cursor;
+    use crate::decode::Instruction;
+    use crate::{librender_create_buffer, librender_create_element, librender_text_node};
+
+    const PROGRAM: &[u8] = b"\x01\x03div\x06\x02hi\x03";
+
+    #[test]
+    fn thaw_copies_on_first_edit() {
+        let program = Program::from(PROGRAM);
+        let thawed = program.thaw();
+
+        assert!(!thawed.is_modified());
+        assert!(thawed.clone().freeze().ptr_eq(&program));
+
+        let mut cursor = Cursor::new(thawed).unwrap();
+        cursor.seek(1).unwrap();
+        cursor.replace_payload(0, b"yo").unwrap();
+
+        let thawed = cursor.into_inner();
+        assert!(thawed.is_modified());
+        assert_eq!(thawed.bytes(), b"\x01\x03div\x06\x02yo\x03");
+        assert_eq!(program.as_bytes(), PROGRAM);
+        assert!(!thawed.freeze().ptr_eq(&program));
+    }
+
+    #[test]
+    fn shares_across_threads() {
+        let program = Program::from(PROGRAM);
+        let lengths: Vec<_> = (0..4)
+            .map(|_| {
+                let program = program.clone();
+                std::thread::spawn(move || crate::decode::decode(&program).unwrap().len())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect();
+
+        assert_eq!(lengths, [3; 4]);
+    }
+
+    #[test]
+    fn writes_through_thawed_buffers() {
+        let mut thawed = Program::from(PROGRAM).thaw();
+
+        crate::io::Builder::new(&mut thawed)
+            .instruction(&Instruction::AppendChild)
+            .unwrap();
+
+        assert_eq!(thawed.into_vec(), b"\x01\x03div\x06\x02hi\x03\x03");
+    }
+
+    #[test]
+    fn counts_references_from_c() {
+        unsafe {
+            let buf = librender_create_buffer(0);
+
+            librender_create_element(buf, c"div".as_ptr(), 3);
+            librender_text_node(buf, c"hi".as_ptr(), 2);
+
+            let program = librender_freeze(buf);
+            let other = librender_program_retain(program);
+
+            assert_eq!(other, program);
+            assert_eq!(
+                Arc::strong_count(&ManuallyDrop::new(Arc::from_raw(program))),
+                2
+            );
+
+            librender_program_release(program);
+            assert_eq!(librender_program_size(other), 9);
+            assert_eq!(
+                std::slice::from_raw_parts(librender_program_bytes(other), 9),
+                &PROGRAM[..9]
+            );
+
+            let thawed = librender_program_thaw(other);
+            librender_program_release(other);
+            assert_eq!(buffer_bytes(thawed), &PROGRAM[..9]);
+            librender_free_buffer(thawed);
+
+            assert!(librender_freeze(std::ptr::null_mut()).is_null());
+            assert_eq!(librender_program_size(std::ptr::null()), 0);
+        }
+    }
+}
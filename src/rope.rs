@@ -0,0 +1,862 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Chunked program buffer for inserting into the middle of large programs.
+//!
+//! A [`Rope`] keeps a program as a treap of chunks, each holding whole
+//! instructions of at most [`CHUNK_SIZE`] bytes. Edits address instructions
+//! by index, so they always land on instruction boundaries. Inserting or
+//! removing instructions splits the tree around them and joins it back,
+//! which takes O(log n) in the number of chunks plus the size of the edit,
+//! instead of shifting everything after it as `librender_splice` does.
+//!
+//! A rope is a `Write`, so a [`crate::io::Builder`] emits into it with the
+//! usual emitters. [`Rope::flatten`] copies the program into contiguous
+//! bytes for shipping.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+use crate::decode::{decode_at, opcode_arity, DecodeError, DecodeErrorKind, Instruction};
+use crate::{buffer_bytes, buffer_from_bytes, librender_bytecode_buffer, size_t};
+
+/// Size a chunk is filled up to before a new one is started. A single
+/// instruction may still exceed it.
+pub const CHUNK_SIZE: usize = 4096;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RopeError {
+    /// The inserted bytes are not a well-formed program.
+    Decode(DecodeError),
+    /// No instruction has this index.
+    OutOfRange(usize),
+}
+
+impl fmt::Display for RopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RopeError::Decode(err) => err.fmt(f),
+            RopeError::OutOfRange(index) => write!(f, "instruction {} is out of range", index),
+        }
+    }
+}
+
+impl std::error::Error for RopeError {}
+
+impl From<DecodeError> for RopeError {
+    fn from(err: DecodeError) -> Self {
+        RopeError::Decode(err)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Chunk {
+    bytes: Vec<u8>,
+    /// Offset of each instruction in `bytes`.
+    starts: Vec<usize>,
+}
+
+impl Chunk {
+    fn count(&self) -> usize {
+        self.starts.len()
+    }
+
+    /// Moves the instructions of `other` to the end of this chunk.
+    fn append(&mut self, other: Chunk) {
+        let offset = self.bytes.len();
+
+        self.bytes.extend(other.bytes);
+        self.starts
+            .extend(other.starts.into_iter().map(|start| start + offset));
+    }
+
+    /// Moves instructions `at..` into a new chunk.
+    fn split_off(&mut self, at: usize) -> Chunk {
+        let offset = self.starts[at];
+        let starts = self.starts.split_off(at);
+
+        Chunk {
+            bytes: self.bytes.split_off(offset),
+            starts: starts.into_iter().map(|start| start - offset).collect(),
+        }
+    }
+}
+
+type Link = Option<Box<Node>>;
+
+#[derive(Clone, Debug)]
+struct Node {
+    chunk: Chunk,
+    priority: u64,
+    /// Bytes and instructions in the subtree.
+    len: usize,
+    count: usize,
+    left: Link,
+    right: Link,
+}
+
+impl Node {
+    fn update(&mut self) {
+        self.len = len(&self.left) + self.chunk.bytes.len() + len(&self.right);
+        self.count = count(&self.left) + self.chunk.count() + count(&self.right);
+    }
+}
+
+fn len(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.len)
+}
+
+fn count(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.count)
+}
+
+/// Splits `link` into its first `k` instructions and the rest.
+fn split(link: Link, k: usize) -> (Link, Link) {
+    let Some(mut node) = link else {
+        return (None, None);
+    };
+
+    let before = count(&node.left);
+    let after = before + node.chunk.count();
+
+    if k <= before {
+        let (left, right) = split(node.left.take(), k);
+        node.left = right;
+        node.update();
+        (left, Some(node))
+    } else if k >= after {
+        let (left, right) = split(node.right.take(), k - after);
+        node.right = left;
+        node.update();
+        (Some(node), right)
+    } else {
+        // The tail keeps the priority of the node, which is at least that
+        // of the right subtree it takes over.
+        let mut tail = Box::new(Node {
+            chunk: node.chunk.split_off(k - before),
+            priority: node.priority,
+            len: 0,
+            count: 0,
+            left: None,
+            right: node.right.take(),
+        });
+
+        tail.update();
+        node.update();
+        (Some(node), Some(tail))
+    }
+}
+
+fn merge(left: Link, right: Link) -> Link {
+    match (left, right) {
+        (None, link) | (link, None) => link,
+        (Some(mut left), Some(mut right)) => {
+            if left.priority >= right.priority {
+                left.right = merge(left.right.take(), Some(right));
+                left.update();
+                Some(left)
+            } else {
+                right.left = merge(Some(left), right.left.take());
+                right.update();
+                Some(right)
+            }
+        }
+    }
+}
+
+/// Detaches the last chunk of `link`, returning the rest and the chunk's
+/// node.
+fn pop_last(link: Link) -> (Link, Link) {
+    let Some(mut node) = link else {
+        return (None, None);
+    };
+
+    match node.right.take() {
+        Some(right) => {
+            let (rest, last) = pop_last(Some(right));
+            node.right = rest;
+            node.update();
+            (Some(node), last)
+        }
+        None => {
+            let rest = node.left.take();
+            node.update();
+            (rest, Some(node))
+        }
+    }
+}
+
+/// Detaches the first chunk of `link`, returning the chunk's node and the
+/// rest.
+fn pop_first(link: Link) -> (Link, Link) {
+    let Some(mut node) = link else {
+        return (None, None);
+    };
+
+    match node.left.take() {
+        Some(left) => {
+            let (first, rest) = pop_first(Some(left));
+            node.left = rest;
+            node.update();
+            (first, Some(node))
+        }
+        None => {
+            let rest = node.right.take();
+            node.update();
+            (Some(node), rest)
+        }
+    }
+}
+
+fn fits(a: &Node, b: &Node) -> bool {
+    a.chunk.bytes.len() + b.chunk.bytes.len() <= CHUNK_SIZE
+}
+
+/// Moves the instructions of `back` to the end of the chunk of `front`.
+fn absorb(front: &mut Node, back: Chunk) {
+    front.chunk.append(back);
+    front.update();
+}
+
+/// Joins `left` and `right` like [`merge`], coalescing the chunks around
+/// the seam that fit in one. Splitting a chunk leaves its pieces at the
+/// edges of the trees being joined, so the neighbours of the chunks at the
+/// seam are tried as well. As long as no two adjacent chunks of either
+/// tree fit in one, none of the joined tree do.
+fn join(left: Link, right: Link) -> Link {
+    let (mut left, last) = pop_last(left);
+    let (first, mut right) = pop_first(right);
+    let mut seam: Vec<Box<Node>> = last.into_iter().chain(first).collect();
+
+    if seam.len() == 2 && fits(&seam[0], &seam[1]) {
+        let back = seam.pop().unwrap();
+        absorb(&mut seam[0], back.chunk);
+    }
+
+    if seam.is_empty() {
+        return None;
+    }
+
+    let (rest, prev) = pop_last(left);
+    left = match prev {
+        Some(prev) if fits(&prev, &seam[0]) => {
+            let node = std::mem::replace(&mut seam[0], prev);
+            absorb(&mut seam[0], node.chunk);
+            rest
+        }
+        prev => merge(rest, prev),
+    };
+
+    let (next, rest) = pop_first(right);
+    right = match next {
+        Some(next) if fits(seam.last().unwrap(), &next) => {
+            absorb(seam.last_mut().unwrap(), next.chunk);
+            rest
+        }
+        next => merge(next, rest),
+    };
+
+    let joined = seam
+        .into_iter()
+        .fold(left, |link, node| merge(link, Some(node)));
+
+    merge(joined, right)
+}
+
+/// Appends instructions to the last chunk if it has room. Returns `false`
+/// otherwise.
+fn extend_last(link: &mut Link, bytes: &[u8], starts: &[usize]) -> bool {
+    let Some(node) = link else {
+        return false;
+    };
+
+    let extended = match node.right {
+        Some(_) => extend_last(&mut node.right, bytes, starts),
+        None if node.chunk.bytes.len() + bytes.len() <= CHUNK_SIZE => {
+            let offset = node.chunk.bytes.len();
+
+            node.chunk.bytes.extend_from_slice(bytes);
+            node.chunk
+                .starts
+                .extend(starts.iter().map(|start| start + offset));
+            true
+        }
+        None => false,
+    };
+
+    if extended {
+        node.update();
+    }
+
+    extended
+}
+
+/// Length of the instruction at `offset`, or `None` if `bytes` ends before
+/// it does.
+fn instruction_len(bytes: &[u8], offset: usize) -> Result<Option<usize>, DecodeError> {
+    let opcode = bytes[offset];
+    let arity = opcode_arity(opcode).ok_or(DecodeError {
+        offset,
+        instruction_offset: offset,
+        kind: DecodeErrorKind::UnknownOpcode(opcode),
+    })?;
+    let mut pos = offset + 1;
+
+    for _ in 0..arity {
+        match bytes.get(pos) {
+            Some(&length) => pos += 1 + length as usize,
+            None => return Ok(None),
+        }
+    }
+
+    Ok((pos <= bytes.len()).then_some(pos - offset))
+}
+
+/// A program stored as a balanced tree of chunks.
+#[derive(Clone, Debug)]
+pub struct Rope {
+    root: Link,
+    /// Bytes written that do not complete an instruction yet.
+    pending: Vec<u8>,
+    seed: u64,
+}
+
+impl Default for Rope {
+    fn default() -> Self {
+        Self {
+            root: None,
+            pending: Vec::new(),
+            seed: 0x9e3779b97f4a7c15,
+        }
+    }
+}
+
+impl Rope {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_program(program: &[u8]) -> Result<Self, DecodeError> {
+        let mut rope = Self::new();
+
+        rope.root = rope.build(program)?;
+        Ok(rope)
+    }
+
+    /// Number of bytes, including a trailing partial instruction.
+    pub fn len(&self) -> usize {
+        len(&self.root) + self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of complete instructions.
+    pub fn instruction_count(&self) -> usize {
+        count(&self.root)
+    }
+
+    /// Whether the bytes written so far end on an instruction boundary.
+    pub fn is_complete(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn priority(&mut self) -> u64 {
+        // xorshift64, so the shape of the tree is reproducible.
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed
+    }
+
+    fn node(&mut self, chunk: Chunk) -> Link {
+        let mut node = Box::new(Node {
+            chunk,
+            priority: self.priority(),
+            len: 0,
+            count: 0,
+            left: None,
+            right: None,
+        });
+
+        node.update();
+        Some(node)
+    }
+
+    /// Cuts a well-formed program into chunks and joins them into a tree.
+    fn build(&mut self, program: &[u8]) -> Result<Link, DecodeError> {
+        let mut root = None;
+        let mut chunk = Chunk::default();
+        let mut offset = 0;
+
+        while offset < program.len() {
+            let (_, length) = decode_at(program, offset)?;
+
+            if !chunk.bytes.is_empty() && chunk.bytes.len() + length > CHUNK_SIZE {
+                let node = self.node(std::mem::take(&mut chunk));
+                root = merge(root, node);
+            }
+
+            chunk.starts.push(chunk.bytes.len());
+            chunk
+                .bytes
+                .extend_from_slice(&program[offset..offset + length]);
+            offset += length;
+        }
+
+        if !chunk.bytes.is_empty() {
+            let node = self.node(chunk);
+            root = merge(root, node);
+        }
+
+        Ok(root)
+    }
+
+    /// Byte offset of instruction `index`, or of the end of the complete
+    /// instructions if `index` is the instruction count.
+    pub fn offset(&self, index: usize) -> Option<usize> {
+        if index > self.instruction_count() {
+            return None;
+        }
+
+        let mut link = &self.root;
+        let mut k = index;
+        let mut offset = 0;
+
+        while let Some(node) = link {
+            let before = count(&node.left);
+
+            if k < before {
+                link = &node.left;
+                continue;
+            }
+
+            offset += len(&node.left);
+            k -= before;
+
+            match node.chunk.starts.get(k) {
+                Some(start) => return Some(offset + start),
+                None => {
+                    offset += node.chunk.bytes.len();
+                    k -= node.chunk.count();
+                    link = &node.right;
+                }
+            }
+        }
+
+        Some(offset)
+    }
+
+    /// Instruction `index`.
+    pub fn get(&self, index: usize) -> Option<Instruction> {
+        let mut link = &self.root;
+        let mut k = index;
+
+        while let Some(node) = link {
+            let before = count(&node.left);
+
+            if k < before {
+                link = &node.left;
+                continue;
+            }
+
+            k -= before;
+
+            match node.chunk.starts.get(k) {
+                Some(&start) => return Some(decode_at(&node.chunk.bytes, start).unwrap().0),
+                None => {
+                    k -= node.chunk.count();
+                    link = &node.right;
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Inserts the instructions of `program` before instruction `index`.
+    pub fn insert(&mut self, index: usize, program: &[u8]) -> Result<(), RopeError> {
+        if index > self.instruction_count() {
+            return Err(RopeError::OutOfRange(index));
+        }
+
+        let middle = self.build(program)?;
+        let (left, right) = split(self.root.take(), index);
+
+        self.root = join(join(left, middle), right);
+        Ok(())
+    }
+
+    /// Removes instructions `range` and returns their bytes.
+    pub fn remove(&mut self, range: Range<usize>) -> Result<Vec<u8>, RopeError> {
+        if range.end > self.instruction_count() {
+            return Err(RopeError::OutOfRange(range.end));
+        }
+
+        if range.start > range.end {
+            return Err(RopeError::OutOfRange(range.start));
+        }
+
+        let (left, right) = split(self.root.take(), range.end);
+        let (left, middle) = split(left, range.start);
+
+        self.root = join(left, right);
+
+        let mut bytes = Vec::with_capacity(len(&middle));
+        for_each_chunk(&middle, &mut |chunk| bytes.extend_from_slice(chunk));
+        Ok(bytes)
+    }
+
+    /// Calls `f` with the chunks in program order, then with a trailing
+    /// partial instruction if there is one.
+    pub fn for_each_chunk<F: FnMut(&[u8])>(&self, mut f: F) {
+        for_each_chunk(&self.root, &mut f);
+
+        if !self.pending.is_empty() {
+            f(&self.pending);
+        }
+    }
+
+    /// Copies the program into contiguous bytes.
+    pub fn flatten(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.len());
+
+        self.for_each_chunk(|chunk| out.extend_from_slice(chunk));
+        out
+    }
+
+    /// Appends complete instructions, starting a new chunk if the last one
+    /// is full.
+    fn append(&mut self, bytes: &[u8], starts: &[usize]) {
+        if !extend_last(&mut self.root, bytes, starts) {
+            let node = self.node(Chunk {
+                bytes: bytes.to_vec(),
+                starts: starts.to_vec(),
+            });
+
+            self.root = merge(self.root.take(), node);
+        }
+    }
+}
+
+fn for_each_chunk<F: FnMut(&[u8])>(link: &Link, f: &mut F) {
+    if let Some(node) = link {
+        for_each_chunk(&node.left, f);
+        f(&node.chunk.bytes);
+        for_each_chunk(&node.right, f);
+    }
+}
+
+impl Write for Rope {
+    /// Appends `buf`, keeping a trailing partial instruction aside until the
+    /// bytes that complete it arrive. Bytes that do not start a known
+    /// instruction are rejected with `ErrorKind::InvalidData`.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.pending.len();
+        let mut pos = 0;
+        let mut starts = Vec::new();
+
+        self.pending.extend_from_slice(buf);
+
+        while pos < self.pending.len() {
+            match instruction_len(&self.pending, pos) {
+                Ok(Some(length)) => {
+                    starts.push(pos);
+                    pos += length;
+                }
+                Ok(None) => break,
+                Err(mut err) => {
+                    self.pending.truncate(written);
+                    err.offset += len(&self.root);
+                    err.instruction_offset += len(&self.root);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                }
+            }
+        }
+
+        if pos > 0 {
+            let pending = self.pending.split_off(pos);
+            let bytes = std::mem::replace(&mut self.pending, pending);
+
+            self.append(&bytes, &starts);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub type librender_rope = Rope;
+
+#[no_mangle]
+pub extern "C" fn librender_rope_create() -> *mut librender_rope {
+    Box::into_raw(Box::new(Rope::new()))
+}
+
+/// Creates a rope holding the program in `buf`. Returns null if it is not
+/// well-formed.
+///
+/// # Safety
+///
+/// `buf` must be null or point to a valid buffer.
+#[no_mangle]
+pub unsafe extern "C" fn librender_rope_from_buffer(
+    buf: *const librender_bytecode_buffer,
+) -> *mut librender_rope {
+    match Rope::from_program(buffer_bytes(buf)) {
+        Ok(rope) => Box::into_raw(Box::new(rope)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// # Safety
+///
+/// `rope` must be null or a rope from this module that is not used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn librender_rope_free(rope: *mut librender_rope) {
+    if !rope.is_null() {
+        drop(Box::from_raw(rope));
+    }
+}
+
+/// # Safety
+///
+/// `rope` must be null or a live rope from this module.
+#[no_mangle]
+pub unsafe extern "C" fn librender_rope_size(rope: *const librender_rope) -> size_t {
+    match rope.as_ref() {
+        Some(rope) => rope.len() as size_t,
+        None => 0,
+    }
+}
+
+/// # Safety
+///
+/// `rope` must be null or a live rope from this module.
+#[no_mangle]
+pub unsafe extern "C" fn librender_rope_count(rope: *const librender_rope) -> size_t {
+    match rope.as_ref() {
+        Some(rope) => rope.instruction_count() as size_t,
+        None => 0,
+    }
+}
+
+/// Inserts the program in `buf`, typically written with the `librender_*`
+/// emitters, before instruction `index`. Returns 0, or -1 if `index` is out
+/// of range or the program is not well-formed.
+///
+/// # Safety
+///
+/// `rope` must be null or a live rope from this module, and `buf` null
+/// or a valid buffer.
+#[no_mangle]
+pub unsafe extern "C" fn librender_rope_insert(
+    rope: *mut librender_rope,
+    index: size_t,
+    buf: *const librender_bytecode_buffer,
+) -> libc::c_int {
+    match rope.as_mut() {
+        Some(rope) => match rope.insert(index as usize, buffer_bytes(buf)) {
+            Ok(()) => 0,
+            Err(_) => -1,
+        },
+        None => -1,
+    }
+}
+
+/// Removes instructions `start..end`. Returns 0, or -1 if the range is out
+/// of bounds.
+///
+/// # Safety
+///
+/// `rope` must be null or a live rope from this module.
+#[no_mangle]
+pub unsafe extern "C" fn librender_rope_remove(
+    rope: *mut librender_rope,
+    start: size_t,
+    end: size_t,
+) -> libc::c_int {
+    match rope.as_mut() {
+        Some(rope) => match rope.remove(start as usize..end as usize) {
+            Ok(_) => 0,
+            Err(_) => -1,
+        },
+        None => -1,
+    }
+}
+
+/// Copies the program into a new buffer the caller frees.
+///
+/// # Safety
+///
+/// `rope` must be null or a live rope from this module.
+#[no_mangle]
+pub unsafe extern "C" fn librender_rope_flatten(
+    rope: *const librender_rope,
+) -> *mut librender_bytecode_buffer {
+    match rope.as_ref() {
+        Some(rope) => buffer_from_bytes(&rope.flatten()),
+        None => std::ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{decode, encode};
+
+    /// Deterministic instructions of varying size, some near a payload.
+    struct Source(u64);
+
+    impl Source {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn instruction(&mut self) -> Instruction {
+            let text = vec![b'a' + self.below(26) as u8; self.below(256)];
+
+            match self.below(4) {
+                0 => Instruction::CreateElement(b"div".to_vec()),
+                1 => Instruction::AppendChild,
+                2 => Instruction::SetAttribute(b"class".to_vec(), text),
+                _ => Instruction::TextNode(text),
+            }
+        }
+
+        fn instructions(&mut self, n: usize) -> Vec<Instruction> {
+            (0..n).map(|_| self.instruction()).collect()
+        }
+    }
+
+    fn chunk_sizes(rope: &Rope) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        for_each_chunk(&rope.root, &mut |chunk| sizes.push(chunk.len()));
+        sizes
+    }
+
+    /// Checks `rope` against `expected` and that no two adjacent chunks
+    /// would fit in one.
+    fn check(rope: &Rope, expected: &[Instruction]) {
+        assert_eq!(rope.flatten(), encode(expected));
+        assert_eq!(rope.instruction_count(), expected.len());
+
+        for pair in chunk_sizes(rope).windows(2) {
+            assert!(
+                pair[0] + pair[1] > CHUNK_SIZE,
+                "chunks {:?} fit in one",
+                pair
+            );
+        }
+    }
+
+    #[test]
+    fn edits_like_vec_splice() {
+        let mut source = Source(0x2545f4914f6cdd1d);
+        let mut expected = source.instructions(2000);
+        let mut rope = Rope::from_program(&encode(&expected)).unwrap();
+
+        check(&rope, &expected);
+
+        for step in 0..300 {
+            let count = expected.len();
+
+            if step % 2 == 0 {
+                let index = source.below(count + 1);
+                let n = source.below(40);
+                let inserted = source.instructions(n);
+
+                rope.insert(index, &encode(&inserted)).unwrap();
+                expected.splice(index..index, inserted);
+            } else {
+                let start = source.below(count + 1);
+                let end = start + source.below(count - start + 1).min(60);
+                let removed: Vec<_> = expected.splice(start..end, []).collect();
+
+                assert_eq!(rope.remove(start..end).unwrap(), encode(&removed));
+            }
+
+            check(&rope, &expected);
+        }
+
+        for (index, instruction) in expected.iter().enumerate().step_by(97) {
+            assert_eq!(rope.get(index).as_ref(), Some(instruction));
+        }
+    }
+
+    #[test]
+    fn coalesces_small_inserts() {
+        let mut rope = Rope::new();
+
+        for i in 0..1000 {
+            rope.insert(i / 2, b"\x01\x03div").unwrap();
+        }
+
+        let sizes = chunk_sizes(&rope);
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes.iter().sum::<usize>(), 5000);
+
+        rope.remove(1..999).unwrap();
+        assert_eq!(chunk_sizes(&rope), [10]);
+    }
+
+    #[test]
+    fn writes_partial_instructions() {
+        let mut source = Source(7);
+        let program = encode(&source.instructions(200));
+        let mut rope = Rope::new();
+
+        for chunk in program.chunks(7) {
+            rope.write_all(chunk).unwrap();
+        }
+
+        assert!(rope.is_complete());
+        assert_eq!(rope.flatten(), program);
+        assert_eq!(decode(&rope.flatten()).unwrap().len(), 200);
+        assert_eq!(
+            rope.write(b"\xee").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(rope.insert(201, b""), Err(RopeError::OutOfRange(201)));
+    }
+
+    #[test]
+    fn edits_through_the_c_functions() {
+        let program = b"\x01\x02ul\x01\x02li\x03";
+
+        unsafe {
+            let buf = buffer_from_bytes(program);
+            let rope = librender_rope_from_buffer(buf);
+
+            assert_eq!(librender_rope_count(rope), 3);
+            assert_eq!(librender_rope_insert(rope, 3, buf), 0);
+            assert_eq!(librender_rope_insert(rope, 7, buf), -1);
+            assert_eq!(librender_rope_remove(rope, 0, 1), 0);
+            assert_eq!(librender_rope_remove(rope, 4, 6), -1);
+            assert_eq!(librender_rope_size(rope), 14);
+
+            let flat = librender_rope_flatten(rope);
+            assert_eq!(
+                buffer_bytes(flat),
+                b"\x01\x02li\x03\x01\x02ul\x01\x02li\x03"
+            );
+
+            crate::librender_free_buffer(flat);
+            crate::librender_free_buffer(buf);
+            librender_rope_free(rope);
+        }
+    }
+}
@@ -0,0 +1,401 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Text dumps of rendered trees and snapshot testing.
+//!
+//! [`dump`] prints one node per line, children indented by two spaces:
+//!
+//! ```text
+//! div class="card" style:color="red" on:click
+//!   h1
+//!     "Title"
+//!   p
+//! ```
+//!
+//! Attributes, styles and listeners are sorted by name so that programs
+//! that render the same tree dump the same text. Listener IDs are left out
+//! since they depend on registration order.
+//!
+//! [`assert_renders!`](crate::assert_renders) compares a program against an
+//! inline dump, and [`assert_snapshot!`](crate::assert_snapshot) against a
+//! file in a `snapshots` directory next to the test. Run tests with
+//! `LIBRENDER_UPDATE_SNAPSHOTS=1` to write the current output to the
+//! snapshot files; otherwise a mismatch writes it to `<name>.snap.new`
+//! beside the snapshot and fails with a diff.
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::interp::{interpret, InterpretError, Tree, VNode};
+
+/// Environment variable that makes [`check_snapshot`] overwrite snapshots.
+pub const UPDATE_VAR: &str = "LIBRENDER_UPDATE_SNAPSHOTS";
+
+pub fn dump(tree: &Tree) -> String {
+    dump_vnodes(&tree.render())
+}
+
+pub fn dump_vnodes(nodes: &[VNode]) -> String {
+    let mut out = String::new();
+    write_vnodes(&mut out, nodes, 0);
+    out
+}
+
+pub fn dump_program(program: &[u8]) -> Result<String, InterpretError> {
+    Ok(dump(&interpret(program)?))
+}
+
+fn write_vnodes(out: &mut String, nodes: &[VNode], depth: usize) {
+    for node in nodes {
+        let indent = "  ".repeat(depth);
+
+        match node {
+            VNode::Text(text) => {
+                let _ = writeln!(out, "{}{:?}", indent, text);
+            }
+            VNode::Element {
+                tag,
+                attributes,
+                styles,
+                listeners,
+                children,
+            } => {
+                let mut styles: Vec<_> = styles.iter().collect();
+                let mut events: Vec<_> = listeners.iter().map(|(event, _)| event).collect();
+
+                styles.sort();
+                events.sort();
+
+                out.push_str(&indent);
+                out.push_str(tag);
+
+                for (name, value) in attributes {
+                    let _ = write!(out, " {}={:?}", name, value);
+                }
+
+                for (name, value) in styles {
+                    let _ = write!(out, " style:{}={:?}", name, value);
+                }
+
+                for event in events {
+                    let _ = write!(out, " on:{}", event);
+                }
+
+                out.push('\n');
+                write_vnodes(out, children, depth + 1);
+            }
+        }
+    }
+}
+
+/// Strips the indentation common to all non-blank lines, and leading and
+/// trailing blank lines, so that expected dumps can be written as indented
+/// string literals.
+pub fn dedent(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let start = lines.iter().position(|l| !l.trim().is_empty());
+    let end = lines.iter().rposition(|l| !l.trim().is_empty());
+
+    let (Some(start), Some(end)) = (start, end) else {
+        return String::new();
+    };
+
+    let lines = &lines[start..=end];
+    let margin = lines
+        .iter()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.len() - l.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    let mut out = String::new();
+
+    for line in lines {
+        out.push_str(line.get(margin..).unwrap_or("").trim_end());
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Longest common subsequence diff of two line lists, after trimming the
+/// common prefix and suffix. Each line is paired with `' '`, `'-'` or `'+'`.
+/// Large middles fall back to replacing the whole range.
+pub fn diff_lines(a: &[String], b: &[String]) -> Vec<(char, String)> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+
+    let (ma, mb) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let mut out: Vec<(char, String)> = a[..prefix].iter().map(|l| (' ', l.clone())).collect();
+
+    if ma.len().saturating_mul(mb.len()) > 1 << 24 {
+        out.extend(ma.iter().map(|l| ('-', l.clone())));
+        out.extend(mb.iter().map(|l| ('+', l.clone())));
+    } else {
+        let mut table = vec![vec![0u32; mb.len() + 1]; ma.len() + 1];
+
+        for i in (0..ma.len()).rev() {
+            for j in (0..mb.len()).rev() {
+                table[i][j] = if ma[i] == mb[j] {
+                    table[i + 1][j + 1] + 1
+                } else {
+                    table[i + 1][j].max(table[i][j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+
+        while i < ma.len() || j < mb.len() {
+            if i < ma.len() && j < mb.len() && ma[i] == mb[j] {
+                out.push((' ', ma[i].clone()));
+                i += 1;
+                j += 1;
+            } else if j < mb.len() && (i == ma.len() || table[i][j + 1] >= table[i + 1][j]) {
+                out.push(('+', mb[j].clone()));
+                j += 1;
+            } else {
+                out.push(('-', ma[i].clone()));
+                i += 1;
+            }
+        }
+    }
+
+    out.extend(a[a.len() - suffix..].iter().map(|l| (' ', l.clone())));
+    out
+}
+
+/// Formats a diff from `expected` to `actual`, or `None` if they are equal.
+pub fn text_diff(expected: &str, actual: &str) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+
+    let lines = |s: &str| s.lines().map(str::to_string).collect::<Vec<_>>();
+    let mut out = String::from("--- expected\n+++ actual\n");
+
+    for (sign, line) in diff_lines(&lines(expected), &lines(actual)) {
+        let _ = writeln!(out, "{} {}", sign, line);
+    }
+
+    Some(out)
+}
+
+/// Compares the dump of `program` with `expected`, which is dedented first.
+pub fn check_renders(program: &[u8], expected: &str) -> Result<(), String> {
+    let actual = dump_program(program).map_err(|err| format!("program failed: {}", err))?;
+
+    match text_diff(&dedent(expected), &actual) {
+        None => Ok(()),
+        Some(diff) => Err(format!("rendered tree does not match\n{}", diff)),
+    }
+}
+
+/// Path of snapshot `name` for a test in `file`, as given by `file!()`.
+/// `file!()` is relative to the workspace root, which may be an ancestor of
+/// `manifest_dir`.
+pub fn snapshot_path(manifest_dir: &str, file: &str, name: &str) -> PathBuf {
+    let file = Path::new(file);
+    let source = Path::new(manifest_dir)
+        .ancestors()
+        .map(|dir| dir.join(file))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| Path::new(manifest_dir).join(file));
+
+    source
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join("snapshots")
+        .join(format!("{}.snap", name))
+}
+
+/// Compares `actual` with the snapshot at `path`. With [`UPDATE_VAR`] set
+/// to `1` the snapshot is overwritten instead.
+pub fn check_snapshot(path: &Path, actual: &str) -> Result<(), String> {
+    let update = std::env::var(UPDATE_VAR).is_ok_and(|v| v == "1");
+    let pending = path.with_extension("snap.new");
+    let write = |path: &Path| {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        std::fs::write(path, actual)
+    };
+
+    if update {
+        let _ = std::fs::remove_file(&pending);
+        return write(path).map_err(|err| format!("{}: {}", path.display(), err));
+    }
+
+    let expected = match std::fs::read_to_string(path) {
+        Ok(expected) => expected,
+        Err(_) => {
+            let _ = write(&pending);
+            return Err(format!(
+                "missing snapshot {}, wrote {}; rerun with {}=1 to accept it",
+                path.display(),
+                pending.display(),
+                UPDATE_VAR
+            ));
+        }
+    };
+
+    match text_diff(&expected, actual) {
+        None => {
+            let _ = std::fs::remove_file(&pending);
+            Ok(())
+        }
+        Some(diff) => {
+            let _ = write(&pending);
+            Err(format!(
+                "snapshot {} does not match\n{}rerun with {}=1 to accept it",
+                path.display(),
+                diff,
+                UPDATE_VAR
+            ))
+        }
+    }
+}
+
+/// Asserts that a program renders the given tree dump.
+///
+/// ```
+/// use librender::asm::assemble;
+/// use librender::assert_renders;
+///
+/// let program = assemble(
+///     r#"
+///     create_element "div"
+///     set_attribute "class" "card"
+///     text_node "hello"
+///     append_child
+///     "#,
+/// )
+/// .unwrap();
+///
+/// assert_renders!(program, r#"
+///     div class="card"
+///       "hello"
+/// "#);
+/// ```
+#[macro_export]
+macro_rules! assert_renders {
+    ($program:expr, $expected:expr $(,)?) => {
+        if let Err(message) = $crate::snapshot::check_renders(&$program[..], $expected) {
+            panic!("{}", message);
+        }
+    };
+}
+
+/// Asserts that a program renders the tree stored in snapshot `name`.
+#[macro_export]
+macro_rules! assert_snapshot {
+    ($name:expr, $program:expr $(,)?) => {{
+        let path = $crate::snapshot::snapshot_path(env!("CARGO_MANIFEST_DIR"), file!(), $name);
+        let actual = match $crate::snapshot::dump_program(&$program[..]) {
+            Ok(actual) => actual,
+            Err(err) => panic!("program failed: {}", err),
+        };
+
+        if let Err(message) = $crate::snapshot::check_snapshot(&path, &actual) {
+            panic!("{}", message);
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    /// A fresh directory for snapshots written by test `name`.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("librender-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn dedents_indented_literals() {
+        assert_eq!(dedent("\n    div\n      p\n\n  "), "div\n  p\n");
+        assert_eq!(dedent(" \n "), "");
+    }
+
+    #[test]
+    fn diffs_changed_lines() {
+        let diff = text_diff("div\n  p\n  \"a\"\n", "div\n  span\n  \"a\"\n").unwrap();
+
+        assert_eq!(
+            diff,
+            "--- expected\n+++ actual\n  div\n+   span\n-   p\n    \"a\"\n"
+        );
+        assert_eq!(text_diff("div\n", "div\n"), None);
+    }
+
+    #[test]
+    fn check_snapshot_writes_pending_output_until_accepted() {
+        let dir = scratch("pending");
+        let path = dir.join("snapshots").join("card.snap");
+        let pending = path.with_extension("snap.new");
+
+        let err = check_snapshot(&path, "div\n").unwrap_err();
+        assert!(err.starts_with("missing snapshot"), "{}", err);
+        assert_eq!(std::fs::read_to_string(&pending).unwrap(), "div\n");
+
+        std::fs::rename(&pending, &path).unwrap();
+        assert_eq!(check_snapshot(&path, "div\n"), Ok(()));
+
+        let err = check_snapshot(&path, "p\n").unwrap_err();
+        assert!(err.contains("+ p\n- div\n"), "{}", err);
+        assert_eq!(std::fs::read_to_string(&pending).unwrap(), "p\n");
+
+        // A match removes the stale pending output.
+        assert_eq!(check_snapshot(&path, "div\n"), Ok(()));
+        assert!(!pending.exists());
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn snapshot_paths_sit_next_to_the_test() {
+        let path = snapshot_path(env!("CARGO_MANIFEST_DIR"), file!(), "card");
+
+        assert_eq!(
+            path,
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("src/snapshots/card.snap")
+        );
+    }
+
+    #[test]
+    fn renders_a_card() {
+        let program = assemble(
+            r#"
+            create_element "div"
+            set_attribute "class" "card"
+            style "color" "red"
+            event_listener "click"
+            create_element "h1"
+            text_node "Title"
+            append_child
+            append_child
+            create_element "p"
+            append_child
+            "#,
+        )
+        .unwrap();
+
+        crate::assert_renders!(
+            program,
+            r#"
+            div class="card" style:color="red" on:click
+              h1
+                "Title"
+              p
+            "#
+        );
+    }
+}
@@ -0,0 +1,550 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+//! Size attribution for programs.
+//!
+//! Every instruction is charged to one node: the node it creates, the
+//! element it modifies, or the node it attaches. Subtree sizes add up the
+//! charges of each node and its descendants in the rendered tree, so they
+//! answer "how many bytes would go away without this subtree".
+
+use std::fmt::{self, Write};
+use std::ops::Range;
+
+use crate::decode::{decode, opcode_mnemonic, Instruction};
+use crate::interp::{InterpretError, Interpreter, NodeId, NodeKind, Tree};
+
+/// Number of subtrees kept in [`Stats::largest_subtrees`].
+pub const LARGEST_SUBTREES: usize = 10;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpcodeStats {
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub count: usize,
+    /// Encoded bytes, including opcodes and length prefixes.
+    pub bytes: usize,
+    pub payload_bytes: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubtreeStats {
+    pub node: NodeId,
+    /// Tag name, `#text` or `#slot`.
+    pub label: String,
+    /// Offset of the instruction that created the node.
+    pub offset: usize,
+    pub depth: usize,
+    pub nodes: usize,
+    pub bytes: usize,
+}
+
+/// A named byte range of a program, such as the instructions emitted by one
+/// component.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Component {
+    pub name: String,
+    pub range: Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ComponentStats {
+    pub name: String,
+    pub instructions: usize,
+    /// Bytes of instructions that start inside the component's range.
+    pub bytes: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub bytes: usize,
+    pub instructions: usize,
+    /// Opcodes that occur in the program, in opcode order.
+    pub opcodes: Vec<OpcodeStats>,
+    /// Bytes of string payloads.
+    pub payload_bytes: usize,
+    /// Opcode and length prefix bytes.
+    pub overhead_bytes: usize,
+    pub node_count: usize,
+    pub element_count: usize,
+    pub text_count: usize,
+    /// Number of levels in the rendered tree, roots included.
+    pub max_depth: usize,
+    pub listener_count: usize,
+    /// Rendered subtrees with the most bytes, largest first.
+    pub largest_subtrees: Vec<SubtreeStats>,
+    /// Bytes charged to no node, such as `nop`s and instructions on removed
+    /// or orphaned nodes.
+    pub unattributed_bytes: usize,
+    pub components: Vec<ComponentStats>,
+}
+
+pub fn stats(program: &[u8]) -> Result<Stats, InterpretError> {
+    stats_with_components(program, &[])
+}
+
+/// Like [`stats`], additionally attributing bytes to `components`. An
+/// instruction counts towards every component whose range contains its
+/// offset, so nested components include their children.
+pub fn stats_with_components(
+    program: &[u8],
+    components: &[Component],
+) -> Result<Stats, InterpretError> {
+    let instructions = decode(program)?;
+    let mut interpreter = Interpreter::new();
+    let mut charges: Vec<usize> = Vec::new();
+    let mut opcodes: Vec<Option<OpcodeStats>> = vec![None; 16];
+    let mut component_stats: Vec<ComponentStats> = components
+        .iter()
+        .map(|c| ComponentStats {
+            name: c.name.clone(),
+            instructions: 0,
+            bytes: 0,
+        })
+        .collect();
+
+    let mut stats = Stats {
+        bytes: program.len(),
+        instructions: instructions.len(),
+        ..Stats::default()
+    };
+
+    let mut offset = 0;
+
+    for instruction in &instructions {
+        let len = instruction.encoded_len();
+        let payload: usize = instruction.payloads().iter().map(|p| p.len()).sum();
+        let opcode = instruction.opcode();
+
+        let entry = opcodes[opcode as usize].get_or_insert_with(|| OpcodeStats {
+            opcode,
+            mnemonic: opcode_mnemonic(opcode).unwrap_or("unknown"),
+            count: 0,
+            bytes: 0,
+            payload_bytes: 0,
+        });
+
+        entry.count += 1;
+        entry.bytes += len;
+        entry.payload_bytes += payload;
+        stats.payload_bytes += payload;
+
+        for (component, stats) in components.iter().zip(&mut component_stats) {
+            if component.range.contains(&offset) {
+                stats.instructions += 1;
+                stats.bytes += len;
+            }
+        }
+
+        let owner = match instruction {
+            Instruction::Nop => None,
+            Instruction::SetAttribute(..)
+            | Instruction::RemoveAttribute(_)
+            | Instruction::Style(..)
+            | Instruction::EventListener(_) => interpreter.target(),
+            _ if instruction.creates_node() => Some(interpreter.tree().nodes.len()),
+            _ => interpreter.stack().last().copied(),
+        };
+
+        interpreter.step(instruction, offset)?;
+        offset += len;
+
+        charges.resize(interpreter.tree().nodes.len(), 0);
+
+        if let Some(node) = owner {
+            charges[node] += len;
+        }
+    }
+
+    let tree = interpreter.finish();
+    let mut subtrees = Vec::new();
+
+    stats.opcodes = opcodes.into_iter().flatten().collect();
+    stats.overhead_bytes = stats.bytes - stats.payload_bytes;
+    stats.listener_count = tree.listener_count;
+    stats.components = component_stats;
+
+    for &root in &tree.roots {
+        measure(&tree, root, 1, &charges, &mut stats, &mut subtrees);
+    }
+
+    stats.unattributed_bytes = stats.bytes - subtrees_total(&tree, &subtrees);
+    subtrees.sort_by(|a: &SubtreeStats, b| b.bytes.cmp(&a.bytes).then(a.node.cmp(&b.node)));
+    subtrees.truncate(LARGEST_SUBTREES);
+    stats.largest_subtrees = subtrees;
+
+    Ok(stats)
+}
+
+/// Walks the rendered tree below `id`, returning the node count and bytes of
+/// the subtree.
+fn measure(
+    tree: &Tree,
+    id: NodeId,
+    depth: usize,
+    charges: &[usize],
+    stats: &mut Stats,
+    subtrees: &mut Vec<SubtreeStats>,
+) -> (usize, usize) {
+    let node = &tree.nodes[id];
+    let (mut nodes, mut bytes) = (1, charges.get(id).copied().unwrap_or(0));
+
+    let label = match &node.kind {
+        NodeKind::Element { tag, .. } => {
+            stats.element_count += 1;
+            tag.clone()
+        }
+        NodeKind::Text(_) => {
+            stats.text_count += 1;
+            "#text".to_string()
+        }
+        NodeKind::Slot(_) => "#slot".to_string(),
+    };
+
+    stats.node_count += 1;
+    stats.max_depth = stats.max_depth.max(depth);
+
+    for &child in &node.children {
+        let (n, b) = measure(tree, child, depth + 1, charges, stats, subtrees);
+        nodes += n;
+        bytes += b;
+    }
+
+    subtrees.push(SubtreeStats {
+        node: id,
+        label,
+        offset: node.offset,
+        depth: depth - 1,
+        nodes,
+        bytes,
+    });
+
+    (nodes, bytes)
+}
+
+fn subtrees_total(tree: &Tree, subtrees: &[SubtreeStats]) -> usize {
+    subtrees
+        .iter()
+        .filter(|s| tree.roots.contains(&s.node))
+        .map(|s| s.bytes)
+        .sum()
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+}
+
+impl Stats {
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+
+        let _ = write!(
+            out,
+            "{{\"bytes\":{},\"instructions\":{},\"payload_bytes\":{},\"overhead_bytes\":{},\
+             \"node_count\":{},\"element_count\":{},\"text_count\":{},\"max_depth\":{},\
+             \"listener_count\":{},\"unattributed_bytes\":{},\"opcodes\":[",
+            self.bytes,
+            self.instructions,
+            self.payload_bytes,
+            self.overhead_bytes,
+            self.node_count,
+            self.element_count,
+            self.text_count,
+            self.max_depth,
+            self.listener_count,
+            self.unattributed_bytes,
+        );
+
+        for (i, op) in self.opcodes.iter().enumerate() {
+            let _ = write!(
+                out,
+                "{}{{\"opcode\":{},\"mnemonic\":\"{}\",\"count\":{},\"bytes\":{},\"payload_bytes\":{}}}",
+                if i == 0 { "" } else { "," },
+                op.opcode,
+                op.mnemonic,
+                op.count,
+                op.bytes,
+                op.payload_bytes
+            );
+        }
+
+        out.push_str("],\"largest_subtrees\":[");
+
+        for (i, subtree) in self.largest_subtrees.iter().enumerate() {
+            let _ = write!(
+                out,
+                "{}{{\"node\":{},\"label\":",
+                if i == 0 { "" } else { "," },
+                subtree.node
+            );
+            json_string(&mut out, &subtree.label);
+            let _ = write!(
+                out,
+                ",\"offset\":{},\"depth\":{},\"nodes\":{},\"bytes\":{}}}",
+                subtree.offset, subtree.depth, subtree.nodes, subtree.bytes
+            );
+        }
+
+        out.push_str("],\"components\":[");
+
+        for (i, component) in self.components.iter().enumerate() {
+            out.push_str(if i == 0 { "{\"name\":" } else { ",{\"name\":" });
+            json_string(&mut out, &component.name);
+            let _ = write!(
+                out,
+                ",\"instructions\":{},\"bytes\":{}}}",
+                component.instructions, component.bytes
+            );
+        }
+
+        out.push_str("]}");
+        out
+    }
+}
+
+fn percent(part: usize, whole: usize) -> f64 {
+    match whole {
+        0 => 0.0,
+        _ => 100.0 * part as f64 / whole as f64,
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "bytes           {}", self.bytes)?;
+        writeln!(f, "instructions    {}", self.instructions)?;
+        writeln!(
+            f,
+            "payload         {} ({:.1}%)",
+            self.payload_bytes,
+            percent(self.payload_bytes, self.bytes)
+        )?;
+        writeln!(
+            f,
+            "overhead        {} ({:.1}%)",
+            self.overhead_bytes,
+            percent(self.overhead_bytes, self.bytes)
+        )?;
+        writeln!(
+            f,
+            "nodes           {} ({} elements, {} text)",
+            self.node_count, self.element_count, self.text_count
+        )?;
+        writeln!(f, "max depth       {}", self.max_depth)?;
+        writeln!(f, "listeners       {}", self.listener_count)?;
+        writeln!(f, "unattributed    {}", self.unattributed_bytes)?;
+
+        writeln!(
+            f,
+            "\n{:<18} {:>8} {:>10} {:>10}",
+            "opcode", "count", "bytes", "payload"
+        )?;
+
+        for op in &self.opcodes {
+            writeln!(
+                f,
+                "{:<18} {:>8} {:>10} {:>10}",
+                op.mnemonic, op.count, op.bytes, op.payload_bytes
+            )?;
+        }
+
+        writeln!(
+            f,
+            "\n{:<18} {:>8} {:>8} {:>10} {:>7}",
+            "subtree", "offset", "nodes", "bytes", "share"
+        )?;
+
+        for s in &self.largest_subtrees {
+            let label = format!("{}{}", "  ".repeat(s.depth.min(8)), s.label);
+
+            writeln!(
+                f,
+                "{:<18} {:>#8x} {:>8} {:>10} {:>6.1}%",
+                label,
+                s.offset,
+                s.nodes,
+                s.bytes,
+                percent(s.bytes, self.bytes)
+            )?;
+        }
+
+        if !self.components.is_empty() {
+            writeln!(
+                f,
+                "\n{:<18} {:>8} {:>10} {:>7}",
+                "component", "instrs", "bytes", "share"
+            )?;
+
+            for c in &self.components {
+                writeln!(
+                    f,
+                    "{:<18} {:>8} {:>10} {:>6.1}%",
+                    c.name,
+                    c.instructions,
+                    c.bytes,
+                    percent(c.bytes, self.bytes)
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    const LIST: &str = r#"
+        create_element "ul"
+        set_attribute "id" "x"
+        create_element "li"
+        text_node "hello"
+        append_child
+        append_child
+        create_element "li"
+        append_child
+        nop
+    "#;
+
+    fn list() -> Stats {
+        let components = [
+            Component {
+                name: "List".to_string(),
+                range: 0..29,
+            },
+            Component {
+                name: "Item \"a\"".to_string(),
+                range: 10..23,
+            },
+        ];
+
+        stats_with_components(&assemble(LIST).unwrap(), &components).unwrap()
+    }
+
+    #[test]
+    fn counts_opcodes() {
+        let stats = list();
+        let histogram: Vec<_> = stats
+            .opcodes
+            .iter()
+            .map(|op| (op.mnemonic, op.count, op.bytes, op.payload_bytes))
+            .collect();
+
+        assert_eq!(
+            histogram,
+            [
+                ("nop", 1, 1, 0),
+                ("create_element", 3, 12, 6),
+                ("set_attribute", 1, 6, 3),
+                ("append_child", 3, 3, 0),
+                ("text_node", 1, 7, 5),
+            ]
+        );
+        assert_eq!((stats.bytes, stats.instructions), (29, 9));
+    }
+
+    #[test]
+    fn splits_payload_from_overhead() {
+        let stats = list();
+
+        assert_eq!(stats.payload_bytes, 14);
+        assert_eq!(stats.overhead_bytes, 15);
+        assert_eq!(
+            stats.payload_bytes,
+            stats.opcodes.iter().map(|op| op.payload_bytes).sum()
+        );
+    }
+
+    #[test]
+    fn orders_largest_subtrees() {
+        let stats = list();
+        let subtrees: Vec<_> = stats
+            .largest_subtrees
+            .iter()
+            .map(|s| (s.node, s.label.as_str(), s.depth, s.nodes, s.bytes))
+            .collect();
+
+        assert_eq!(
+            subtrees,
+            [
+                (0, "ul", 0, 4, 28),
+                (1, "li", 1, 2, 13),
+                (2, "#text", 2, 1, 8),
+                (3, "li", 1, 1, 5),
+            ]
+        );
+        assert_eq!(stats.unattributed_bytes, 1);
+        assert_eq!((stats.node_count, stats.max_depth), (4, 3));
+
+        let mut source = "create_element \"div\"\n".to_string();
+        source += &"text_node \"x\"\nappend_child\n".repeat(LARGEST_SUBTREES + 2);
+
+        let stats = stats_with_components(&assemble(&source).unwrap(), &[]).unwrap();
+        let nodes: Vec<_> = stats.largest_subtrees.iter().map(|s| s.node).collect();
+
+        // Equal sizes keep creation order.
+        assert_eq!(nodes, (0..LARGEST_SUBTREES).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn attributes_bytes_to_components() {
+        let components: Vec<_> = list()
+            .components
+            .into_iter()
+            .map(|c| (c.name, c.instructions, c.bytes))
+            .collect();
+
+        assert_eq!(
+            components,
+            [
+                ("List".to_string(), 9, 29),
+                ("Item \"a\"".to_string(), 4, 13)
+            ]
+        );
+        assert!(stats(&assemble(LIST).unwrap())
+            .unwrap()
+            .components
+            .is_empty());
+    }
+
+    #[test]
+    fn exports_json() {
+        assert_eq!(
+            list().to_json(),
+            concat!(
+                r#"{"bytes":29,"instructions":9,"payload_bytes":14,"overhead_bytes":15,"#,
+                r#""node_count":4,"element_count":3,"text_count":1,"max_depth":3,"#,
+                r#""listener_count":0,"unattributed_bytes":1,"opcodes":["#,
+                r#"{"opcode":0,"mnemonic":"nop","count":1,"bytes":1,"payload_bytes":0},"#,
+                r#"{"opcode":1,"mnemonic":"create_element","count":3,"bytes":12,"payload_bytes":6},"#,
+                r#"{"opcode":2,"mnemonic":"set_attribute","count":1,"bytes":6,"payload_bytes":3},"#,
+                r#"{"opcode":3,"mnemonic":"append_child","count":3,"bytes":3,"payload_bytes":0},"#,
+                r#"{"opcode":6,"mnemonic":"text_node","count":1,"bytes":7,"payload_bytes":5}],"#,
+                r#""largest_subtrees":["#,
+                r##"{"node":0,"label":"ul","offset":0,"depth":0,"nodes":4,"bytes":28},"##,
+                r##"{"node":1,"label":"li","offset":10,"depth":1,"nodes":2,"bytes":13},"##,
+                r##"{"node":2,"label":"#text","offset":14,"depth":2,"nodes":1,"bytes":8},"##,
+                r##"{"node":3,"label":"li","offset":23,"depth":1,"nodes":1,"bytes":5}],"##,
+                r#""components":[{"name":"List","instructions":9,"bytes":29},"#,
+                r#"{"name":"Item \"a\"","instructions":4,"bytes":13}]}"#,
+            )
+        );
+    }
+}
@@ -41,15 +41,160 @@ enum {
   OPCODE_STYLE = 0x09,
   OPCODE_EVENT_LISTENER = 0x0A,
   OPCODE_APPEND_SIBLING = 0x0B,
+  OPCODE_PLACEHOLDER = 0x0C,
 };
 
+// How deep librender_checkpoint nests.
+enum { LIBRENDER_MAX_CHECKPOINTS = 32 };
+
+// Receives `count` bytes and returns how many of them were consumed, or 0
+// on failure. Partial writes are retried with the remaining bytes.
+typedef size_t (*librender_write_fn)(const uint8_t* bytes, size_t count,
+                                     void* user_data);
+
 struct librender_bytecode_buffer {
   uint8_t* buffer;
   size_t size;
   size_t capacity;
   int is_locked;
+  librender_write_fn sink;
+  void* sink_data;
+  size_t sink_threshold;
+  int sink_failed;
+  size_t checkpoints;
+  size_t checkpoint_sizes[LIBRENDER_MAX_CHECKPOINTS];
+  int is_fixed;
+  int overflowed;
 };
 
+int librender_flush(struct librender_bytecode_buffer* buf);
+
+// The functions below are implemented by the Rust library, so using them
+// requires linking against librender.
+
+// Frames the program in `buf` into a new buffer, cutting at subtree
+// boundaries every `block_size` bytes (or the default if 0). Returns NULL if
+// the program does not interpret.
+struct librender_bytecode_buffer* librender_frame_buffer(
+    const struct librender_bytecode_buffer* buf, size_t block_size);
+
+// Compares two programs by the tree they render rather than their bytes.
+// Returns 1 if they are equal, 0 if not and -1 if either does not
+// interpret.
+int librender_semantically_eq(const struct librender_bytecode_buffer* a,
+                              const struct librender_bytecode_buffer* b);
+
+// Computes the delta that turns the program in `prev` into the one in
+// `next`, into a new buffer. Returns NULL if either does not decode.
+struct librender_bytecode_buffer* librender_delta_buffer(
+    const struct librender_bytecode_buffer* prev,
+    const struct librender_bytecode_buffer* next);
+
+// Applies the delta in `delta` to `prev`, into a new buffer. Returns NULL
+// if the delta is malformed or was not computed from `prev`.
+struct librender_bytecode_buffer* librender_apply_delta(
+    const struct librender_bytecode_buffer* prev,
+    const struct librender_bytecode_buffer* delta);
+
+// Edits a buffer an instruction at a time. The cursor sits before the
+// instruction at librender_cursor_index, or at the end. While a cursor
+// exists, the buffer must outlive it and only be edited through it.
+struct librender_cursor;
+
+// Returns NULL if `buf` does not hold a well-formed program.
+struct librender_cursor* librender_cursor_create(
+    struct librender_bytecode_buffer* buf);
+void librender_cursor_free(struct librender_cursor* cursor);
+size_t librender_cursor_offset(const struct librender_cursor* cursor);
+size_t librender_cursor_index(const struct librender_cursor* cursor);
+
+// The functions below return -1 at the end of the program, where the
+// instruction does not decode, or where the buffer refuses the edit.
+
+// Opcode of the current instruction.
+int librender_cursor_opcode(const struct librender_cursor* cursor);
+int librender_cursor_advance(struct librender_cursor* cursor);
+
+// Moves before instruction `n`, or to the end if `n` is the number of
+// instructions.
+int librender_cursor_seek(struct librender_cursor* cursor, size_t n);
+
+// Moves before the instruction that created node `id`.
+int librender_cursor_seek_node(struct librender_cursor* cursor, size_t id);
+
+// Inserts an instruction before the cursor and moves past it. Payloads the
+// opcode does not take are ignored.
+int librender_cursor_insert(struct librender_cursor* cursor, uint8_t opcode,
+                            const uint8_t* first, uint8_t first_length,
+                            const uint8_t* second, uint8_t second_length);
+int librender_cursor_delete(struct librender_cursor* cursor);
+
+// Replaces payload `index` of the current instruction.
+int librender_cursor_replace_payload(struct librender_cursor* cursor,
+                                     size_t index, const uint8_t* bytes,
+                                     uint8_t length);
+
+// A program kept as a balanced tree of chunks, so that inserting into the
+// middle of a large program does not shift everything after it. Edits
+// address whole instructions by index.
+typedef struct librender_rope librender_rope;
+
+librender_rope* librender_rope_create(void);
+
+// Returns NULL if `buf` does not hold a well-formed program.
+librender_rope* librender_rope_from_buffer(
+    const struct librender_bytecode_buffer* buf);
+void librender_rope_free(librender_rope* rope);
+
+// Size in bytes and number of instructions.
+size_t librender_rope_size(const librender_rope* rope);
+size_t librender_rope_count(const librender_rope* rope);
+
+// Inserts the program in `buf` before instruction `index`. Returns 0, or
+// -1 if `index` is out of range or the program is not well-formed.
+int librender_rope_insert(librender_rope* rope, size_t index,
+                          const struct librender_bytecode_buffer* buf);
+
+// Removes instructions `start` up to `end`. Returns 0, or -1 if the range
+// is out of bounds.
+int librender_rope_remove(librender_rope* rope, size_t start, size_t end);
+
+// Copies the program into a new buffer the caller frees.
+struct librender_bytecode_buffer* librender_rope_flatten(
+    const librender_rope* rope);
+
+// An immutable, reference-counted program that can be shared across
+// threads, unlike a locked buffer, which anyone can unlock.
+typedef struct librender_program librender_program;
+
+// Moves the program in `buf` into a new frozen program with a reference
+// count of 1, and frees `buf`.
+const librender_program* librender_freeze(
+    struct librender_bytecode_buffer* buf);
+
+// Adds a reference to `program` and returns it.
+const librender_program* librender_program_retain(
+    const librender_program* program);
+
+// Drops a reference to `program`, freeing it with the last one.
+void librender_program_release(const librender_program* program);
+
+// Bytes of `program`, valid while a reference to it is held.
+const uint8_t* librender_program_bytes(const librender_program* program);
+size_t librender_program_size(const librender_program* program);
+
+// Copies `program` into a new unlocked buffer the caller frees.
+struct librender_bytecode_buffer* librender_program_thaw(
+    const librender_program* program);
+
+// Mounts each of the `count` programs in `children` as the last child of
+// the node with the matching ID in `nodes`, in the program in `parent`.
+// Each child must run on its own and leave a single subtree. Returns a new
+// buffer the caller frees, or NULL if the programs cannot be merged.
+struct librender_bytecode_buffer* librender_merge_tree(
+    const struct librender_bytecode_buffer* parent, const size_t* nodes,
+    const struct librender_bytecode_buffer* const* children, size_t count);
+
 struct librender_bytecode_buffer* librender_create_buffer(
     size_t initial_capacity) {
   if (initial_capacity == 0) {
@@ -76,12 +221,20 @@ struct librender_bytecode_buffer* librender_create_buffer(
   buf->size = 0;
   buf->capacity = initial_capacity;
   buf->is_locked = 0;
+  buf->sink = NULL;
+  buf->sink_data = NULL;
+  buf->sink_threshold = 0;
+  buf->sink_failed = 0;
+  buf->checkpoints = 0;
+  memset(buf->checkpoint_sizes, 0, sizeof(buf->checkpoint_sizes));
+  buf->is_fixed = 0;
+  buf->overflowed = 0;
 
   return buf;
 }
 
 void librender_free_buffer(struct librender_bytecode_buffer* buf) {
-  if (!buf) {
+  if (!buf || buf->is_fixed) {
     return;
   }
 
@@ -92,6 +245,47 @@ void librender_free_buffer(struct librender_bytecode_buffer* buf) {
   free(buf);
 }
 
+// Prepares `buf` to write into the `capacity` bytes at `data` without ever
+// allocating. Both are owned by the caller, so librender_free_buffer leaves
+// the buffer alone. An instruction that does not fit is not written at all
+// and sets the flag read by librender_buffer_overflowed. Once it is set,
+// nothing more is written until the buffer is cleared or rolled back, so
+// smaller instructions cannot leave a hole where the first one was dropped.
+void librender_init_fixed_buffer(struct librender_bytecode_buffer* buf,
+                                 uint8_t* data, size_t capacity) {
+  if (!buf) {
+    return;
+  }
+
+  buf->buffer = data;
+  buf->size = 0;
+  buf->capacity = data ? capacity : 0;
+  buf->is_locked = 0;
+  buf->sink = NULL;
+  buf->sink_data = NULL;
+  buf->sink_threshold = 0;
+  buf->sink_failed = 0;
+  buf->checkpoints = 0;
+  memset(buf->checkpoint_sizes, 0, sizeof(buf->checkpoint_sizes));
+  buf->is_fixed = 1;
+  buf->overflowed = 0;
+}
+
+// Whether a fixed buffer has run out of capacity since it was initialized,
+// last cleared or rolled back.
+int librender_buffer_overflowed(const struct librender_bytecode_buffer* buf) {
+  if (!buf) {
+    return 0;
+  }
+
+  return buf->overflowed;
+}
+
+// librender_lock_buffer and librender_unlock_buffer are deprecated: use
+// librender_freeze to share a finished program. Locking only makes the
+// emitters and edits ignore the buffer, silently, until anyone holding it
+// unlocks it again. Both are kept for existing callers and will be removed
+// with the is_locked field.
 void librender_lock_buffer(struct librender_bytecode_buffer* buf) {
   if (!buf) {
     return;
@@ -116,33 +310,68 @@ int librender_is_buffer_locked(const struct librender_bytecode_buffer* buf) {
   return buf->is_locked;
 }
 
+// Makes room for at least `additional` more bytes, at least doubling the
+// capacity when it grows. Returns 0, or -1 if the buffer is locked or is a
+// fixed buffer that has overflowed or does not have enough room left.
+int librender_reserve(struct librender_bytecode_buffer* buf,
+                      size_t additional) {
+  if (!buf || buf->is_locked || buf->overflowed) {
+    return -1;
+  }
+
+  if (buf->capacity - buf->size >= additional) {
+    return 0;
+  }
+
+  if (buf->is_fixed) {
+    buf->overflowed = 1;
+    return -1;
+  }
+
+  size_t capacity = buf->capacity * 2;
+
+  if (capacity < buf->size + additional) {
+    capacity = buf->size + additional;
+  }
+
+  buf->buffer = (uint8_t*)realloc(buf->buffer, capacity * sizeof(uint8_t));
+
+  if (!buf->buffer) {
+    fprintf(stderr, "Failed to reallocate memory for buffer\n");
+    exit(EXIT_FAILURE);
+  }
+
+  buf->capacity = capacity;
+
+  return 0;
+}
+
 void librender_append_byte(struct librender_bytecode_buffer* buf,
                            uint8_t byte) {
-  if (!buf || buf->is_locked) {
+  if (librender_reserve(buf, 1) != 0) {
     return;
   }
 
-  if (buf->size >= buf->capacity) {
-    buf->capacity *= 2;
-    buf->buffer =
-        (uint8_t*)realloc(buf->buffer, buf->capacity * sizeof(uint8_t));
-    if (!buf->buffer) {
-      fprintf(stderr, "Failed to reallocate memory for buffer\n");
-      exit(EXIT_FAILURE);
-    }
-  }
-
   buf->buffer[buf->size++] = byte;
+
+  if (buf->sink && buf->sink_threshold && !buf->sink_failed &&
+      buf->size >= buf->sink_threshold) {
+    librender_flush(buf);
+  }
 }
 
 void librender_append_bytes(struct librender_bytecode_buffer* buf,
                             const uint8_t* bytes, size_t count) {
-  if (!buf || buf->is_locked || !bytes) {
+  if (!bytes || librender_reserve(buf, count) != 0) {
     return;
   }
 
-  for (size_t i = 0; i < count; i++) {
-    librender_append_byte(buf, bytes[i]);
+  memcpy(buf->buffer + buf->size, bytes, count);
+  buf->size += count;
+
+  if (buf->sink && buf->sink_threshold && !buf->sink_failed &&
+      buf->size >= buf->sink_threshold) {
+    librender_flush(buf);
   }
 }
 
@@ -152,6 +381,10 @@ void librender_create_element(struct librender_bytecode_buffer* buf,
     return;
   }
 
+  if (librender_reserve(buf, 2 + (size_t)tag_length) != 0) {
+    return;
+  }
+
   librender_append_byte(buf, OPCODE_CREATE_ELEMENT);
   librender_append_byte(buf, tag_length);
   librender_append_bytes(buf, (const uint8_t*)tag_name, tag_length);
@@ -166,6 +399,12 @@ void librender_set_attribute(struct librender_bytecode_buffer* buf,
     return;
   }
 
+  size_t length = 3 + (size_t)attr_name_length + attr_value_length;
+
+  if (librender_reserve(buf, length) != 0) {
+    return;
+  }
+
   librender_append_byte(buf, OPCODE_SET_ATTRIBUTE);
   librender_append_byte(buf, attr_name_length);
   librender_append_bytes(buf, (const uint8_t*)attr_name, attr_name_length);
@@ -211,6 +450,10 @@ void librender_text_node(struct librender_bytecode_buffer* buf,
     return;
   }
 
+  if (librender_reserve(buf, 2 + (size_t)text_length) != 0) {
+    return;
+  }
+
   librender_append_byte(buf, OPCODE_TEXT_NODE);
   librender_append_byte(buf, text_length);
   librender_append_bytes(buf, (const uint8_t*)text, text_length);
@@ -222,6 +465,10 @@ void librender_set_text(struct librender_bytecode_buffer* buf, const char* text,
     return;
   }
 
+  if (librender_reserve(buf, 2 + (size_t)text_length) != 0) {
+    return;
+  }
+
   librender_append_byte(buf, OPCODE_SET_TEXT);
   librender_append_byte(buf, text_length);
   librender_append_bytes(buf, (const uint8_t*)text, text_length);
@@ -234,6 +481,10 @@ void librender_remove_attribute(struct librender_bytecode_buffer* buf,
     return;
   }
 
+  if (librender_reserve(buf, 2 + (size_t)attr_name_length) != 0) {
+    return;
+  }
+
   librender_append_byte(buf, OPCODE_REMOVE_ATTRIBUTE);
   librender_append_byte(buf, attr_name_length);
   librender_append_bytes(buf, (const uint8_t*)attr_name, attr_name_length);
@@ -247,6 +498,12 @@ void librender_set_style(struct librender_bytecode_buffer* buf,
     return;
   }
 
+  size_t length = 3 + (size_t)style_name_length + style_value_length;
+
+  if (librender_reserve(buf, length) != 0) {
+    return;
+  }
+
   librender_append_byte(buf, OPCODE_STYLE);
   librender_append_byte(buf, style_name_length);
   librender_append_bytes(buf, (const uint8_t*)style_name, style_name_length);
@@ -261,11 +518,30 @@ void librender_add_event_listener(struct librender_bytecode_buffer* buf,
     return;
   }
 
+  if (librender_reserve(buf, 2 + (size_t)event_type_length) != 0) {
+    return;
+  }
+
   librender_append_byte(buf, OPCODE_EVENT_LISTENER);
   librender_append_byte(buf, event_type_length);
   librender_append_bytes(buf, (const uint8_t*)event_type, event_type_length);
 }
 
+void librender_placeholder(struct librender_bytecode_buffer* buf,
+                           const char* slot_id, uint8_t slot_id_length) {
+  if (!buf || buf->is_locked || !slot_id || slot_id_length == 0) {
+    return;
+  }
+
+  if (librender_reserve(buf, 2 + (size_t)slot_id_length) != 0) {
+    return;
+  }
+
+  librender_append_byte(buf, OPCODE_PLACEHOLDER);
+  librender_append_byte(buf, slot_id_length);
+  librender_append_bytes(buf, (const uint8_t*)slot_id, slot_id_length);
+}
+
 void librender_nop(struct librender_bytecode_buffer* buf) {
   if (!buf || buf->is_locked) {
     return;
@@ -274,21 +550,192 @@ void librender_nop(struct librender_bytecode_buffer* buf) {
   librender_append_byte(buf, OPCODE_NOP);
 }
 
-void librender_output_bytecode(const struct librender_bytecode_buffer* buf,
-                               const char* filename) {
+int librender_output_bytecode(const struct librender_bytecode_buffer* buf,
+                              const char* filename) {
   if (!buf || !filename) {
-    return;
+    return -1;
   }
 
   FILE* file = fopen(filename, "wb");
 
   if (!file) {
     fprintf(stderr, "Failed to open file %s for writing\n", filename);
-    return;
+    return -1;
+  }
+
+  size_t written = fwrite(buf->buffer, 1, buf->size, file);
+
+  if (fclose(file) != 0 || written != buf->size) {
+    fprintf(stderr, "Failed to write file %s\n", filename);
+    return -1;
+  }
+
+  return 0;
+}
+
+struct librender_bytecode_buffer* librender_load_bytecode(
+    const char* filename) {
+  if (!filename) {
+    return NULL;
+  }
+
+  FILE* file = fopen(filename, "rb");
+
+  if (!file) {
+    fprintf(stderr, "Failed to open file %s for reading\n", filename);
+    return NULL;
+  }
+
+  struct librender_bytecode_buffer* buf = librender_create_buffer(0);
+  uint8_t chunk[4096];
+  size_t count;
+
+  while ((count = fread(chunk, 1, sizeof(chunk), file)) > 0) {
+    librender_append_bytes(buf, chunk, count);
+  }
+
+  if (ferror(file)) {
+    fprintf(stderr, "Failed to read file %s\n", filename);
+    librender_free_buffer(buf);
+    buf = NULL;
   }
 
-  fwrite(buf->buffer, 1, buf->size, file);
   fclose(file);
+
+  return buf;
+}
+
+static size_t librender_write_all(const uint8_t* bytes, size_t count,
+                                  librender_write_fn write, void* user_data) {
+  size_t offset = 0;
+
+  while (offset < count) {
+    size_t written = write(bytes + offset, count - offset, user_data);
+
+    if (written == 0 || written > count - offset) {
+      break;
+    }
+
+    offset += written;
+  }
+
+  return offset;
+}
+
+int librender_write_with(const struct librender_bytecode_buffer* buf,
+                         librender_write_fn write, void* user_data) {
+  if (!buf || !write) {
+    return -1;
+  }
+
+  if (librender_write_all(buf->buffer, buf->size, write, user_data) !=
+      buf->size) {
+    return -1;
+  }
+
+  return 0;
+}
+
+// Streams the buffer into `sink`. With a non-zero threshold, pending bytes
+// are flushed whenever at least `threshold` bytes are buffered, which may
+// split an instruction across calls; otherwise only `librender_flush` writes.
+// Flushed bytes are removed from the buffer. Passing a NULL sink detaches it.
+// Setting a sink clears the failure reported by librender_sink_failed.
+void librender_set_sink(struct librender_bytecode_buffer* buf,
+                        librender_write_fn sink, void* user_data,
+                        size_t threshold) {
+  if (!buf) {
+    return;
+  }
+
+  buf->sink = sink;
+  buf->sink_data = user_data;
+  buf->sink_threshold = threshold;
+  buf->sink_failed = 0;
+}
+
+// Writes pending bytes to the sink. Bytes the sink did not accept stay in
+// the buffer for the next flush and mark the sink as failed until a flush
+// succeeds. Nothing is written while a checkpoint is open, since it could
+// still be rolled back.
+int librender_flush(struct librender_bytecode_buffer* buf) {
+  if (!buf || !buf->sink || buf->is_locked || buf->checkpoints) {
+    return -1;
+  }
+
+  size_t written =
+      librender_write_all(buf->buffer, buf->size, buf->sink, buf->sink_data);
+
+  memmove(buf->buffer, buf->buffer + written, buf->size - written);
+  buf->size -= written;
+  buf->sink_failed = buf->size != 0;
+
+  return buf->sink_failed ? -1 : 0;
+}
+
+// Whether the sink stopped accepting bytes. Flushes triggered by the
+// threshold fail silently, so check this before relying on the output.
+// While it is set, only librender_flush writes to the sink.
+int librender_sink_failed(const struct librender_bytecode_buffer* buf) {
+  if (!buf) {
+    return 0;
+  }
+
+  return buf->sink_failed;
+}
+
+// Opens a checkpoint and returns it. Checkpoints nest up to
+// LIBRENDER_MAX_CHECKPOINTS deep and only the innermost open one can be
+// rolled back or committed. Past that depth, or without a buffer,
+// (size_t)-1 is returned, which librender_rollback and librender_commit
+// reject.
+size_t librender_checkpoint(struct librender_bytecode_buffer* buf) {
+  if (!buf) {
+    return (size_t)-1;
+  }
+
+  if (buf->checkpoints == LIBRENDER_MAX_CHECKPOINTS) {
+    return (size_t)-1;
+  }
+
+  buf->checkpoint_sizes[buf->checkpoints++] = buf->size;
+
+  return buf->size;
+}
+
+// Discards everything emitted since checkpoint `cp` and closes it, along
+// with the overflow of a fixed buffer. Returns 0, or -1 if `cp` is not the
+// innermost open checkpoint, is past the end or the buffer is locked.
+int librender_rollback(struct librender_bytecode_buffer* buf, size_t cp) {
+  if (!buf || buf->is_locked || !buf->checkpoints ||
+      cp != buf->checkpoint_sizes[buf->checkpoints - 1] || cp > buf->size) {
+    return -1;
+  }
+
+  buf->size = cp;
+  buf->checkpoints--;
+  buf->overflowed = 0;
+
+  return 0;
+}
+
+// Keeps everything emitted since checkpoint `cp` and closes it. Closing the
+// outermost checkpoint resumes flushing to the sink. Returns 0, or -1 if
+// `cp` is not the innermost open checkpoint or is past the end.
+int librender_commit(struct librender_bytecode_buffer* buf, size_t cp) {
+  if (!buf || !buf->checkpoints ||
+      cp != buf->checkpoint_sizes[buf->checkpoints - 1] || cp > buf->size) {
+    return -1;
+  }
+
+  buf->checkpoints--;
+
+  if (!buf->checkpoints && buf->sink && buf->sink_threshold &&
+      !buf->sink_failed && buf->size >= buf->sink_threshold) {
+    librender_flush(buf);
+  }
+
+  return 0;
 }
 
 void librender_clear_buffer(struct librender_bytecode_buffer* buf) {
@@ -297,8 +744,12 @@ void librender_clear_buffer(struct librender_bytecode_buffer* buf) {
   }
 
   buf->size = 0;
+  buf->checkpoints = 0;
+  buf->overflowed = 0;
 }
 
+// Concatenates the programs, so each keeps its own root. To mount programs
+// under nodes of another one, use librender_merge_tree.
 struct librender_bytecode_buffer* librender_merge_bytecode(
     struct librender_bytecode_buffer** buffers, size_t num_buffers) {
   if (!buffers || num_buffers == 0) {
@@ -341,7 +792,8 @@ void librender_copy_buffer(struct librender_bytecode_buffer* dst,
 
 void librender_resize_buffer(struct librender_bytecode_buffer* buf,
                              size_t new_capacity) {
-  if (!buf || buf->is_locked || new_capacity <= buf->capacity) {
+  if (!buf || buf->is_locked || buf->is_fixed ||
+      new_capacity <= buf->capacity) {
     return;
   }
 
@@ -364,29 +816,77 @@ void librender_append_bytecode(struct librender_bytecode_buffer* buf,
   librender_append_bytes(buf, bytecode, bytecode_size);
 }
 
-void librender_insert_byte(struct librender_bytecode_buffer* buf, size_t index,
-                           uint8_t byte) {
-  if (!buf || buf->is_locked || index > buf->size) {
+// Replaces `remove_count` bytes at `index` with `count` bytes from `bytes`,
+// moving the tail once. `bytes` must not point into the buffer. Returns 0,
+// or -1 if the buffer is locked or has overflowed or the range is out of
+// bounds.
+int librender_splice(struct librender_bytecode_buffer* buf, size_t index,
+                     size_t remove_count, const uint8_t* bytes, size_t count) {
+  if (!buf || buf->is_locked || buf->overflowed || index > buf->size ||
+      remove_count > buf->size - index || (count && !bytes)) {
+    return -1;
+  }
+
+  if (count > remove_count &&
+      librender_reserve(buf, count - remove_count) != 0) {
+    return -1;
+  }
+
+  memmove(buf->buffer + index + count, buf->buffer + index + remove_count,
+          buf->size - index - remove_count);
+
+  if (count) {
+    memcpy(buf->buffer + index, bytes, count);
+  }
+
+  buf->size = buf->size - remove_count + count;
+
+  return 0;
+}
+
+// Shortens the buffer to `size` bytes, closing the checkpoints opened past
+// it. Does nothing if it is not longer.
+void librender_truncate(struct librender_bytecode_buffer* buf, size_t size) {
+  if (!buf || buf->is_locked || size >= buf->size) {
     return;
   }
 
-  if (buf->size >= buf->capacity) {
-    buf->capacity *= 2;
-    buf->buffer =
-        (uint8_t*)realloc(buf->buffer, buf->capacity * sizeof(uint8_t));
+  buf->size = size;
 
-    if (!buf->buffer) {
-      fprintf(stderr, "Failed to reallocate buffer during insertion\n");
-      exit(EXIT_FAILURE);
-    }
+  while (buf->checkpoints &&
+         buf->checkpoint_sizes[buf->checkpoints - 1] > size) {
+    buf->checkpoints--;
+  }
+}
+
+// Releases unused capacity. Returns 0, or -1 if the buffer is locked or
+// fixed or the allocator fails, in which case the buffer is unchanged.
+int librender_shrink_to_fit(struct librender_bytecode_buffer* buf) {
+  if (!buf || buf->is_locked || buf->is_fixed) {
+    return -1;
+  }
+
+  size_t capacity = buf->size ? buf->size : 1;
+
+  if (capacity == buf->capacity) {
+    return 0;
   }
 
-  for (size_t i = buf->size; i > index; i--) {
-    buf->buffer[i] = buf->buffer[i - 1];
+  uint8_t* buffer = (uint8_t*)realloc(buf->buffer, capacity * sizeof(uint8_t));
+
+  if (!buffer) {
+    return -1;
   }
 
-  buf->buffer[index] = byte;
-  buf->size++;
+  buf->buffer = buffer;
+  buf->capacity = capacity;
+
+  return 0;
+}
+
+void librender_insert_byte(struct librender_bytecode_buffer* buf, size_t index,
+                           uint8_t byte) {
+  librender_splice(buf, index, 0, &byte, 1);
 }
 
 void librender_remove_byte(struct librender_bytecode_buffer* buf,
@@ -395,11 +895,7 @@ void librender_remove_byte(struct librender_bytecode_buffer* buf,
     return;
   }
 
-  for (size_t i = index; i < buf->size - 1; i++) {
-    buf->buffer[i] = buf->buffer[i + 1];
-  }
-
-  buf->size--;
+  librender_splice(buf, index, 1, NULL, 0);
 }
 
 void librender_get_byte(const struct librender_bytecode_buffer* buf,
@@ -36,6 +36,30 @@
 )]
 #![feature(extern_types)]
 
+pub mod analysis;
+pub mod asm;
+pub mod canon;
+pub mod compress;
+pub mod cursor;
+pub mod debug;
+pub mod decode;
+pub mod delta;
+pub mod diag;
+pub mod fixed;
+pub mod frame;
+pub mod html;
+pub mod interp;
+pub mod io;
+pub mod memo;
+pub mod merge;
+pub mod mux;
+pub mod opt;
+pub mod parallel;
+pub mod program;
+pub mod rope;
+pub mod snapshot;
+pub mod stats;
+
 extern "C" {
     pub type _IO_wide_data;
     pub type _IO_codecvt;
@@ -44,8 +68,15 @@ extern "C" {
     static mut stderr: *mut FILE;
 
     fn fclose(__stream: *mut FILE) -> libc::c_int;
+    fn ferror(__stream: *mut FILE) -> libc::c_int;
     fn fopen(_: *const libc::c_char, _: *const libc::c_char) -> *mut FILE;
     fn fprintf(_: *mut FILE, _: *const libc::c_char, _: ...) -> libc::c_int;
+    fn fread(
+        _: *mut libc::c_void,
+        _: libc::c_ulong,
+        _: libc::c_ulong,
+        _: *mut FILE,
+    ) -> libc::c_ulong;
     fn fwrite(
         _: *const libc::c_void,
         _: libc::c_ulong,
@@ -53,6 +84,9 @@ extern "C" {
         _: *mut FILE,
     ) -> libc::c_ulong;
     fn malloc(_: libc::c_ulong) -> *mut libc::c_void;
+    fn memcpy(_: *mut libc::c_void, _: *const libc::c_void, _: libc::c_ulong) -> *mut libc::c_void;
+    fn memmove(_: *mut libc::c_void, _: *const libc::c_void, _: libc::c_ulong)
+        -> *mut libc::c_void;
     fn realloc(_: *mut libc::c_void, _: libc::c_ulong) -> *mut libc::c_void;
     fn free(_: *mut libc::c_void);
     fn exit(_: libc::c_int) -> !;
@@ -101,6 +135,7 @@ pub struct _IO_FILE {
 pub type _IO_lock_t = ();
 pub type FILE = _IO_FILE;
 
+pub const OPCODE_PLACEHOLDER: libc::c_uint = 12;
 pub const OPCODE_APPEND_SIBLING: libc::c_uint = 11;
 pub const OPCODE_EVENT_LISTENER: libc::c_uint = 10;
 pub const OPCODE_STYLE: libc::c_uint = 9;
@@ -114,6 +149,11 @@ pub const OPCODE_SET_ATTRIBUTE: libc::c_uint = 2;
 pub const OPCODE_CREATE_ELEMENT: libc::c_uint = 1;
 pub const OPCODE_NOP: libc::c_uint = 0;
 
+pub const LIBRENDER_MAX_CHECKPOINTS: libc::c_uint = 32;
+
+pub type librender_write_fn =
+    Option<unsafe extern "C" fn(*const uint8_t, size_t, *mut libc::c_void) -> size_t>;
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub struct librender_bytecode_buffer {
@@ -121,9 +161,18 @@ pub struct librender_bytecode_buffer {
     pub size: size_t,
     pub capacity: size_t,
     pub is_locked: libc::c_int,
+    pub sink: librender_write_fn,
+    pub sink_data: *mut libc::c_void,
+    pub sink_threshold: size_t,
+    pub sink_failed: libc::c_int,
+    pub checkpoints: size_t,
+    pub checkpoint_sizes: [size_t; 32],
+    pub is_fixed: libc::c_int,
+    pub overflowed: libc::c_int,
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return, clippy::zero_ptr)]
 pub unsafe extern "C" fn librender_create_buffer(
     mut initial_capacity: size_t,
 ) -> *mut librender_bytecode_buffer {
@@ -160,13 +209,22 @@ pub unsafe extern "C" fn librender_create_buffer(
     (*buf).size = 0 as libc::c_int as size_t;
     (*buf).capacity = initial_capacity;
     (*buf).is_locked = 0 as libc::c_int;
+    (*buf).sink = None;
+    (*buf).sink_data = 0 as *mut libc::c_void;
+    (*buf).sink_threshold = 0 as libc::c_int as size_t;
+    (*buf).sink_failed = 0 as libc::c_int;
+    (*buf).checkpoints = 0 as libc::c_int as size_t;
+    (*buf).checkpoint_sizes = [0; 32];
+    (*buf).is_fixed = 0 as libc::c_int;
+    (*buf).overflowed = 0 as libc::c_int;
 
     return buf;
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_free_buffer(mut buf: *mut librender_bytecode_buffer) {
-    if buf.is_null() {
+    if buf.is_null() || (*buf).is_fixed != 0 {
         return;
     }
 
@@ -178,6 +236,49 @@ pub unsafe extern "C" fn librender_free_buffer(mut buf: *mut librender_bytecode_
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::zero_ptr)]
+pub unsafe extern "C" fn librender_init_fixed_buffer(
+    mut buf: *mut librender_bytecode_buffer,
+    mut data: *mut uint8_t,
+    mut capacity: size_t,
+) {
+    if buf.is_null() {
+        return;
+    }
+
+    (*buf).buffer = data;
+    (*buf).size = 0 as libc::c_int as size_t;
+    (*buf).capacity = if !data.is_null() {
+        capacity
+    } else {
+        0 as libc::c_int as size_t
+    };
+    (*buf).is_locked = 0 as libc::c_int;
+    (*buf).sink = None;
+    (*buf).sink_data = 0 as *mut libc::c_void;
+    (*buf).sink_threshold = 0 as libc::c_int as size_t;
+    (*buf).sink_failed = 0 as libc::c_int;
+    (*buf).checkpoints = 0 as libc::c_int as size_t;
+    (*buf).checkpoint_sizes = [0; 32];
+    (*buf).is_fixed = 1 as libc::c_int;
+    (*buf).overflowed = 0 as libc::c_int;
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return)]
+pub unsafe extern "C" fn librender_buffer_overflowed(
+    mut buf: *const librender_bytecode_buffer,
+) -> libc::c_int {
+    if buf.is_null() {
+        return 0 as libc::c_int;
+    }
+
+    return (*buf).overflowed;
+}
+
+#[no_mangle]
+#[deprecated(note = "use librender_freeze")]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_lock_buffer(mut buf: *mut librender_bytecode_buffer) {
     if buf.is_null() {
         return;
@@ -187,6 +288,8 @@ pub unsafe extern "C" fn librender_lock_buffer(mut buf: *mut librender_bytecode_
 }
 
 #[no_mangle]
+#[deprecated(note = "use librender_freeze")]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_unlock_buffer(mut buf: *mut librender_bytecode_buffer) {
     if buf.is_null() {
         return;
@@ -196,6 +299,7 @@ pub unsafe extern "C" fn librender_unlock_buffer(mut buf: *mut librender_bytecod
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return)]
 pub unsafe extern "C" fn librender_is_buffer_locked(
     mut buf: *const librender_bytecode_buffer,
 ) -> libc::c_int {
@@ -207,58 +311,101 @@ pub unsafe extern "C" fn librender_is_buffer_locked(
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn librender_append_byte(
+#[allow(clippy::missing_safety_doc, clippy::needless_return)]
+pub unsafe extern "C" fn librender_reserve(
     mut buf: *mut librender_bytecode_buffer,
-    mut byte: uint8_t,
-) {
-    if buf.is_null() || (*buf).is_locked != 0 {
-        return;
+    mut additional: size_t,
+) -> libc::c_int {
+    if buf.is_null() || (*buf).is_locked != 0 || (*buf).overflowed != 0 {
+        return -(1 as libc::c_int);
     }
 
-    if (*buf).size >= (*buf).capacity {
-        (*buf).capacity = ((*buf).capacity as libc::c_ulong)
-            .wrapping_mul(2 as libc::c_int as libc::c_ulong) as size_t
-            as size_t;
+    if ((*buf).capacity).wrapping_sub((*buf).size) >= additional {
+        return 0 as libc::c_int;
+    }
 
-        (*buf).buffer = realloc(
-            (*buf).buffer as *mut libc::c_void,
-            ((*buf).capacity).wrapping_mul(::core::mem::size_of::<uint8_t>() as libc::c_ulong),
-        ) as *mut uint8_t;
+    if (*buf).is_fixed != 0 {
+        (*buf).overflowed = 1 as libc::c_int;
+        return -(1 as libc::c_int);
+    }
 
-        if ((*buf).buffer).is_null() {
-            fprintf(
-                stderr,
-                b"Failed to reallocate memory for buffer\n\0" as *const u8 as *const libc::c_char,
-            );
-            exit(1 as libc::c_int);
-        }
+    let mut capacity: size_t = ((*buf).capacity as libc::c_ulong)
+        .wrapping_mul(2 as libc::c_int as libc::c_ulong) as size_t;
+
+    if capacity < ((*buf).size).wrapping_add(additional) {
+        capacity = ((*buf).size).wrapping_add(additional);
+    }
+
+    (*buf).buffer = realloc(
+        (*buf).buffer as *mut libc::c_void,
+        capacity.wrapping_mul(::core::mem::size_of::<uint8_t>() as libc::c_ulong),
+    ) as *mut uint8_t;
+
+    if ((*buf).buffer).is_null() {
+        fprintf(
+            stderr,
+            b"Failed to reallocate memory for buffer\n\0" as *const u8 as *const libc::c_char,
+        );
+        exit(1 as libc::c_int);
+    }
+
+    (*buf).capacity = capacity;
+
+    return 0 as libc::c_int;
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn librender_append_byte(
+    mut buf: *mut librender_bytecode_buffer,
+    mut byte: uint8_t,
+) {
+    if librender_reserve(buf, 1 as libc::c_int as size_t) != 0 as libc::c_int {
+        return;
     }
 
     let fresh0 = (*buf).size;
     (*buf).size = ((*buf).size).wrapping_add(1);
     *((*buf).buffer).offset(fresh0 as isize) = byte;
+
+    if ((*buf).sink).is_some()
+        && (*buf).sink_threshold != 0
+        && (*buf).sink_failed == 0
+        && (*buf).size >= (*buf).sink_threshold
+    {
+        librender_flush(buf);
+    }
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_append_bytes(
     mut buf: *mut librender_bytecode_buffer,
     mut bytes: *const uint8_t,
     mut count: size_t,
 ) {
-    if buf.is_null() || (*buf).is_locked != 0 || bytes.is_null() {
+    if bytes.is_null() || librender_reserve(buf, count) != 0 as libc::c_int {
         return;
     }
 
-    let mut i: size_t = 0 as libc::c_int as size_t;
+    memcpy(
+        ((*buf).buffer).offset((*buf).size as isize) as *mut libc::c_void,
+        bytes as *const libc::c_void,
+        count,
+    );
+    (*buf).size = ((*buf).size).wrapping_add(count);
 
-    while i < count {
-        librender_append_byte(buf, *bytes.offset(i as isize));
-        i = i.wrapping_add(1);
-        // i;
+    if ((*buf).sink).is_some()
+        && (*buf).sink_threshold != 0
+        && (*buf).sink_failed == 0
+        && (*buf).size >= (*buf).sink_threshold
+    {
+        librender_flush(buf);
     }
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_create_element(
     mut buf: *mut librender_bytecode_buffer,
     mut tag_name: *const libc::c_char,
@@ -272,12 +419,21 @@ pub unsafe extern "C" fn librender_create_element(
         return;
     }
 
+    if librender_reserve(
+        buf,
+        (2 as libc::c_int as size_t).wrapping_add(tag_length as size_t),
+    ) != 0 as libc::c_int
+    {
+        return;
+    }
+
     librender_append_byte(buf, OPCODE_CREATE_ELEMENT as libc::c_int as uint8_t);
     librender_append_byte(buf, tag_length);
     librender_append_bytes(buf, tag_name as *const uint8_t, tag_length as size_t);
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_set_attribute(
     mut buf: *mut librender_bytecode_buffer,
     mut attr_name: *const libc::c_char,
@@ -295,6 +451,14 @@ pub unsafe extern "C" fn librender_set_attribute(
         return;
     }
 
+    let mut length: size_t = (3 as libc::c_int as size_t)
+        .wrapping_add(attr_name_length as size_t)
+        .wrapping_add(attr_value_length as size_t);
+
+    if librender_reserve(buf, length) != 0 as libc::c_int {
+        return;
+    }
+
     librender_append_byte(buf, OPCODE_SET_ATTRIBUTE as libc::c_int as uint8_t);
     librender_append_byte(buf, attr_name_length);
     librender_append_bytes(buf, attr_name as *const uint8_t, attr_name_length as size_t);
@@ -307,6 +471,7 @@ pub unsafe extern "C" fn librender_set_attribute(
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_append_child(mut buf: *mut librender_bytecode_buffer) {
     if buf.is_null() || (*buf).is_locked != 0 {
         return;
@@ -316,6 +481,7 @@ pub unsafe extern "C" fn librender_append_child(mut buf: *mut librender_bytecode
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_append_sibling(mut buf: *mut librender_bytecode_buffer) {
     if buf.is_null() || (*buf).is_locked != 0 {
         return;
@@ -325,6 +491,7 @@ pub unsafe extern "C" fn librender_append_sibling(mut buf: *mut librender_byteco
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_remove_child(mut buf: *mut librender_bytecode_buffer) {
     if buf.is_null() || (*buf).is_locked != 0 {
         return;
@@ -334,6 +501,7 @@ pub unsafe extern "C" fn librender_remove_child(mut buf: *mut librender_bytecode
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_replace_child(mut buf: *mut librender_bytecode_buffer) {
     if buf.is_null() || (*buf).is_locked != 0 {
         return;
@@ -343,6 +511,7 @@ pub unsafe extern "C" fn librender_replace_child(mut buf: *mut librender_bytecod
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_text_node(
     mut buf: *mut librender_bytecode_buffer,
     mut text: *const libc::c_char,
@@ -356,12 +525,21 @@ pub unsafe extern "C" fn librender_text_node(
         return;
     }
 
+    if librender_reserve(
+        buf,
+        (2 as libc::c_int as size_t).wrapping_add(text_length as size_t),
+    ) != 0 as libc::c_int
+    {
+        return;
+    }
+
     librender_append_byte(buf, OPCODE_TEXT_NODE as libc::c_int as uint8_t);
     librender_append_byte(buf, text_length);
     librender_append_bytes(buf, text as *const uint8_t, text_length as size_t);
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_set_text(
     mut buf: *mut librender_bytecode_buffer,
     mut text: *const libc::c_char,
@@ -375,12 +553,21 @@ pub unsafe extern "C" fn librender_set_text(
         return;
     }
 
+    if librender_reserve(
+        buf,
+        (2 as libc::c_int as size_t).wrapping_add(text_length as size_t),
+    ) != 0 as libc::c_int
+    {
+        return;
+    }
+
     librender_append_byte(buf, OPCODE_SET_TEXT as libc::c_int as uint8_t);
     librender_append_byte(buf, text_length);
     librender_append_bytes(buf, text as *const uint8_t, text_length as size_t);
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_remove_attribute(
     mut buf: *mut librender_bytecode_buffer,
     mut attr_name: *const libc::c_char,
@@ -394,12 +581,21 @@ pub unsafe extern "C" fn librender_remove_attribute(
         return;
     }
 
+    if librender_reserve(
+        buf,
+        (2 as libc::c_int as size_t).wrapping_add(attr_name_length as size_t),
+    ) != 0 as libc::c_int
+    {
+        return;
+    }
+
     librender_append_byte(buf, OPCODE_REMOVE_ATTRIBUTE as libc::c_int as uint8_t);
     librender_append_byte(buf, attr_name_length);
     librender_append_bytes(buf, attr_name as *const uint8_t, attr_name_length as size_t);
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_set_style(
     mut buf: *mut librender_bytecode_buffer,
     mut style_name: *const libc::c_char,
@@ -417,6 +613,14 @@ pub unsafe extern "C" fn librender_set_style(
         return;
     }
 
+    let mut length: size_t = (3 as libc::c_int as size_t)
+        .wrapping_add(style_name_length as size_t)
+        .wrapping_add(style_value_length as size_t);
+
+    if librender_reserve(buf, length) != 0 as libc::c_int {
+        return;
+    }
+
     librender_append_byte(buf, OPCODE_STYLE as libc::c_int as uint8_t);
     librender_append_byte(buf, style_name_length);
     librender_append_bytes(
@@ -433,6 +637,7 @@ pub unsafe extern "C" fn librender_set_style(
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_add_event_listener(
     mut buf: *mut librender_bytecode_buffer,
     mut event_type: *const libc::c_char,
@@ -446,6 +651,14 @@ pub unsafe extern "C" fn librender_add_event_listener(
         return;
     }
 
+    if librender_reserve(
+        buf,
+        (2 as libc::c_int as size_t).wrapping_add(event_type_length as size_t),
+    ) != 0 as libc::c_int
+    {
+        return;
+    }
+
     librender_append_byte(buf, OPCODE_EVENT_LISTENER as libc::c_int as uint8_t);
     librender_append_byte(buf, event_type_length);
     librender_append_bytes(
@@ -456,6 +669,35 @@ pub unsafe extern "C" fn librender_add_event_listener(
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn librender_placeholder(
+    mut buf: *mut librender_bytecode_buffer,
+    mut slot_id: *const libc::c_char,
+    mut slot_id_length: uint8_t,
+) {
+    if buf.is_null()
+        || (*buf).is_locked != 0
+        || slot_id.is_null()
+        || slot_id_length as libc::c_int == 0 as libc::c_int
+    {
+        return;
+    }
+
+    if librender_reserve(
+        buf,
+        (2 as libc::c_int as size_t).wrapping_add(slot_id_length as size_t),
+    ) != 0 as libc::c_int
+    {
+        return;
+    }
+
+    librender_append_byte(buf, OPCODE_PLACEHOLDER as libc::c_int as uint8_t);
+    librender_append_byte(buf, slot_id_length);
+    librender_append_bytes(buf, slot_id as *const uint8_t, slot_id_length as size_t);
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_nop(mut buf: *mut librender_bytecode_buffer) {
     if buf.is_null() || (*buf).is_locked != 0 {
         return;
@@ -465,12 +707,13 @@ pub unsafe extern "C" fn librender_nop(mut buf: *mut librender_bytecode_buffer)
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return)]
 pub unsafe extern "C" fn librender_output_bytecode(
     mut buf: *const librender_bytecode_buffer,
     mut filename: *const libc::c_char,
-) {
+) -> libc::c_int {
     if buf.is_null() || filename.is_null() {
-        return;
+        return -(1 as libc::c_int);
     }
 
     let mut file: *mut FILE = fopen(filename, b"wb\0" as *const u8 as *const libc::c_char);
@@ -481,29 +724,264 @@ pub unsafe extern "C" fn librender_output_bytecode(
             b"Failed to open file %s for writing\n\0" as *const u8 as *const libc::c_char,
             filename,
         );
-        return;
+        return -(1 as libc::c_int);
     }
 
-    fwrite(
+    let mut written: size_t = fwrite(
         (*buf).buffer as *const libc::c_void,
         1 as libc::c_int as libc::c_ulong,
         (*buf).size,
         file,
     );
 
+    if fclose(file) != 0 as libc::c_int || written != (*buf).size {
+        fprintf(
+            stderr,
+            b"Failed to write file %s\n\0" as *const u8 as *const libc::c_char,
+            filename,
+        );
+        return -(1 as libc::c_int);
+    }
+
+    return 0 as libc::c_int;
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return, clippy::zero_ptr)]
+pub unsafe extern "C" fn librender_load_bytecode(
+    mut filename: *const libc::c_char,
+) -> *mut librender_bytecode_buffer {
+    if filename.is_null() {
+        return 0 as *mut librender_bytecode_buffer;
+    }
+
+    let mut file: *mut FILE = fopen(filename, b"rb\0" as *const u8 as *const libc::c_char);
+
+    if file.is_null() {
+        fprintf(
+            stderr,
+            b"Failed to open file %s for reading\n\0" as *const u8 as *const libc::c_char,
+            filename,
+        );
+        return 0 as *mut librender_bytecode_buffer;
+    }
+
+    let mut buf: *mut librender_bytecode_buffer =
+        librender_create_buffer(0 as libc::c_int as size_t);
+    let mut chunk: [uint8_t; 4096] = [0; 4096];
+    let mut count: size_t = 0;
+
+    loop {
+        count = fread(
+            chunk.as_mut_ptr() as *mut libc::c_void,
+            1 as libc::c_int as libc::c_ulong,
+            ::core::mem::size_of::<[uint8_t; 4096]>() as libc::c_ulong,
+            file,
+        );
+
+        if count <= 0 as libc::c_int as libc::c_ulong {
+            break;
+        }
+
+        librender_append_bytes(buf, chunk.as_mut_ptr(), count);
+    }
+
+    if ferror(file) != 0 {
+        fprintf(
+            stderr,
+            b"Failed to read file %s\n\0" as *const u8 as *const libc::c_char,
+            filename,
+        );
+        librender_free_buffer(buf);
+        buf = 0 as *mut librender_bytecode_buffer;
+    }
+
     fclose(file);
+
+    return buf;
+}
+
+#[allow(clippy::needless_return)]
+unsafe extern "C" fn librender_write_all(
+    mut bytes: *const uint8_t,
+    mut count: size_t,
+    mut write: librender_write_fn,
+    mut user_data: *mut libc::c_void,
+) -> size_t {
+    let mut offset: size_t = 0 as libc::c_int as size_t;
+
+    while offset < count {
+        let mut written: size_t = write.expect("non-null function pointer")(
+            bytes.offset(offset as isize),
+            count.wrapping_sub(offset),
+            user_data,
+        );
+
+        if written == 0 as libc::c_int as libc::c_ulong || written > count.wrapping_sub(offset) {
+            break;
+        }
+
+        offset = (offset as libc::c_ulong).wrapping_add(written) as size_t as size_t;
+    }
+
+    return offset;
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return)]
+pub unsafe extern "C" fn librender_write_with(
+    mut buf: *const librender_bytecode_buffer,
+    mut write: librender_write_fn,
+    mut user_data: *mut libc::c_void,
+) -> libc::c_int {
+    if buf.is_null() || write.is_none() {
+        return -(1 as libc::c_int);
+    }
+
+    if librender_write_all((*buf).buffer, (*buf).size, write, user_data) != (*buf).size {
+        return -(1 as libc::c_int);
+    }
+
+    return 0 as libc::c_int;
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn librender_set_sink(
+    mut buf: *mut librender_bytecode_buffer,
+    mut sink: librender_write_fn,
+    mut user_data: *mut libc::c_void,
+    mut threshold: size_t,
+) {
+    if buf.is_null() {
+        return;
+    }
+
+    (*buf).sink = sink;
+    (*buf).sink_data = user_data;
+    (*buf).sink_threshold = threshold;
+    (*buf).sink_failed = 0 as libc::c_int;
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return)]
+pub unsafe extern "C" fn librender_flush(mut buf: *mut librender_bytecode_buffer) -> libc::c_int {
+    if buf.is_null() || ((*buf).sink).is_none() || (*buf).is_locked != 0 || (*buf).checkpoints != 0
+    {
+        return -(1 as libc::c_int);
+    }
+
+    let mut written: size_t =
+        librender_write_all((*buf).buffer, (*buf).size, (*buf).sink, (*buf).sink_data);
+    memmove(
+        (*buf).buffer as *mut libc::c_void,
+        ((*buf).buffer).offset(written as isize) as *const libc::c_void,
+        ((*buf).size).wrapping_sub(written),
+    );
+    (*buf).size = ((*buf).size as libc::c_ulong).wrapping_sub(written) as size_t as size_t;
+    (*buf).sink_failed = ((*buf).size != 0 as libc::c_int as libc::c_ulong) as libc::c_int;
+
+    return if (*buf).sink_failed != 0 {
+        -(1 as libc::c_int)
+    } else {
+        0 as libc::c_int
+    };
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return)]
+pub unsafe extern "C" fn librender_sink_failed(
+    mut buf: *const librender_bytecode_buffer,
+) -> libc::c_int {
+    if buf.is_null() {
+        return 0 as libc::c_int;
+    }
+
+    return (*buf).sink_failed;
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return)]
+pub unsafe extern "C" fn librender_checkpoint(mut buf: *mut librender_bytecode_buffer) -> size_t {
+    if buf.is_null() {
+        return -(1 as libc::c_int) as size_t;
+    }
+
+    if (*buf).checkpoints == LIBRENDER_MAX_CHECKPOINTS as libc::c_ulong {
+        return -(1 as libc::c_int) as size_t;
+    }
+
+    let fresh0 = (*buf).checkpoints;
+    (*buf).checkpoints = ((*buf).checkpoints).wrapping_add(1);
+    (*buf).checkpoint_sizes[fresh0 as usize] = (*buf).size;
+
+    return (*buf).size;
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return)]
+pub unsafe extern "C" fn librender_rollback(
+    mut buf: *mut librender_bytecode_buffer,
+    mut cp: size_t,
+) -> libc::c_int {
+    if buf.is_null()
+        || (*buf).is_locked != 0
+        || (*buf).checkpoints == 0
+        || cp != (*buf).checkpoint_sizes[((*buf).checkpoints).wrapping_sub(1) as usize]
+        || cp > (*buf).size
+    {
+        return -(1 as libc::c_int);
+    }
+
+    (*buf).size = cp;
+    (*buf).checkpoints = ((*buf).checkpoints).wrapping_sub(1);
+    (*buf).overflowed = 0 as libc::c_int;
+
+    return 0 as libc::c_int;
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return)]
+pub unsafe extern "C" fn librender_commit(
+    mut buf: *mut librender_bytecode_buffer,
+    mut cp: size_t,
+) -> libc::c_int {
+    if buf.is_null()
+        || (*buf).checkpoints == 0
+        || cp != (*buf).checkpoint_sizes[((*buf).checkpoints).wrapping_sub(1) as usize]
+        || cp > (*buf).size
+    {
+        return -(1 as libc::c_int);
+    }
+
+    (*buf).checkpoints = ((*buf).checkpoints).wrapping_sub(1);
+
+    if (*buf).checkpoints == 0
+        && ((*buf).sink).is_some()
+        && (*buf).sink_threshold != 0
+        && (*buf).sink_failed == 0
+        && (*buf).size >= (*buf).sink_threshold
+    {
+        librender_flush(buf);
+    }
+
+    return 0 as libc::c_int;
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_clear_buffer(mut buf: *mut librender_bytecode_buffer) {
     if buf.is_null() || (*buf).is_locked != 0 {
         return;
     }
 
     (*buf).size = 0 as libc::c_int as size_t;
+    (*buf).checkpoints = 0 as libc::c_int as size_t;
+    (*buf).overflowed = 0 as libc::c_int;
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return, clippy::zero_ptr)]
 pub unsafe extern "C" fn librender_merge_bytecode(
     mut buffers: *mut *mut librender_bytecode_buffer,
     mut num_buffers: size_t,
@@ -551,6 +1029,7 @@ pub unsafe extern "C" fn librender_merge_bytecode(
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_copy_buffer(
     mut dst: *mut librender_bytecode_buffer,
     mut src: *const librender_bytecode_buffer,
@@ -567,11 +1046,16 @@ pub unsafe extern "C" fn librender_copy_buffer(
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_resize_buffer(
     mut buf: *mut librender_bytecode_buffer,
     mut new_capacity: size_t,
 ) {
-    if buf.is_null() || (*buf).is_locked != 0 || new_capacity <= (*buf).capacity {
+    if buf.is_null()
+        || (*buf).is_locked != 0
+        || (*buf).is_fixed != 0
+        || new_capacity <= (*buf).capacity
+    {
         return;
     }
 
@@ -592,6 +1076,7 @@ pub unsafe extern "C" fn librender_resize_buffer(
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_append_bytecode(
     mut buf: *mut librender_bytecode_buffer,
     mut bytecode: *const uint8_t,
@@ -609,50 +1094,120 @@ pub unsafe extern "C" fn librender_append_bytecode(
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn librender_insert_byte(
+#[allow(clippy::missing_safety_doc, clippy::needless_return)]
+pub unsafe extern "C" fn librender_splice(
     mut buf: *mut librender_bytecode_buffer,
     mut index: size_t,
-    mut byte: uint8_t,
+    mut remove_count: size_t,
+    mut bytes: *const uint8_t,
+    mut count: size_t,
+) -> libc::c_int {
+    if buf.is_null()
+        || (*buf).is_locked != 0
+        || (*buf).overflowed != 0
+        || index > (*buf).size
+        || remove_count > ((*buf).size).wrapping_sub(index)
+        || count != 0 && bytes.is_null()
+    {
+        return -(1 as libc::c_int);
+    }
+
+    if count > remove_count
+        && librender_reserve(buf, count.wrapping_sub(remove_count)) != 0 as libc::c_int
+    {
+        return -(1 as libc::c_int);
+    }
+
+    memmove(
+        ((*buf).buffer).offset(index.wrapping_add(count) as isize) as *mut libc::c_void,
+        ((*buf).buffer).offset(index.wrapping_add(remove_count) as isize) as *const libc::c_void,
+        ((*buf).size).wrapping_sub(index).wrapping_sub(remove_count),
+    );
+
+    if count != 0 {
+        memcpy(
+            ((*buf).buffer).offset(index as isize) as *mut libc::c_void,
+            bytes as *const libc::c_void,
+            count,
+        );
+    }
+
+    (*buf).size = ((*buf).size).wrapping_sub(remove_count).wrapping_add(count);
+
+    return 0 as libc::c_int;
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn librender_truncate(
+    mut buf: *mut librender_bytecode_buffer,
+    mut size: size_t,
 ) {
-    if buf.is_null() || (*buf).is_locked != 0 || index > (*buf).size {
+    if buf.is_null() || (*buf).is_locked != 0 || size >= (*buf).size {
         return;
     }
 
-    if (*buf).size >= (*buf).capacity {
-        (*buf).capacity = ((*buf).capacity as libc::c_ulong)
-            .wrapping_mul(2 as libc::c_int as libc::c_ulong) as size_t
-            as size_t;
+    (*buf).size = size;
+
+    while (*buf).checkpoints != 0
+        && (*buf).checkpoint_sizes[((*buf).checkpoints).wrapping_sub(1) as usize] > size
+    {
+        (*buf).checkpoints = ((*buf).checkpoints).wrapping_sub(1);
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return)]
+pub unsafe extern "C" fn librender_shrink_to_fit(
+    mut buf: *mut librender_bytecode_buffer,
+) -> libc::c_int {
+    if buf.is_null() || (*buf).is_locked != 0 || (*buf).is_fixed != 0 {
+        return -(1 as libc::c_int);
+    }
 
-        (*buf).buffer = realloc(
-            (*buf).buffer as *mut libc::c_void,
-            ((*buf).capacity).wrapping_mul(::core::mem::size_of::<uint8_t>() as libc::c_ulong),
-        ) as *mut uint8_t;
+    let mut capacity: size_t = if (*buf).size != 0 {
+        (*buf).size
+    } else {
+        1 as libc::c_int as size_t
+    };
 
-        if ((*buf).buffer).is_null() {
-            fprintf(
-                stderr,
-                b"Failed to reallocate buffer during insertion\n\0" as *const u8
-                    as *const libc::c_char,
-            );
-            exit(1 as libc::c_int);
-        }
+    if capacity == (*buf).capacity {
+        return 0 as libc::c_int;
     }
 
-    let mut i: size_t = (*buf).size;
+    let mut buffer: *mut uint8_t = realloc(
+        (*buf).buffer as *mut libc::c_void,
+        capacity.wrapping_mul(::core::mem::size_of::<uint8_t>() as libc::c_ulong),
+    ) as *mut uint8_t;
 
-    while i > index {
-        *((*buf).buffer).offset(i as isize) =
-            *((*buf).buffer).offset(i.wrapping_sub(1 as libc::c_int as libc::c_ulong) as isize);
-        i = i.wrapping_sub(1);
-        // i;
+    if buffer.is_null() {
+        return -(1 as libc::c_int);
     }
 
-    *((*buf).buffer).offset(index as isize) = byte;
-    (*buf).size = ((*buf).size).wrapping_add(1);
-    (*buf).size;
+    (*buf).buffer = buffer;
+    (*buf).capacity = capacity;
+
+    return 0 as libc::c_int;
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn librender_insert_byte(
+    mut buf: *mut librender_bytecode_buffer,
+    mut index: size_t,
+    mut byte: uint8_t,
+) {
+    librender_splice(
+        buf,
+        index,
+        0 as libc::c_int as size_t,
+        &byte,
+        1 as libc::c_int as size_t,
+    );
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::zero_ptr)]
 pub unsafe extern "C" fn librender_remove_byte(
     mut buf: *mut librender_bytecode_buffer,
     mut index: size_t,
@@ -661,20 +1216,17 @@ pub unsafe extern "C" fn librender_remove_byte(
         return;
     }
 
-    let mut i: size_t = index;
-
-    while i < ((*buf).size).wrapping_sub(1 as libc::c_int as libc::c_ulong) {
-        *((*buf).buffer).offset(i as isize) =
-            *((*buf).buffer).offset(i.wrapping_add(1 as libc::c_int as libc::c_ulong) as isize);
-        i = i.wrapping_add(1);
-        // i;
-    }
-
-    (*buf).size = ((*buf).size).wrapping_sub(1);
-    (*buf).size;
+    librender_splice(
+        buf,
+        index,
+        1 as libc::c_int as size_t,
+        0 as *const uint8_t,
+        0 as libc::c_int as size_t,
+    );
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_get_byte(
     mut buf: *const librender_bytecode_buffer,
     mut index: size_t,
@@ -688,6 +1240,7 @@ pub unsafe extern "C" fn librender_get_byte(
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc, clippy::needless_return, clippy::zero_ptr)]
 pub unsafe extern "C" fn librender_clone_buffer(
     mut src: *const librender_bytecode_buffer,
 ) -> *mut librender_bytecode_buffer {
@@ -707,6 +1260,7 @@ pub unsafe extern "C" fn librender_clone_buffer(
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn librender_destroy_bytecode(mut buf: *mut librender_bytecode_buffer) {
     if buf.is_null() {
         return;
@@ -714,3 +1268,20 @@ pub unsafe extern "C" fn librender_destroy_bytecode(mut buf: *mut librender_byte
 
     librender_free_buffer(buf);
 }
+
+/// Borrows the contents of a buffer for the Rust-side modules.
+pub(crate) unsafe fn buffer_bytes<'a>(buf: *const librender_bytecode_buffer) -> &'a [u8] {
+    if buf.is_null() || ((*buf).buffer).is_null() || (*buf).size == 0 {
+        return &[];
+    }
+
+    ::core::slice::from_raw_parts((*buf).buffer, (*buf).size as usize)
+}
+
+/// Copies `bytes` into a newly created buffer.
+pub(crate) unsafe fn buffer_from_bytes(bytes: &[u8]) -> *mut librender_bytecode_buffer {
+    let buf = librender_create_buffer(bytes.len() as size_t);
+
+    librender_append_bytes(buf, bytes.as_ptr(), bytes.len() as size_t);
+    buf
+}
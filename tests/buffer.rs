@@ -0,0 +1,184 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+use std::mem::MaybeUninit;
+use std::ptr;
+
+use librender::*;
+
+unsafe fn bytes<'a>(buf: *const librender_bytecode_buffer) -> &'a [u8] {
+    match (*buf).size {
+        0 => &[],
+        size => std::slice::from_raw_parts((*buf).buffer, size as usize),
+    }
+}
+
+unsafe fn filled(data: &[u8]) -> *mut librender_bytecode_buffer {
+    let buf = librender_create_buffer(4);
+    librender_append_bytes(buf, data.as_ptr(), data.len() as size_t);
+    buf
+}
+
+unsafe fn fixed(data: &mut [u8]) -> librender_bytecode_buffer {
+    let mut buf = MaybeUninit::uninit();
+    librender_init_fixed_buffer(buf.as_mut_ptr(), data.as_mut_ptr(), data.len() as size_t);
+    buf.assume_init()
+}
+
+#[test]
+fn reserve_at_least_doubles() {
+    unsafe {
+        let buf = filled(b"abc");
+
+        assert_eq!(librender_reserve(buf, 1), 0);
+        assert_eq!((*buf).capacity, 4);
+        assert_eq!(librender_reserve(buf, 2), 0);
+        assert_eq!((*buf).capacity, 8);
+        assert_eq!(librender_reserve(buf, 100), 0);
+        assert_eq!((*buf).capacity, 103);
+        assert_eq!(bytes(buf), b"abc");
+
+        librender_free_buffer(buf);
+    }
+}
+
+#[test]
+fn splice_replaces_ranges() {
+    unsafe {
+        let buf = filled(b"abcdef");
+
+        assert_eq!(librender_splice(buf, 2, 2, b"XYZ".as_ptr(), 3), 0);
+        assert_eq!(bytes(buf), b"abXYZef");
+        assert_eq!(librender_splice(buf, 0, 3, ptr::null(), 0), 0);
+        assert_eq!(bytes(buf), b"YZef");
+        assert_eq!(librender_splice(buf, 4, 0, b"!".as_ptr(), 1), 0);
+        assert_eq!(bytes(buf), b"YZef!");
+
+        assert_eq!(librender_splice(buf, 6, 0, b"!".as_ptr(), 1), -1);
+        assert_eq!(librender_splice(buf, 3, 3, ptr::null(), 0), -1);
+        assert_eq!(librender_splice(buf, 0, 0, ptr::null(), 1), -1);
+        assert_eq!(bytes(buf), b"YZef!");
+
+        librender_insert_byte(buf, 1, b'-');
+        librender_remove_byte(buf, 0);
+        assert_eq!(bytes(buf), b"-Zef!");
+
+        librender_free_buffer(buf);
+    }
+}
+
+#[test]
+fn truncate_only_shortens() {
+    unsafe {
+        let buf = filled(b"abcdef");
+
+        librender_truncate(buf, 10);
+        assert_eq!(bytes(buf), b"abcdef");
+        librender_truncate(buf, 2);
+        assert_eq!(bytes(buf), b"ab");
+        assert_eq!((*buf).capacity, 8);
+
+        librender_free_buffer(buf);
+    }
+}
+
+#[test]
+fn shrink_to_fit_releases_capacity() {
+    unsafe {
+        let buf = filled(b"abcdef");
+
+        assert_eq!(librender_shrink_to_fit(buf), 0);
+        assert_eq!((*buf).capacity, 6);
+
+        librender_truncate(buf, 0);
+        assert_eq!(librender_shrink_to_fit(buf), 0);
+        assert_eq!((*buf).capacity, 1);
+
+        librender_append_bytes(buf, b"abc".as_ptr(), 3);
+        assert_eq!(bytes(buf), b"abc");
+
+        librender_free_buffer(buf);
+    }
+}
+
+#[test]
+#[allow(deprecated)]
+fn locked_buffers_refuse_every_edit() {
+    unsafe {
+        let buf = filled(b"abcdef");
+        let capacity = (*buf).capacity;
+
+        librender_lock_buffer(buf);
+
+        assert_eq!(librender_reserve(buf, 100), -1);
+        assert_eq!(librender_splice(buf, 0, 1, b"X".as_ptr(), 1), -1);
+        librender_truncate(buf, 1);
+        assert_eq!(librender_shrink_to_fit(buf), -1);
+        librender_insert_byte(buf, 0, b'X');
+        librender_remove_byte(buf, 0);
+        librender_append_byte(buf, b'X');
+
+        assert_eq!(bytes(buf), b"abcdef");
+        assert_eq!((*buf).capacity, capacity);
+
+        librender_unlock_buffer(buf);
+        librender_remove_byte(buf, 0);
+        assert_eq!(bytes(buf), b"bcdef");
+
+        librender_free_buffer(buf);
+    }
+}
+
+#[test]
+fn fixed_buffers_never_grow() {
+    let mut data = [0u8; 8];
+
+    unsafe {
+        let mut buf = fixed(&mut data);
+        let buf = &mut buf as *mut librender_bytecode_buffer;
+
+        librender_append_bytes(buf, b"abcdef".as_ptr(), 6);
+        assert_eq!(librender_reserve(buf, 2), 0);
+        assert_eq!(librender_splice(buf, 1, 1, b"XY".as_ptr(), 2), 0);
+        assert_eq!(bytes(buf), b"aXYcdef");
+
+        librender_truncate(buf, 5);
+        assert_eq!(bytes(buf), b"aXYcd");
+        assert_eq!(librender_shrink_to_fit(buf), -1);
+        assert_eq!((*buf).capacity, 8);
+        assert_eq!(librender_buffer_overflowed(buf), 0);
+
+        assert_eq!(librender_splice(buf, 0, 0, b"0123".as_ptr(), 4), -1);
+        assert_eq!(bytes(buf), b"aXYcd");
+        assert_eq!(librender_buffer_overflowed(buf), 1);
+    }
+
+    assert_eq!(&data[..5], b"aXYcd");
+}
+
+#[test]
+fn fixed_buffers_stop_writing_after_an_overflow() {
+    let mut data = [0u8; 8];
+
+    unsafe {
+        let mut buf = fixed(&mut data);
+        let buf = &mut buf as *mut librender_bytecode_buffer;
+
+        librender_create_element(buf, c"div".as_ptr(), 3);
+        librender_text_node(buf, c"hello".as_ptr(), 5);
+        assert_eq!(librender_buffer_overflowed(buf), 1);
+
+        // Each of these fits in the 3 bytes left.
+        librender_append_child(buf);
+        librender_append_byte(buf, 0);
+        assert_eq!(librender_reserve(buf, 1), -1);
+        assert_eq!(librender_splice(buf, 0, 1, b"\x01".as_ptr(), 1), -1);
+        assert_eq!(bytes(buf), b"\x01\x03div");
+
+        librender_clear_buffer(buf);
+        assert_eq!(librender_buffer_overflowed(buf), 0);
+        librender_text_node(buf, c"hello".as_ptr(), 5);
+        librender_append_byte(buf, 0);
+        assert_eq!(bytes(buf), b"\x06\x05hello\x00");
+        assert_eq!((*buf).size, (*buf).capacity);
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+use std::mem::MaybeUninit;
+
+use librender::*;
+
+unsafe fn bytes<'a>(buf: *const librender_bytecode_buffer) -> &'a [u8] {
+    match (*buf).size {
+        0 => &[],
+        size => std::slice::from_raw_parts((*buf).buffer, size as usize),
+    }
+}
+
+unsafe fn element(buf: *mut librender_bytecode_buffer, tag: &str) {
+    librender_create_element(buf, tag.as_ptr().cast(), tag.len() as u8);
+}
+
+unsafe extern "C" fn collect(
+    bytes: *const uint8_t,
+    count: size_t,
+    data: *mut libc::c_void,
+) -> size_t {
+    let out = &mut *(data as *mut Vec<u8>);
+    out.extend_from_slice(std::slice::from_raw_parts(bytes, count as usize));
+    count
+}
+
+#[test]
+fn rolls_back_innermost_first() {
+    unsafe {
+        let buf = librender_create_buffer(0);
+
+        element(buf, "a");
+        let outer = librender_checkpoint(buf);
+        element(buf, "b");
+        let inner = librender_checkpoint(buf);
+        element(buf, "c");
+
+        assert_eq!(librender_rollback(buf, outer), -1);
+        assert_eq!(librender_commit(buf, outer), -1);
+        assert_eq!(bytes(buf), b"\x01\x01a\x01\x01b\x01\x01c");
+
+        assert_eq!(librender_rollback(buf, inner), 0);
+        assert_eq!(librender_commit(buf, inner), -1);
+        assert_eq!(librender_commit(buf, outer), 0);
+        assert_eq!(librender_commit(buf, outer), -1);
+        assert_eq!(bytes(buf), b"\x01\x01a\x01\x01b");
+
+        librender_free_buffer(buf);
+    }
+}
+
+#[test]
+fn out_of_order_rollbacks_do_not_block_the_sink() {
+    let mut out = Vec::new();
+
+    unsafe {
+        let buf = librender_create_buffer(0);
+
+        librender_set_sink(buf, Some(collect), (&mut out as *mut Vec<u8>).cast(), 0);
+
+        let outer = librender_checkpoint(buf);
+        element(buf, "a");
+        let inner = librender_checkpoint(buf);
+        element(buf, "b");
+
+        assert_eq!(librender_rollback(buf, outer), -1);
+        assert_eq!(librender_flush(buf), -1);
+
+        assert_eq!(librender_commit(buf, inner), 0);
+        assert_eq!(librender_rollback(buf, outer), 0);
+        assert_eq!((*buf).checkpoints, 0);
+
+        element(buf, "c");
+        assert_eq!(librender_flush(buf), 0);
+
+        librender_free_buffer(buf);
+    }
+
+    assert_eq!(out, b"\x01\x01c");
+}
+
+#[test]
+fn nests_up_to_the_limit() {
+    unsafe {
+        let buf = librender_create_buffer(0);
+        let mut cps = Vec::new();
+
+        for i in 0..LIBRENDER_MAX_CHECKPOINTS {
+            cps.push(librender_checkpoint(buf));
+            librender_append_byte(buf, i as u8);
+        }
+
+        let past = librender_checkpoint(buf);
+        assert_eq!(past, size_t::MAX);
+        assert_eq!(librender_rollback(buf, past), -1);
+        assert_eq!(librender_commit(buf, past), -1);
+
+        while let Some(cp) = cps.pop() {
+            assert_eq!(librender_rollback(buf, cp), 0);
+            assert_eq!((*buf).size, cp);
+        }
+
+        assert_eq!((*buf).size, 0);
+        assert_eq!((*buf).checkpoints, 0);
+
+        librender_free_buffer(buf);
+    }
+}
+
+#[test]
+fn rollback_clears_the_overflow() {
+    let mut data = [0u8; 8];
+
+    unsafe {
+        let mut buf = MaybeUninit::uninit();
+        librender_init_fixed_buffer(buf.as_mut_ptr(), data.as_mut_ptr(), 8);
+        let buf = buf.as_mut_ptr();
+
+        element(buf, "a");
+        let cp = librender_checkpoint(buf);
+        element(buf, "bbbbbb");
+        assert_eq!(librender_buffer_overflowed(buf), 1);
+
+        assert_eq!(librender_rollback(buf, cp), 0);
+        assert_eq!(librender_buffer_overflowed(buf), 0);
+
+        element(buf, "b");
+        assert_eq!(bytes(buf), b"\x01\x01a\x01\x01b");
+        assert_eq!(librender_buffer_overflowed(buf), 0);
+    }
+}
+
+#[test]
+fn clearing_closes_open_checkpoints() {
+    let mut out = Vec::new();
+
+    unsafe {
+        let buf = librender_create_buffer(0);
+
+        librender_set_sink(buf, Some(collect), (&mut out as *mut Vec<u8>).cast(), 0);
+
+        element(buf, "a");
+        let cp = librender_checkpoint(buf);
+        element(buf, "b");
+
+        librender_clear_buffer(buf);
+        assert_eq!((*buf).checkpoints, 0);
+        assert_eq!(librender_flush(buf), 0);
+
+        // Past the old offset, the stale checkpoint must not cut into the
+        // new output.
+        element(buf, "cc");
+        element(buf, "d");
+        assert_eq!(librender_rollback(buf, cp), -1);
+        assert_eq!(librender_commit(buf, cp), -1);
+        assert_eq!(bytes(buf), b"\x01\x02cc\x01\x01d");
+
+        librender_free_buffer(buf);
+    }
+}
+
+#[test]
+fn truncating_closes_checkpoints_past_the_end() {
+    unsafe {
+        let buf = librender_create_buffer(0);
+
+        element(buf, "a");
+        let outer = librender_checkpoint(buf);
+        element(buf, "b");
+        let inner = librender_checkpoint(buf);
+        element(buf, "c");
+
+        librender_truncate(buf, 4);
+        assert_eq!((*buf).checkpoints, 1);
+
+        element(buf, "dd");
+        assert_eq!(librender_rollback(buf, inner), -1);
+        assert_eq!(librender_rollback(buf, outer), 0);
+        assert_eq!(bytes(buf), b"\x01\x01a");
+
+        librender_free_buffer(buf);
+        assert_eq!(librender_checkpoint(std::ptr::null_mut()), size_t::MAX);
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use librender::asm::assemble;
+use librender::html::render_html;
+use librender::interp::interpret;
+
+const SOURCE: &str = r#"
+    create_element "ul"
+    create_element "li"
+    text_node "one"
+    append_child
+    append_child
+"#;
+
+fn librender(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_librender"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn assembles_inspects_and_renders() {
+    let program = assemble(SOURCE).unwrap();
+
+    let asm = librender(&["asm"], SOURCE.as_bytes());
+    assert!(asm.status.success());
+    assert_eq!(asm.stdout, program);
+
+    let disasm = librender(&["disasm", "-"], &program);
+    assert!(disasm.status.success());
+    assert_eq!(
+        assemble(&String::from_utf8(disasm.stdout).unwrap()).unwrap(),
+        program
+    );
+
+    let validate = librender(&["validate"], &program);
+    assert!(validate.status.success());
+    assert_eq!(
+        String::from_utf8(validate.stdout).unwrap(),
+        "ok: 15 bytes, 5 instructions, 3 nodes\n"
+    );
+
+    let html = librender(&["render-html"], &program);
+    assert_eq!(
+        String::from_utf8(html.stdout).unwrap(),
+        render_html(&interpret(&program).unwrap()) + "\n"
+    );
+}
+
+#[test]
+fn reports_errors_with_exit_code_2() {
+    let truncated = librender(&["validate"], b"\x01\x05ab");
+
+    assert_eq!(truncated.status.code(), Some(2));
+    assert!(String::from_utf8(truncated.stderr)
+        .unwrap()
+        .starts_with("error: create_element tag length 5 exceeds remaining 2 bytes"));
+
+    let unknown = librender(&["frobnicate"], b"");
+
+    assert_eq!(unknown.status.code(), Some(2));
+    assert!(String::from_utf8(unknown.stderr)
+        .unwrap()
+        .starts_with("librender: unknown command `frobnicate`"));
+}
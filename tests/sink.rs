@@ -0,0 +1,92 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+use librender::*;
+
+/// Collects written bytes until `limit` is reached, then refuses more.
+struct Sink {
+    out: Vec<u8>,
+    limit: usize,
+    calls: usize,
+}
+
+unsafe extern "C" fn write(
+    bytes: *const uint8_t,
+    count: size_t,
+    data: *mut libc::c_void,
+) -> size_t {
+    let sink = &mut *(data as *mut Sink);
+    let count = (count as usize).min(sink.limit - sink.out.len());
+
+    sink.calls += 1;
+    sink.out
+        .extend_from_slice(std::slice::from_raw_parts(bytes, count));
+    count as size_t
+}
+
+unsafe fn text(buf: *mut librender_bytecode_buffer, text: &str) {
+    librender_text_node(buf, text.as_ptr().cast(), text.len() as u8);
+}
+
+#[test]
+fn streams_through_the_threshold() {
+    let mut sink = Sink {
+        out: Vec::new(),
+        limit: usize::MAX,
+        calls: 0,
+    };
+
+    unsafe {
+        let buf = librender_create_buffer(0);
+
+        librender_set_sink(buf, Some(write), (&mut sink as *mut Sink).cast(), 16);
+
+        for _ in 0..10 {
+            text(buf, "hello");
+        }
+
+        assert_eq!(librender_sink_failed(buf), 0);
+        assert_eq!(librender_flush(buf), 0);
+        assert_eq!((*buf).size, 0);
+        librender_free_buffer(buf);
+    }
+
+    assert_eq!(sink.out, b"\x06\x05hello".repeat(10));
+    assert!(sink.calls > 1);
+}
+
+#[test]
+fn reports_a_failing_sink_without_retrying_per_byte() {
+    let mut sink = Sink {
+        out: Vec::new(),
+        limit: 20,
+        calls: 0,
+    };
+
+    unsafe {
+        let buf = librender_create_buffer(0);
+
+        librender_set_sink(buf, Some(write), (&mut sink as *mut Sink).cast(), 8);
+
+        for _ in 0..10 {
+            text(buf, "hello");
+        }
+
+        assert_eq!(librender_sink_failed(buf), 1);
+
+        // The first failing flush stops the threshold from flushing again.
+        let calls = sink.calls;
+        text(buf, "hello");
+        assert_eq!(sink.calls, calls);
+
+        // Nothing is lost: the rejected bytes are still buffered.
+        assert_eq!(sink.out.len() + (*buf).size as usize, 11 * 7);
+        assert_eq!(librender_flush(buf), -1);
+
+        sink.limit = usize::MAX;
+        assert_eq!(librender_flush(buf), 0);
+        assert_eq!(librender_sink_failed(buf), 0);
+        librender_free_buffer(buf);
+    }
+
+    assert_eq!(sink.out, b"\x06\x05hello".repeat(11));
+}
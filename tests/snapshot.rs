@@ -0,0 +1,26 @@
+// Copyright (c) 2024 Elric Neumann. All rights reserved. MIT license.
+
+use librender::asm::assemble;
+use librender::assert_snapshot;
+
+#[test]
+fn renders_a_list() {
+    let program = assemble(
+        r#"
+        create_element "ul"
+        set_attribute "id" "items"
+        create_element "li"
+        text_node "one"
+        append_child
+        append_child
+        create_element "li"
+        set_attribute "class" "last"
+        text_node "two"
+        append_child
+        append_child
+        "#,
+    )
+    .unwrap();
+
+    assert_snapshot!("list", program);
+}